@@ -0,0 +1,488 @@
+/**
+ *  how the service proves who it is to cosmos.  the `credential` setting (see config.rs) picks the provider, but the
+ *  secrets themselves only ever come from the environment so that they stay out of config files:
+ *
+ *      credential           environment variables
+ *      ----------           ---------------------
+ *      master-key           COSMOS_AUTH_TOKEN
+ *      resource-token       COSMOS_RESOURCE_TOKEN
+ *      client-secret        AZURE_TENANT_ID, AZURE_CLIENT_ID, AZURE_CLIENT_SECRET
+ *      workload-identity    AZURE_TENANT_ID, AZURE_CLIENT_ID, AZURE_FEDERATED_TOKEN_FILE
+ *
+ *  the workload identity variables are the ones the AKS workload identity webhook injects into the pod.  both Azure AD
+ *  credentials also honour AZURE_AUTHORITY_HOST, which is how you point them at a sovereign cloud (or at a fake token
+ *  endpoint in the tests below).  Azure AD tokens are cached and fetched again shortly before they expire.
+ */
+use crate::error::{UserDbError, UserDbResult};
+use async_trait::async_trait;
+use azure_core::auth::{AccessToken, TokenCredential, TokenResponse};
+use azure_core::error::ErrorKind;
+use azure_data_cosmos::prelude::AuthorizationToken;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use futures::lock::Mutex;
+use hmac::{Hmac, Mac};
+use log::info;
+use serde::Deserialize;
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+use time::{Duration, OffsetDateTime};
+
+pub const DEFAULT_AUTHORITY_HOST: &str = "https://login.microsoftonline.com";
+
+// fetch a new token when the cached one has less than this left
+const REFRESH_MARGIN: Duration = Duration::minutes(5);
+
+const JWT_BEARER: &str = "urn:ietf:params:oauth:client-assertion-type:jwt-bearer";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CredentialKind {
+    MasterKey,
+    ResourceToken,
+    ClientSecret,
+    WorkloadIdentity,
+}
+
+impl CredentialKind {
+    /**
+     *  the names used by the `credential` setting
+     */
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.trim().to_lowercase().as_str() {
+            "master-key" => Some(CredentialKind::MasterKey),
+            "resource-token" => Some(CredentialKind::ResourceToken),
+            "client-secret" => Some(CredentialKind::ClientSecret),
+            "workload-identity" => Some(CredentialKind::WorkloadIdentity),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            CredentialKind::MasterKey => "master-key",
+            CredentialKind::ResourceToken => "resource-token",
+            CredentialKind::ClientSecret => "client-secret",
+            CredentialKind::WorkloadIdentity => "workload-identity",
+        }
+    }
+}
+
+/**
+ *  the credential the service authenticates with.  the sdk only needs an AuthorizationToken, but the few REST calls we
+ *  make ourselves (see cosmos_rest.rs) have to sign their requests, so we keep the secret itself around too
+ */
+#[derive(Clone)]
+pub enum CosmosCredential {
+    // the base64 key as it is shown in the portal
+    MasterKey(String),
+    ResourceToken(String),
+    AzureAd(Arc<AadCredential>),
+}
+
+/**
+ *  build the credential for the chosen kind from the environment.  env is passed in so the tests don't depend on the
+ *  process they run in.  nothing is sent anywhere yet -- the Azure AD credentials get their first token on the first
+ *  request to cosmos.
+ */
+pub fn credential(
+    kind: CredentialKind,
+    env: &dyn Fn(&str) -> Option<String>,
+) -> UserDbResult<CosmosCredential> {
+    let require = |name: &str| {
+        env(name).ok_or_else(|| {
+            UserDbError::Unauthorized(format!(
+                "Set env variable {} first!  the {} credential needs it",
+                name,
+                kind.name()
+            ))
+        })
+    };
+    let authority_host =
+        env("AZURE_AUTHORITY_HOST").unwrap_or_else(|| DEFAULT_AUTHORITY_HOST.to_string());
+
+    info!(
+        "authenticating to cosmos with the {} credential",
+        kind.name()
+    );
+    match kind {
+        /*
+         *  To get the key, go to the Azure portal and pick your CosmosDb, then pick "Keys" on the left pane.  Copy the
+         *  PRIMARY or SECONDARY KEY.  it has to be valid base64 -- if it isn't, it was usually only partly copied
+         */
+        CredentialKind::MasterKey => {
+            let key = require("COSMOS_AUTH_TOKEN")?;
+            match STANDARD.decode(key.trim()) {
+                Ok(..) => Ok(CosmosCredential::MasterKey(key.trim().to_string())),
+                Err(e) => Err(UserDbError::Unauthorized(format!(
+                    "COSMOS_AUTH_TOKEN is not a valid base64 Cosmos key ({}).  copy the PRIMARY or SECONDARY KEY \
+                     from the Keys page of your Cosmos account in the Azure portal",
+                    e
+                ))),
+            }
+        }
+        // resource tokens are minted by a token broker from a permission, so refreshing them is the broker's job
+        CredentialKind::ResourceToken => Ok(CosmosCredential::ResourceToken(require(
+            "COSMOS_RESOURCE_TOKEN",
+        )?)),
+        CredentialKind::ClientSecret => {
+            Ok(CosmosCredential::AzureAd(Arc::new(AadCredential::new(
+                &authority_host,
+                &require("AZURE_TENANT_ID")?,
+                &require("AZURE_CLIENT_ID")?,
+                ClientProof::Secret(require("AZURE_CLIENT_SECRET")?),
+            ))))
+        }
+        CredentialKind::WorkloadIdentity => {
+            Ok(CosmosCredential::AzureAd(Arc::new(AadCredential::new(
+                &authority_host,
+                &require("AZURE_TENANT_ID")?,
+                &require("AZURE_CLIENT_ID")?,
+                ClientProof::FederatedTokenFile(PathBuf::from(require(
+                    "AZURE_FEDERATED_TOKEN_FILE",
+                )?)),
+            ))))
+        }
+    }
+}
+
+impl CosmosCredential {
+    /**
+     *  the token the sdk's CosmosClient authenticates with
+     */
+    pub fn authorization_token(&self) -> UserDbResult<AuthorizationToken> {
+        match self {
+            CosmosCredential::MasterKey(key) => Ok(AuthorizationToken::primary_from_base64(key)?),
+            CosmosCredential::ResourceToken(token) => {
+                Ok(AuthorizationToken::new_resource(token.clone()))
+            }
+            CosmosCredential::AzureAd(credential) => Ok(AuthorizationToken::from_token_credential(
+                credential.clone(),
+            )),
+        }
+    }
+
+    /**
+     *  the value of the authorization header for one REST request, the way cosmos documents it:
+     *
+     *      master key:  type=master&ver=1.0&sig=<base64 HMAC-SHA256 of the request, keyed with the master key>
+     *      Azure AD:    type=aad&ver=1.0&sig=<access token>
+     *      resource:    the resource token as it came from the broker
+     *
+     *  all url encoded.  resource_link is the path of the resource without the leading / (e.g. "dbs/Users-db" when
+     *  creating a container in it), date is the exact value of the x-ms-date header, and resource is the account url,
+     *  which is what Azure AD tokens are scoped to.
+     */
+    pub async fn authorization_header(
+        &self,
+        resource: &str,
+        verb: &str,
+        resource_type: &str,
+        resource_link: &str,
+        date: &str,
+    ) -> UserDbResult<String> {
+        let unencoded = match self {
+            CosmosCredential::MasterKey(key) => {
+                let key = STANDARD
+                    .decode(key)
+                    .map_err(|e| UserDbError::Unauthorized(e.to_string()))?;
+                let string_to_sign = format!(
+                    "{}\n{}\n{}\n{}\n\n",
+                    verb.to_lowercase(),
+                    resource_type.to_lowercase(),
+                    resource_link,
+                    date.to_lowercase()
+                );
+                let mut mac = Hmac::<Sha256>::new_from_slice(&key)
+                    .map_err(|e| UserDbError::Unauthorized(e.to_string()))?;
+                mac.update(string_to_sign.as_bytes());
+                format!(
+                    "type=master&ver=1.0&sig={}",
+                    STANDARD.encode(mac.finalize().into_bytes())
+                )
+            }
+            CosmosCredential::ResourceToken(token) => token.clone(),
+            CosmosCredential::AzureAd(credential) => {
+                let token = credential.get_token(resource).await?;
+                format!("type=aad&ver=1.0&sig={}", token.token.secret())
+            }
+        };
+        Ok(form_urlencoded::byte_serialize(unencoded.as_bytes()).collect())
+    }
+}
+
+/**
+ *  how an AadCredential proves it is the app: a client secret, or a federated token that the platform writes to a
+ *  file
+ */
+#[derive(Debug, Clone)]
+pub enum ClientProof {
+    Secret(String),
+    FederatedTokenFile(PathBuf),
+}
+
+/**
+ *  the body Azure AD sends back from the token endpoint
+ */
+#[derive(Debug, Deserialize)]
+struct AadTokenResponse {
+    access_token: String,
+    expires_in: i64,
+}
+
+/**
+ *  gets tokens from the Azure AD v2 token endpoint with the client credentials flow, and caches them per resource
+ *  until they are REFRESH_MARGIN from expiring.  the lock is held while a token is fetched so concurrent requests
+ *  wait for one fetch instead of all going to Azure AD at once.
+ */
+pub struct AadCredential {
+    http: reqwest::Client,
+    token_url: String,
+    client_id: String,
+    proof: ClientProof,
+    cache: Mutex<HashMap<String, TokenResponse>>,
+}
+
+impl AadCredential {
+    pub fn new(authority_host: &str, tenant_id: &str, client_id: &str, proof: ClientProof) -> Self {
+        Self {
+            http: reqwest::Client::new(),
+            token_url: format!(
+                "{}/{}/oauth2/v2.0/token",
+                authority_host.trim_end_matches('/'),
+                tenant_id
+            ),
+            client_id: client_id.to_string(),
+            proof,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    async fn fetch_token(&self, resource: &str) -> azure_core::Result<TokenResponse> {
+        let mut form = vec![
+            ("grant_type", "client_credentials".to_string()),
+            ("client_id", self.client_id.clone()),
+            ("scope", scope(resource)),
+        ];
+        match &self.proof {
+            ClientProof::Secret(secret) => form.push(("client_secret", secret.clone())),
+            ClientProof::FederatedTokenFile(path) => {
+                // the platform rotates this file, so read it every time instead of once at startup
+                let assertion = fs::read_to_string(path).map_err(|e| {
+                    credential_error(format!(
+                        "could not read the federated token file {}: {}",
+                        path.display(),
+                        e
+                    ))
+                })?;
+                form.push(("client_assertion_type", JWT_BEARER.to_string()));
+                form.push(("client_assertion", assertion.trim().to_string()));
+            }
+        }
+
+        let response = self
+            .http
+            .post(&self.token_url)
+            .form(&form)
+            .send()
+            .await
+            .map_err(|e| credential_error(format!("could not reach {}: {}", self.token_url, e)))?;
+        let status = response.status();
+        let body = response
+            .text()
+            .await
+            .map_err(|e| credential_error(format!("could not read the token response: {}", e)))?;
+        if !status.is_success() {
+            return Err(credential_error(format!(
+                "Azure AD returned {} for client {}: {}",
+                status, self.client_id, body
+            )));
+        }
+        let token: AadTokenResponse = serde_json::from_str(&body)
+            .map_err(|e| credential_error(format!("unexpected token response: {}", e)))?;
+        Ok(TokenResponse::new(
+            AccessToken::new(token.access_token),
+            OffsetDateTime::now_utc() + Duration::seconds(token.expires_in),
+        ))
+    }
+}
+
+#[async_trait]
+impl TokenCredential for AadCredential {
+    async fn get_token(&self, resource: &str) -> azure_core::Result<TokenResponse> {
+        let mut cache = self.cache.lock().await;
+        if let Some(token) = cache.get(resource) {
+            if token.expires_on - REFRESH_MARGIN > OffsetDateTime::now_utc() {
+                return Ok(token.clone());
+            }
+        }
+        let token = self.fetch_token(resource).await?;
+        cache.insert(resource.to_string(), token.clone());
+        Ok(token)
+    }
+}
+
+/**
+ *  the sdk asks for a token for the account's url, and Azure AD wants that as a .default scope
+ */
+fn scope(resource: &str) -> String {
+    if resource.ends_with("/.default") {
+        resource.to_string()
+    } else {
+        format!("{}/.default", resource.trim_end_matches('/'))
+    }
+}
+
+fn credential_error(message: String) -> azure_core::Error {
+    azure_core::Error::new(ErrorKind::Credential, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{web, App, HttpResponse, HttpServer};
+    use std::sync::atomic::{AtomicI64, Ordering};
+
+    type Requests = Arc<std::sync::Mutex<Vec<HashMap<String, String>>>>;
+
+    const RESOURCE: &str = "https://myaccount.documents.azure.com";
+
+    /**
+     *  a stand-in for the Azure AD token endpoint.  it remembers every form it was sent and hands out tokens that
+     *  expire in however many seconds expires_in says
+     */
+    fn fake_token_endpoint(expires_in: Arc<AtomicI64>, requests: Requests) -> String {
+        let server = HttpServer::new(move || {
+            let expires_in = expires_in.clone();
+            let requests = requests.clone();
+            App::new().route(
+                "/{tenant}/oauth2/v2.0/token",
+                web::post().to(move |form: web::Form<HashMap<String, String>>| {
+                    let mut requests = requests.lock().unwrap();
+                    requests.push(form.into_inner());
+                    let body = serde_json::json!({
+                        "token_type": "Bearer",
+                        "access_token": format!("token-{}", requests.len()),
+                        "expires_in": expires_in.load(Ordering::SeqCst),
+                    });
+                    async move { HttpResponse::Ok().json(body) }
+                }),
+            )
+        })
+        .workers(1)
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let url = format!("http://{}", server.addrs()[0]);
+        actix_web::rt::spawn(server.run());
+        url
+    }
+
+    #[actix_web::test]
+    async fn test_tokens_are_cached_and_refreshed() {
+        let expires_in = Arc::new(AtomicI64::new(3600));
+        let requests = Requests::default();
+        let url = fake_token_endpoint(expires_in.clone(), requests.clone());
+
+        let credential = AadCredential::new(
+            &url,
+            "tenant",
+            "client",
+            ClientProof::Secret("shh".to_string()),
+        );
+        let first = credential.get_token(RESOURCE).await.unwrap();
+        let second = credential.get_token(RESOURCE).await.unwrap();
+        assert_eq!(first.token.secret(), "token-1");
+        assert_eq!(second.token.secret(), "token-1");
+        assert_eq!(requests.lock().unwrap().len(), 1);
+
+        let sent = requests.lock().unwrap()[0].clone();
+        assert_eq!(sent["grant_type"], "client_credentials");
+        assert_eq!(sent["client_secret"], "shh");
+        assert_eq!(
+            sent["scope"],
+            "https://myaccount.documents.azure.com/.default"
+        );
+
+        // a token that is inside the refresh margin is fetched again every time
+        expires_in.store(60, Ordering::SeqCst);
+        let credential = AadCredential::new(
+            &url,
+            "tenant",
+            "client",
+            ClientProof::Secret("shh".to_string()),
+        );
+        credential.get_token(RESOURCE).await.unwrap();
+        let refreshed = credential.get_token(RESOURCE).await.unwrap();
+        assert_eq!(refreshed.token.secret(), "token-3");
+    }
+
+    #[actix_web::test]
+    async fn test_workload_identity_sends_the_token_file() {
+        let requests = Requests::default();
+        let url = fake_token_endpoint(Arc::new(AtomicI64::new(3600)), requests.clone());
+        let token_file =
+            std::env::temp_dir().join(format!("federated-token-{}", std::process::id()));
+        fs::write(&token_file, "federated.jwt\n").unwrap();
+
+        let credential = AadCredential::new(
+            &url,
+            "tenant",
+            "client",
+            ClientProof::FederatedTokenFile(token_file.clone()),
+        );
+        credential.get_token(RESOURCE).await.unwrap();
+        let sent = requests.lock().unwrap()[0].clone();
+        assert_eq!(sent["client_assertion"], "federated.jwt");
+        assert_eq!(sent["client_assertion_type"], JWT_BEARER);
+        assert!(!sent.contains_key("client_secret"));
+
+        // the file going away is a credential error, not a panic
+        fs::remove_file(&token_file).unwrap();
+        let credential = AadCredential::new(
+            &url,
+            "tenant",
+            "client",
+            ClientProof::FederatedTokenFile(token_file),
+        );
+        let err = UserDbError::from(credential.get_token(RESOURCE).await.unwrap_err());
+        assert!(matches!(err, UserDbError::Unauthorized(..)));
+    }
+
+    #[actix_web::test]
+    async fn test_master_key_signature() {
+        // checked against the algorithm in the Cosmos REST docs, computed separately
+        let credential = CosmosCredential::MasterKey("bm90LWEtcmVhbC1jb3Ntb3Mta2V5".to_string());
+        let header = credential
+            .authorization_header(
+                RESOURCE,
+                "GET",
+                "dbs",
+                "dbs/ToDoList",
+                "Thu, 27 Apr 2017 00:51:12 GMT",
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            header,
+            "type%3Dmaster%26ver%3D1.0%26sig%3Dix9U7uw5nJwBTHnlCJbheLUuGUW70v2De%2F4UzeeobmY%3D"
+        );
+    }
+
+    #[test]
+    fn test_missing_settings_are_named() {
+        let err = credential(CredentialKind::ClientSecret, &|_| None)
+            .err()
+            .unwrap();
+        assert!(err.message().contains("AZURE_TENANT_ID"));
+        let err = credential(CredentialKind::ResourceToken, &|_| None)
+            .err()
+            .unwrap();
+        assert!(err.message().contains("COSMOS_RESOURCE_TOKEN"));
+        assert_eq!(
+            CredentialKind::from_name("Workload-Identity"),
+            Some(CredentialKind::WorkloadIdentity)
+        );
+    }
+}
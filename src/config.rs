@@ -0,0 +1,690 @@
+/**
+ *  the settings for the service.  they come from (lowest to highest precedence):
+ *
+ *      1. the defaults below
+ *      2. a TOML file -- --config <path>, or COSMOS_RUST_SAMPLE_CONFIG, or ./cosmos-sample.toml if it exists
+ *      3. environment variables
+ *      4. command line flags
+ *
+ *  so a flag always wins over an environment variable, which always wins over the file.  everything is validated once
+ *  at startup, and every problem is reported together with the setting that caused it.  the cosmos secrets are not
+ *  part of this -- `credential` only picks how we authenticate, the secrets for it come from the environment (see
+ *  auth.rs), and the account is always COSMOS_ACCOUNT_NAME.  the same goes for the token that POST /reset needs: it is
+ *  only read from RESET_TOKEN_VAR, and only when allow_reset is true.  likewise the token for the admin writes (POST
+ *  /migrate, PUT /admin/throughput and POST /admin/indexing) is only read from ADMIN_TOKEN_VAR, and only when allow_admin is true.
+ *
+ *      setting               file key              environment variable                     flag
+ *      -------               --------              --------------------                     ----
+ *      port                  port                  COSMOS_RUST_SAMPLE_PORT                  --port
+ *      bind address          bind_address          COSMOS_RUST_SAMPLE_BIND_ADDRESS          --bind-address
+ *      database name         database_name         COSMOS_RUST_SAMPLE_DATABASE_NAME         --database-name
+ *      collection name       collection_name       COSMOS_RUST_SAMPLE_COLLECTION_NAME       --collection-name
+ *      partition key path    partition_key_path    COSMOS_RUST_SAMPLE_PARTITION_KEY_PATH    --partition-key-path
+ *      log level             log_level             RUST_LOG                                 --log-level
+ *      backend               backend               USER_STORE_BACKEND                       --backend
+ *      credential            credential            COSMOS_RUST_SAMPLE_CREDENTIAL            --credential
+ *      partition strategy    partition_strategy    COSMOS_RUST_SAMPLE_PARTITION_STRATEGY    --partition-strategy
+ *      retry policy          retry                 COSMOS_RUST_SAMPLE_RETRY                 --retry
+ *      fault injection       fault_injection       COSMOS_RUST_SAMPLE_FAULT_INJECTION       --fault-injection
+ *      cosmos endpoint       endpoint              COSMOS_RUST_SAMPLE_ENDPOINT              --endpoint
+ *      CA certificate        ca_certificate        COSMOS_RUST_SAMPLE_CA_CERTIFICATE        --ca-certificate
+ *      allow reset           allow_reset           COSMOS_RUST_SAMPLE_ALLOW_RESET           --allow-reset
+ *      allow admin writes    allow_admin           COSMOS_RUST_SAMPLE_ALLOW_ADMIN           --allow-admin
+ *      database throughput   database_throughput   COSMOS_RUST_SAMPLE_DATABASE_THROUGHPUT   --database-throughput
+ *      container throughput  container_throughput  COSMOS_RUST_SAMPLE_CONTAINER_THROUGHPUT  --container-throughput
+ */
+use crate::auth::CredentialKind;
+use crate::cosmosdb::CosmosEndpoint;
+use crate::faults::FaultPlan;
+use crate::partition::PartitionStrategy;
+use crate::retry::RetryPolicy;
+use crate::store::Backend;
+use crate::throughput::Throughput;
+use anyhow::{anyhow, bail, Context, Result};
+use serde::Deserialize;
+use std::fs;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+pub const DEFAULT_CONFIG_FILE: &str = "cosmos-sample.toml";
+pub const CONFIG_FILE_VAR: &str = "COSMOS_RUST_SAMPLE_CONFIG";
+pub const RESET_TOKEN_VAR: &str = "COSMOS_RUST_SAMPLE_RESET_TOKEN";
+pub const ADMIN_TOKEN_VAR: &str = "COSMOS_RUST_SAMPLE_ADMIN_TOKEN";
+
+// a reset or admin token has to be at least this long, so it can't be guessed
+const MIN_TOKEN_LENGTH: usize = 16;
+
+/**
+ *  a token a request has to send to do something costly or destructive: the reset token and the admin token.  it is a
+ *  secret, so Debug doesn't show it (main logs the config)
+ */
+#[derive(Clone, PartialEq, Eq)]
+pub struct SecretToken(String);
+
+impl SecretToken {
+    /**
+     *  compares every byte whatever the first difference is, so the time it takes doesn't say how much was right
+     */
+    pub fn matches(&self, candidate: &str) -> bool {
+        let (expected, candidate) = (self.0.as_bytes(), candidate.as_bytes());
+        expected.len() == candidate.len()
+            && expected
+                .iter()
+                .zip(candidate)
+                .fold(0, |diff, (a, b)| diff | (a ^ b))
+                == 0
+    }
+}
+
+impl std::fmt::Debug for SecretToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "SecretToken(..)")
+    }
+}
+
+/**
+ *  the token from the environment, if the setting that uses it is on.  it is only read then, so a stray one in the
+ *  environment doesn't turn anything on
+ */
+fn secret_token(
+    setting: &str,
+    allowed: bool,
+    var: &str,
+    token: Option<String>,
+    problems: &mut Vec<String>,
+) -> Option<SecretToken> {
+    match token.map(|token| token.trim().to_string()) {
+        _ if !allowed => None,
+        Some(token) if token.len() >= MIN_TOKEN_LENGTH => Some(SecretToken(token)),
+        _ => {
+            problems.push(format!(
+                "{} is true, so {} has to be set to a token of at least {} characters",
+                setting, var, MIN_TOKEN_LENGTH
+            ));
+            None
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub port: u16,
+    pub bind_address: String,
+    pub database_name: String,
+    pub collection_name: String,
+    pub partition_key_path: String,
+    pub log_level: String,
+    pub backend: Backend,
+    pub credential: CredentialKind,
+    pub partition_strategy: PartitionStrategy,
+    pub retry: RetryPolicy,
+    pub fault_injection: FaultPlan,
+    pub endpoint: CosmosEndpoint,
+    // a PEM file with a certificate to trust when connecting to cosmos, on top of the system ones
+    pub ca_certificate: Option<PathBuf>,
+    // POST /reset deletes the database.  it is off unless this is true and RESET_TOKEN_VAR is set
+    pub allow_reset: bool,
+    // the token POST /reset has to send.  only Some when allow_reset is true
+    pub reset_token: Option<SecretToken>,
+    // POST /migrate rewrites documents, PUT /admin/throughput changes what the account costs, and POST /admin/indexing
+    // makes cosmos reindex.  they are off unless this is true and ADMIN_TOKEN_VAR is set
+    pub allow_admin: bool,
+    // the token the admin writes have to send.  only Some when allow_admin is true
+    pub admin_token: Option<SecretToken>,
+    // what setupdb creates the database (shared) and the collection (dedicated) with.  None is cosmos's default
+    pub database_throughput: Option<Throughput>,
+    pub container_throughput: Option<Throughput>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            port: 8080,
+            bind_address: "0.0.0.0".to_string(),
+            database_name: "Users-db".to_string(),
+            collection_name: "User-Container".to_string(),
+            partition_key_path: "/partition_key".to_string(),
+            log_level: "info".to_string(),
+            backend: Backend::Cosmos,
+            credential: CredentialKind::MasterKey,
+            partition_strategy: PartitionStrategy::default(),
+            retry: RetryPolicy::default(),
+            fault_injection: FaultPlan::default(),
+            endpoint: CosmosEndpoint::default(),
+            ca_certificate: None,
+            allow_reset: false,
+            reset_token: None,
+            allow_admin: false,
+            admin_token: None,
+            database_throughput: None,
+            container_throughput: None,
+        }
+    }
+}
+
+/**
+ *  one source of settings.  anything that source doesn't set is None and falls through to the layer below it
+ */
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigLayer {
+    port: Option<u16>,
+    bind_address: Option<String>,
+    database_name: Option<String>,
+    collection_name: Option<String>,
+    partition_key_path: Option<String>,
+    log_level: Option<String>,
+    backend: Option<String>,
+    credential: Option<String>,
+    partition_strategy: Option<String>,
+    retry: Option<String>,
+    fault_injection: Option<String>,
+    endpoint: Option<String>,
+    ca_certificate: Option<PathBuf>,
+    allow_reset: Option<bool>,
+    allow_admin: Option<bool>,
+    database_throughput: Option<String>,
+    container_throughput: Option<String>,
+}
+
+impl ConfigLayer {
+    /**
+     *  the settings in `over` win over the ones in self
+     */
+    fn overlay(self, over: ConfigLayer) -> ConfigLayer {
+        ConfigLayer {
+            port: over.port.or(self.port),
+            bind_address: over.bind_address.or(self.bind_address),
+            database_name: over.database_name.or(self.database_name),
+            collection_name: over.collection_name.or(self.collection_name),
+            partition_key_path: over.partition_key_path.or(self.partition_key_path),
+            log_level: over.log_level.or(self.log_level),
+            backend: over.backend.or(self.backend),
+            credential: over.credential.or(self.credential),
+            partition_strategy: over.partition_strategy.or(self.partition_strategy),
+            retry: over.retry.or(self.retry),
+            fault_injection: over.fault_injection.or(self.fault_injection),
+            endpoint: over.endpoint.or(self.endpoint),
+            ca_certificate: over.ca_certificate.or(self.ca_certificate),
+            allow_reset: over.allow_reset.or(self.allow_reset),
+            allow_admin: over.allow_admin.or(self.allow_admin),
+            database_throughput: over.database_throughput.or(self.database_throughput),
+            container_throughput: over.container_throughput.or(self.container_throughput),
+        }
+    }
+
+    fn from_file(path: &Path) -> Result<ConfigLayer> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("could not read config file {}", path.display()))?;
+        toml::from_str(&text).with_context(|| format!("invalid config file {}", path.display()))
+    }
+
+    fn from_env(env: &dyn Fn(&str) -> Option<String>) -> Result<ConfigLayer> {
+        let port = match env("COSMOS_RUST_SAMPLE_PORT") {
+            Some(port) => Some(parse_port(&port).context("COSMOS_RUST_SAMPLE_PORT")?),
+            None => None,
+        };
+        let allow_reset = match env("COSMOS_RUST_SAMPLE_ALLOW_RESET") {
+            Some(value) => Some(parse_bool(&value).context("COSMOS_RUST_SAMPLE_ALLOW_RESET")?),
+            None => None,
+        };
+        let allow_admin = match env("COSMOS_RUST_SAMPLE_ALLOW_ADMIN") {
+            Some(value) => Some(parse_bool(&value).context("COSMOS_RUST_SAMPLE_ALLOW_ADMIN")?),
+            None => None,
+        };
+        Ok(ConfigLayer {
+            port,
+            bind_address: env("COSMOS_RUST_SAMPLE_BIND_ADDRESS"),
+            database_name: env("COSMOS_RUST_SAMPLE_DATABASE_NAME"),
+            collection_name: env("COSMOS_RUST_SAMPLE_COLLECTION_NAME"),
+            partition_key_path: env("COSMOS_RUST_SAMPLE_PARTITION_KEY_PATH"),
+            log_level: env("RUST_LOG"),
+            backend: env("USER_STORE_BACKEND"),
+            credential: env("COSMOS_RUST_SAMPLE_CREDENTIAL"),
+            partition_strategy: env("COSMOS_RUST_SAMPLE_PARTITION_STRATEGY"),
+            retry: env("COSMOS_RUST_SAMPLE_RETRY"),
+            fault_injection: env("COSMOS_RUST_SAMPLE_FAULT_INJECTION"),
+            endpoint: env("COSMOS_RUST_SAMPLE_ENDPOINT"),
+            ca_certificate: env("COSMOS_RUST_SAMPLE_CA_CERTIFICATE").map(PathBuf::from),
+            allow_reset,
+            allow_admin,
+            database_throughput: env("COSMOS_RUST_SAMPLE_DATABASE_THROUGHPUT"),
+            container_throughput: env("COSMOS_RUST_SAMPLE_CONTAINER_THROUGHPUT"),
+        })
+    }
+
+    /**
+     *  parses "--flag value" and "--flag=value".  returns the --config path separately since it says where to find
+     *  the file layer
+     */
+    fn from_args(args: &[String]) -> Result<(Option<PathBuf>, ConfigLayer)> {
+        let mut config_file = None;
+        let mut layer = ConfigLayer::default();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let (flag, inline_value) = match arg.split_once('=') {
+                Some((flag, value)) => (flag, Some(value.to_string())),
+                None => (arg.as_str(), None),
+            };
+            let mut value = || match inline_value.clone().or_else(|| args.next().cloned()) {
+                Some(value) => Ok(value),
+                None => Err(anyhow!("{} needs a value", flag)),
+            };
+            match flag {
+                "--config" => config_file = Some(PathBuf::from(value()?)),
+                "--port" => layer.port = Some(parse_port(&value()?).context("--port")?),
+                "--bind-address" => layer.bind_address = Some(value()?),
+                "--database-name" => layer.database_name = Some(value()?),
+                "--collection-name" => layer.collection_name = Some(value()?),
+                "--partition-key-path" => layer.partition_key_path = Some(value()?),
+                "--log-level" => layer.log_level = Some(value()?),
+                "--backend" => layer.backend = Some(value()?),
+                "--credential" => layer.credential = Some(value()?),
+                "--partition-strategy" => layer.partition_strategy = Some(value()?),
+                "--retry" => layer.retry = Some(value()?),
+                "--fault-injection" => layer.fault_injection = Some(value()?),
+                "--endpoint" => layer.endpoint = Some(value()?),
+                "--ca-certificate" => layer.ca_certificate = Some(PathBuf::from(value()?)),
+                "--allow-reset" => {
+                    layer.allow_reset = Some(parse_bool(&value()?).context("--allow-reset")?)
+                }
+                "--allow-admin" => {
+                    layer.allow_admin = Some(parse_bool(&value()?).context("--allow-admin")?)
+                }
+                "--database-throughput" => layer.database_throughput = Some(value()?),
+                "--container-throughput" => layer.container_throughput = Some(value()?),
+                _ => bail!(
+                    "unknown argument '{}'.  valid flags are --config, --port, --bind-address, --database-name, \
+                     --collection-name, --partition-key-path, --log-level, --backend, --credential, \
+                     --partition-strategy, --retry, --fault-injection, --endpoint, --ca-certificate, \
+                     --allow-reset, --allow-admin, --database-throughput and --container-throughput",
+                    arg
+                ),
+            }
+        }
+        Ok((config_file, layer))
+    }
+}
+
+fn parse_port(port: &str) -> Result<u16> {
+    match port.trim().parse::<u16>() {
+        Ok(port) if port != 0 => Ok(port),
+        _ => bail!(
+            "'{}' is not a valid port.  use a number from 1 to 65535",
+            port
+        ),
+    }
+}
+
+fn parse_bool(value: &str) -> Result<bool> {
+    match value.trim().to_lowercase().as_str() {
+        "true" => Ok(true),
+        "false" => Ok(false),
+        _ => bail!("'{}' is not valid.  use true or false", value),
+    }
+}
+
+impl Config {
+    /**
+     *  load the config for this process from its command line and environment
+     */
+    pub fn load() -> Result<Config> {
+        let args: Vec<String> = std::env::args().skip(1).collect();
+        Config::load_from(&args, &|name| std::env::var(name).ok())
+    }
+
+    /**
+     *  the same as load(), but with the command line and environment passed in so that tests don't depend on the
+     *  process they run in
+     */
+    pub fn load_from(args: &[String], env: &dyn Fn(&str) -> Option<String>) -> Result<Config> {
+        let (config_file, cli) = ConfigLayer::from_args(args)?;
+        let env_layer = ConfigLayer::from_env(env)?;
+
+        // a file that was asked for has to exist, the default one is optional
+        let file = match config_file.or_else(|| env(CONFIG_FILE_VAR).map(PathBuf::from)) {
+            Some(path) => ConfigLayer::from_file(&path)?,
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => {
+                ConfigLayer::from_file(Path::new(DEFAULT_CONFIG_FILE))?
+            }
+            None => ConfigLayer::default(),
+        };
+
+        let layer = file.overlay(env_layer).overlay(cli);
+        Config::from_layer(layer, env(RESET_TOKEN_VAR), env(ADMIN_TOKEN_VAR))
+    }
+
+    /**
+     *  fill in defaults and check every setting, reporting all the problems at once
+     */
+    fn from_layer(
+        layer: ConfigLayer,
+        reset_token: Option<String>,
+        admin_token: Option<String>,
+    ) -> Result<Config> {
+        let defaults = Config::default();
+        let mut problems = Vec::new();
+
+        let backend = match layer.backend {
+            Some(name) => Backend::from_name(&name).unwrap_or_else(|| {
+                problems.push(format!(
+                    "backend '{}' is not valid.  use 'cosmos' or 'memory'",
+                    name
+                ));
+                defaults.backend
+            }),
+            None => defaults.backend,
+        };
+
+        let credential = match layer.credential {
+            Some(name) => CredentialKind::from_name(&name).unwrap_or_else(|| {
+                problems.push(format!(
+                    "credential '{}' is not valid.  use 'master-key', 'resource-token', 'client-secret' or \
+                     'workload-identity'",
+                    name
+                ));
+                defaults.credential
+            }),
+            None => defaults.credential,
+        };
+
+        let partition_strategy = match layer.partition_strategy {
+            Some(name) => PartitionStrategy::from_name(&name).unwrap_or_else(|| {
+                problems.push(format!(
+                    "partition_strategy '{}' is not valid.  use 'single', 'id-hash', 'id-hash:<buckets>' (1 to {}) \
+                     or 'email-domain'",
+                    name,
+                    crate::partition::MAX_ID_HASH_BUCKETS
+                ));
+                defaults.partition_strategy
+            }),
+            None => defaults.partition_strategy,
+        };
+
+        let retry = match layer.retry {
+            Some(spec) => RetryPolicy::from_spec(&spec).unwrap_or_else(|| {
+                problems.push(format!(
+                    "retry '{}' is not valid.  use 'off' or e.g. 'attempts=5,base_delay_ms=100,max_delay_ms=5000,\
+                     budget_ms=30000' (attempts 1 to {}, max_delay_ms at least base_delay_ms)",
+                    spec,
+                    crate::retry::MAX_RETRY_ATTEMPTS
+                ));
+                defaults.retry
+            }),
+            None => defaults.retry,
+        };
+
+        let fault_injection = match layer.fault_injection {
+            Some(spec) => FaultPlan::from_spec(&spec).unwrap_or_else(|| {
+                problems.push(format!(
+                    "fault_injection '{}' is not valid.  use 'off' or e.g. 'throttle=0.1,unavailable=0.05' (rates \
+                     from 0 to 1 that add up to at most 1)",
+                    spec
+                ));
+                defaults.fault_injection
+            }),
+            None => defaults.fault_injection,
+        };
+
+        let endpoint = match layer.endpoint {
+            Some(name) => CosmosEndpoint::from_name(&name).unwrap_or_else(|| {
+                problems.push(format!(
+                    "endpoint '{}' is not valid.  use 'public', 'china', 'us-government', 'emulator' or the url of \
+                     the account, e.g. https://localhost:8081",
+                    name
+                ));
+                CosmosEndpoint::default()
+            }),
+            None => defaults.endpoint,
+        };
+
+        let mut throughput = |setting: &str, spec: Option<String>| {
+            match spec {
+            Some(spec) => Throughput::from_spec(&spec).unwrap_or_else(|| {
+                problems.push(format!(
+                    "{} '{}' is not valid.  use 'none', RU/s for manual throughput (400 or more, in steps of 100) or \
+                     'autoscale:<max RU/s>' (1000 or more, in steps of 1000)",
+                    setting, spec
+                ));
+                None
+            }),
+            None => None,
+        }
+        };
+        let database_throughput = throughput("database_throughput", layer.database_throughput);
+        let container_throughput = throughput("container_throughput", layer.container_throughput);
+
+        let allow_reset = layer.allow_reset.unwrap_or(defaults.allow_reset);
+        let reset_token = secret_token(
+            "allow_reset",
+            allow_reset,
+            RESET_TOKEN_VAR,
+            reset_token,
+            &mut problems,
+        );
+        let allow_admin = layer.allow_admin.unwrap_or(defaults.allow_admin);
+        let admin_token = secret_token(
+            "allow_admin",
+            allow_admin,
+            ADMIN_TOKEN_VAR,
+            admin_token,
+            &mut problems,
+        );
+
+        let config = Config {
+            port: layer.port.unwrap_or(defaults.port),
+            bind_address: layer.bind_address.unwrap_or(defaults.bind_address),
+            database_name: layer.database_name.unwrap_or(defaults.database_name),
+            collection_name: layer.collection_name.unwrap_or(defaults.collection_name),
+            partition_key_path: layer
+                .partition_key_path
+                .unwrap_or(defaults.partition_key_path),
+            log_level: layer.log_level.unwrap_or(defaults.log_level),
+            backend,
+            credential,
+            partition_strategy,
+            retry,
+            fault_injection,
+            endpoint,
+            ca_certificate: layer.ca_certificate,
+            allow_reset,
+            reset_token,
+            allow_admin,
+            admin_token,
+            database_throughput,
+            container_throughput,
+        };
+
+        if config.bind_address.parse::<IpAddr>().is_err() {
+            problems.push(format!(
+                "bind_address '{}' is not an IP address.  use e.g. 0.0.0.0 or 127.0.0.1",
+                config.bind_address
+            ));
+        }
+        if let Err(problem) = check_resource_name("database_name", &config.database_name) {
+            problems.push(problem);
+        }
+        if let Err(problem) = check_resource_name("collection_name", &config.collection_name) {
+            problems.push(problem);
+        }
+        if !config.partition_key_path.starts_with('/') || config.partition_key_path.len() < 2 {
+            problems.push(format!(
+                "partition_key_path '{}' must be a path into the document, like /partition_key",
+                config.partition_key_path
+            ));
+        }
+        if let Some(path) = &config.ca_certificate {
+            if !path.is_file() {
+                problems.push(format!(
+                    "ca_certificate '{}' is not a file.  use the path of a PEM certificate",
+                    path.display()
+                ));
+            }
+        }
+        if config.log_level.trim().is_empty() {
+            problems.push("log_level is empty.  use e.g. info or actix_web=trace".to_string());
+        }
+
+        if problems.is_empty() {
+            Ok(config)
+        } else {
+            bail!("invalid configuration:\n\t{}", problems.join("\n\t"))
+        }
+    }
+}
+
+/**
+ *  cosmos database and container ids can't be empty, can't be longer than 255 characters, can't contain / \ ? or #,
+ *  and can't end with a space
+ */
+fn check_resource_name(setting: &str, name: &str) -> std::result::Result<(), String> {
+    if name.is_empty() || name.len() > 255 {
+        Err(format!("{} must be 1 to 255 characters long", setting))
+    } else if name.contains(['/', '\\', '?', '#']) {
+        Err(format!(
+            "{} '{}' can't contain any of / \\ ? #",
+            setting, name
+        ))
+    } else if name.ends_with(' ') {
+        Err(format!("{} '{}' can't end with a space", setting, name))
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_precedence() {
+        let dir = std::env::temp_dir().join(format!("config-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("test.toml");
+        fs::write(
+            &file,
+            "port = 9000\ndatabase_name = \"from-file\"\nbackend = \"memory\"\ndatabase_throughput = \"autoscale:4000\"\n",
+        )
+        .unwrap();
+
+        let vars: HashMap<&str, &str> = [
+            ("COSMOS_RUST_SAMPLE_PORT", "9001"),
+            ("COSMOS_RUST_SAMPLE_COLLECTION_NAME", "from-env"),
+            ("COSMOS_RUST_SAMPLE_CREDENTIAL", "workload-identity"),
+        ]
+        .iter()
+        .cloned()
+        .collect();
+        let env = |name: &str| vars.get(name).map(|value| value.to_string());
+
+        let config = Config::load_from(
+            &args(&["--config", file.to_str().unwrap(), "--port=9002"]),
+            &env,
+        )
+        .unwrap();
+        assert_eq!(config.port, 9002);
+        assert_eq!(config.database_name, "from-file");
+        assert_eq!(config.collection_name, "from-env");
+        assert_eq!(config.backend, Backend::Memory);
+        assert_eq!(config.partition_key_path, "/partition_key");
+        assert_eq!(config.credential, CredentialKind::WorkloadIdentity);
+        assert_eq!(config.retry, RetryPolicy::default());
+        assert!(config.fault_injection.is_off());
+        assert_eq!(config.endpoint, CosmosEndpoint::Public);
+        assert_eq!(config.ca_certificate, None);
+        assert!(!config.allow_reset);
+        assert_eq!(
+            config.database_throughput,
+            Some(Throughput::Autoscale(4000))
+        );
+        assert_eq!(config.container_throughput, None);
+        assert_eq!(config.reset_token, None);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_validation_reports_every_problem() {
+        let err = Config::load_from(
+            &args(&[
+                "--bind-address",
+                "localhost:80",
+                "--database-name",
+                "bad/name",
+                "--backend",
+                "mongo",
+                "--partition-strategy=id-hash:0",
+                "--retry=attempts=0",
+                "--fault-injection",
+                "throttle=0.8,unavailable=0.5",
+                "--endpoint=ftp://localhost",
+                "--ca-certificate=/no/such/cert.pem",
+                "--container-throughput=autoscale:500",
+            ]),
+            &|_| None,
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(err.contains("bind_address"));
+        assert!(err.contains("database_name"));
+        assert!(err.contains("backend 'mongo'"));
+        assert!(err.contains("partition_strategy 'id-hash:0'"));
+        assert!(err.contains("retry 'attempts=0'"));
+        assert!(err.contains("fault_injection"));
+        assert!(err.contains("endpoint 'ftp://localhost'"));
+        assert!(err.contains("ca_certificate '/no/such/cert.pem'"));
+        assert!(err.contains("container_throughput 'autoscale:500'"));
+    }
+
+    #[test]
+    fn test_reset_needs_a_token() {
+        let token = "a-long-enough-reset-token";
+        let env = |name: &str| (name == RESET_TOKEN_VAR).then(|| token.to_string());
+
+        // the token alone doesn't turn reset on
+        let config = Config::load_from(&[], &env).unwrap();
+        assert_eq!(config.reset_token, None);
+
+        let config = Config::load_from(&args(&["--allow-reset=true"]), &env).unwrap();
+        let reset_token = config.reset_token.unwrap();
+        assert!(reset_token.matches(token));
+        assert!(!reset_token.matches("a-long-enough-reset-tokeN"));
+        assert!(!reset_token.matches(""));
+        assert!(!format!("{:?}", reset_token).contains(token));
+
+        let err = Config::load_from(&args(&["--allow-reset", "true"]), &|_| None)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains(RESET_TOKEN_VAR));
+        assert!(Config::load_from(&args(&["--allow-reset=yes"]), &env).is_err());
+
+        // the admin token works the same way, and is a different secret
+        let env = |name: &str| match name {
+            ADMIN_TOKEN_VAR => Some("a-long-enough-admin-token".to_string()),
+            "COSMOS_RUST_SAMPLE_ALLOW_ADMIN" => Some("true".to_string()),
+            _ => env(name),
+        };
+        let config = Config::load_from(&[], &env).unwrap();
+        assert!(config
+            .admin_token
+            .unwrap()
+            .matches("a-long-enough-admin-token"));
+        assert_eq!(config.reset_token, None);
+        let err = Config::load_from(&args(&["--allow-admin=true"]), &|_| None)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains(ADMIN_TOKEN_VAR));
+    }
+
+    #[test]
+    fn test_bad_flags() {
+        assert!(Config::load_from(&args(&["--port", "0"]), &|_| None).is_err());
+        assert!(Config::load_from(&args(&["--port"]), &|_| None).is_err());
+        assert!(Config::load_from(&args(&["--verbose"]), &|_| None).is_err());
+    }
+}
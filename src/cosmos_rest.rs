@@ -0,0 +1,350 @@
+/**
+ *  the few Cosmos REST calls that azure_data_cosmos doesn't cover -- for example creating a container with a unique
+ *  key policy.  requests are signed with the same credential the sdk uses (see CosmosCredential::authorization_header)
+ *  and errors come back as the same UserDbError variants, so callers can't tell which path a call took.
+ *
+ *  the REST API is documented at https://learn.microsoft.com/rest/api/cosmos-db/
+ */
+use crate::auth::CosmosCredential;
+use crate::error::{UserDbError, UserDbResult};
+use crate::throughput::Throughput;
+use log::info;
+use reqwest::Method;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::convert::TryFrom;
+use std::time::{Duration, SystemTime};
+
+// the REST API version we speak.  it is the first one with every feature used here
+const API_VERSION: &str = "2018-12-31";
+
+/**
+ *  the most operations cosmos takes in one transactional batch
+ */
+pub const MAX_BATCH_OPERATIONS: usize = 100;
+
+/**
+ *  what happened to one operation of a transactional batch.  if any operation fails, that one has its own status and
+ *  every other one is 424 Failed Dependency -- nothing in the batch was written
+ */
+#[derive(Debug, Clone, Deserialize)]
+pub struct BatchOperationResult {
+    #[serde(rename = "statusCode")]
+    pub status: u16,
+    #[serde(rename = "eTag", default)]
+    pub etag: Option<String>,
+    #[serde(rename = "resourceBody", default)]
+    pub body: Option<Value>,
+    #[serde(rename = "retryAfterMilliseconds", default)]
+    pub retry_after_ms: Option<u64>,
+}
+
+pub struct CosmosRest {
+    http: reqwest::Client,
+    endpoint: String,
+    credential: CosmosCredential,
+}
+
+impl CosmosRest {
+    /**
+     *  endpoint is the url of the account, e.g. https://myaccount.documents.azure.com
+     */
+    pub fn new(http: reqwest::Client, endpoint: &str, credential: CosmosCredential) -> Self {
+        Self {
+            http,
+            endpoint: endpoint.trim_end_matches('/').to_string(),
+            credential,
+        }
+    }
+
+    /**
+     *  send one request.  resource_type and resource_link are what the signature covers: for an operation on a feed
+     *  (like creating a container) that is the type of the new resource and the link of its parent, and path is the
+     *  url of the feed.  returns the JSON body, or Null if there isn't one.
+     */
+    pub async fn send(
+        &self,
+        method: Method,
+        resource_type: &str,
+        resource_link: &str,
+        path: &str,
+        body: Option<&Value>,
+    ) -> UserDbResult<Value> {
+        self.send_with(method, resource_type, resource_link, path, &[], body)
+            .await
+    }
+
+    /**
+     *  send with extra headers, e.g. the throughput for a new database
+     */
+    async fn send_with(
+        &self,
+        method: Method,
+        resource_type: &str,
+        resource_link: &str,
+        path: &str,
+        headers: &[(&str, String)],
+        body: Option<&Value>,
+    ) -> UserDbResult<Value> {
+        let (status, retry_after, text) = self
+            .execute(&method, resource_type, resource_link, path, headers, body)
+            .await?;
+        if !(200..300).contains(&status) {
+            return Err(UserDbError::from_status(
+                status,
+                format!("{} {} failed with {}: {}", method, path, status, text),
+                retry_after,
+            ));
+        }
+        if text.is_empty() {
+            Ok(Value::Null)
+        } else {
+            Ok(serde_json::from_str(&text)?)
+        }
+    }
+
+    /**
+     *  run up to MAX_BATCH_OPERATIONS operations (e.g. {"operationType": "Create", "resourceBody": {...}}) in one
+     *  logical partition as a single transaction: either all of them are written or none are.  a batch that ran
+     *  returns one result per operation even when it failed, so only a request that never got that far is an Err
+     */
+    pub async fn transactional_batch(
+        &self,
+        database: &str,
+        collection: &str,
+        partition_key: &str,
+        operations: &[Value],
+    ) -> UserDbResult<Vec<BatchOperationResult>> {
+        let link = format!("dbs/{}/colls/{}", database, collection);
+        let path = format!("{}/docs", link);
+        let headers = [
+            ("x-ms-cosmos-is-batch-request", "True".to_string()),
+            ("x-ms-cosmos-batch-atomic", "True".to_string()),
+            (
+                "x-ms-documentdb-partitionkey",
+                serde_json::to_string(&[partition_key])?,
+            ),
+        ];
+        let body = Value::from(operations.to_vec());
+        let (status, retry_after, text) = self
+            .execute(&Method::POST, "docs", &link, &path, &headers, Some(&body))
+            .await?;
+        match serde_json::from_str::<Vec<BatchOperationResult>>(&text) {
+            Ok(results) if results.len() == operations.len() => Ok(results),
+            _ => Err(UserDbError::from_status(
+                status,
+                format!("batch on {} failed with {}: {}", path, status, text),
+                retry_after,
+            )),
+        }
+    }
+
+    /**
+     *  sign and send a request and read the whole response, whatever its status
+     */
+    async fn execute(
+        &self,
+        method: &Method,
+        resource_type: &str,
+        resource_link: &str,
+        path: &str,
+        headers: &[(&str, String)],
+        body: Option<&Value>,
+    ) -> UserDbResult<(u16, Option<Duration>, String)> {
+        let date = httpdate::fmt_http_date(SystemTime::now());
+        let authorization = self
+            .credential
+            .authorization_header(
+                &self.endpoint,
+                method.as_str(),
+                resource_type,
+                resource_link,
+                &date,
+            )
+            .await?;
+        let mut request = self
+            .http
+            .request(method.clone(), format!("{}/{}", self.endpoint, path))
+            .header("authorization", authorization)
+            .header("x-ms-date", date)
+            .header("x-ms-version", API_VERSION);
+        for (name, value) in headers {
+            request = request.header(*name, value);
+        }
+        if let Some(body) = body {
+            // queries are sent as application/query+json
+            if !headers.iter().any(|(name, _)| *name == "content-type") {
+                request = request.header("content-type", "application/json");
+            }
+            request = request.body(body.to_string());
+        }
+
+        info!("{} {}", method, path);
+        let response = request.send().await.map_err(|e| {
+            UserDbError::Unavailable(format!("could not reach {}: {}", self.endpoint, e))
+        })?;
+        let status = response.status().as_u16();
+        let retry_after = response
+            .headers()
+            .get("x-ms-retry-after-ms")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse().ok())
+            .map(Duration::from_millis);
+        let text = response
+            .text()
+            .await
+            .map_err(|e| UserDbError::Backend(format!("could not read the response: {}", e)))?;
+        Ok((status, retry_after, text))
+    }
+
+    /**
+     *  create a database.  with throughput it is shared by the database's containers
+     */
+    pub async fn create_database(
+        &self,
+        database: &str,
+        throughput: Option<Throughput>,
+    ) -> UserDbResult<Value> {
+        let headers: Vec<_> = throughput.iter().map(throughput_header).collect();
+        self.send_with(
+            Method::POST,
+            "dbs",
+            "",
+            "dbs",
+            &headers,
+            Some(&json!({ "id": database })),
+        )
+        .await
+    }
+
+    pub async fn get_database(&self, database: &str) -> UserDbResult<Value> {
+        let link = format!("dbs/{}", database);
+        self.send(Method::GET, "dbs", &link, &link, None).await
+    }
+
+    /**
+     *  read a container's definition, e.g. to see its partitionKey.  NotFound if it doesn't exist
+     */
+    pub async fn get_collection(&self, database: &str, collection: &str) -> UserDbResult<Value> {
+        let link = format!("dbs/{}/colls/{}", database, collection);
+        self.send(Method::GET, "colls", &link, &link, None).await
+    }
+
+    /**
+     *  create a container from its full JSON definition (id, partitionKey, uniqueKeyPolicy, ...).  with throughput it
+     *  is dedicated to this container
+     */
+    pub async fn create_collection(
+        &self,
+        database: &str,
+        collection: &Value,
+        throughput: Option<Throughput>,
+    ) -> UserDbResult<Value> {
+        let link = format!("dbs/{}", database);
+        let headers: Vec<_> = throughput.iter().map(throughput_header).collect();
+        self.send_with(
+            Method::POST,
+            "colls",
+            &link,
+            &format!("{}/colls", link),
+            &headers,
+            Some(collection),
+        )
+        .await
+    }
+
+    /**
+     *  replace a container's definition, e.g. to change its indexingPolicy.  the partition key and unique keys can't be
+     *  changed, so send them as they are
+     */
+    pub async fn replace_collection(
+        &self,
+        database: &str,
+        collection: &Value,
+    ) -> UserDbResult<Value> {
+        let id = collection["id"].as_str().unwrap_or_default();
+        let link = format!("dbs/{}/colls/{}", database, id);
+        self.send(Method::PUT, "colls", &link, &link, Some(collection))
+            .await
+    }
+
+    /**
+     *  the offer that holds the throughput of a database or container, found by the resource's _rid.  None if it has
+     *  no throughput of its own
+     */
+    pub async fn offer_for(&self, resource_rid: &str) -> UserDbResult<Option<Value>> {
+        let query = json!({
+            "query": "SELECT * FROM root WHERE root.offerResourceId = @rid",
+            "parameters": [{ "name": "@rid", "value": resource_rid }]
+        });
+        let headers = [
+            ("x-ms-documentdb-isquery", "True".to_string()),
+            ("content-type", "application/query+json".to_string()),
+        ];
+        let mut found = self
+            .send_with(Method::POST, "offers", "", "offers", &headers, Some(&query))
+            .await?;
+        Ok(found["Offers"]
+            .as_array_mut()
+            .and_then(|offers| offers.pop()))
+    }
+
+    /**
+     *  change an offer (from offer_for) to the new throughput.  returns the offer as it is now
+     */
+    pub async fn replace_offer(
+        &self,
+        offer: &Value,
+        throughput: Throughput,
+    ) -> UserDbResult<Value> {
+        let rid = offer["_rid"].as_str().unwrap_or_default();
+        let mut offer = offer.clone();
+        offer["content"] = match throughput {
+            Throughput::Manual(ru) => json!({ "offerThroughput": ru }),
+            Throughput::Autoscale(max) => {
+                json!({ "offerAutopilotSettings": { "maxThroughput": max } })
+            }
+        };
+        // offers are addressed by _rid, and a link by _rid is signed in lower case
+        self.send(
+            Method::PUT,
+            "offers",
+            &rid.to_lowercase(),
+            &format!("offers/{}", rid),
+            Some(&offer),
+        )
+        .await
+    }
+}
+
+/**
+ *  the header that sets the throughput of a new database or container
+ */
+fn throughput_header(throughput: &Throughput) -> (&'static str, String) {
+    match throughput {
+        Throughput::Manual(ru) => ("x-ms-offer-throughput", ru.to_string()),
+        Throughput::Autoscale(max) => (
+            "x-ms-cosmos-offer-autopilot-settings",
+            json!({ "maxThroughput": max }).to_string(),
+        ),
+    }
+}
+
+/**
+ *  the throughput an offer is set to
+ */
+pub fn offer_throughput(offer: &Value) -> UserDbResult<Throughput> {
+    let content = &offer["content"];
+    let ru = |value: &Value| value.as_u64().and_then(|ru| u32::try_from(ru).ok());
+    match (
+        ru(&content["offerAutopilotSettings"]["maxThroughput"]),
+        ru(&content["offerThroughput"]),
+    ) {
+        (Some(max), _) => Ok(Throughput::Autoscale(max)),
+        (None, Some(ru)) => Ok(Throughput::Manual(ru)),
+        _ => Err(UserDbError::Backend(format!(
+            "offer {} has no throughput in it",
+            offer["id"]
+        ))),
+    }
+}
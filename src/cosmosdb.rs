@@ -1,347 +1,1361 @@
 /**
  *  this is the class that calls directly to CosmosDb --
  */
-
+use crate::auth::{self, CosmosCredential};
+use crate::config::Config;
+use crate::cosmos_rest::{
+    offer_throughput, BatchOperationResult, CosmosRest, MAX_BATCH_OPERATIONS,
+};
+use crate::error::{UserDbError, UserDbResult};
+use crate::indexing::{IndexingPolicy, IndexingReport};
 use crate::log_return_err;
-use crate::models::{CosmosSecrets, User};
+use crate::models::{
+    MigrationReport, PartialUser, SetupReport, SortOrder, User, UserFilter, UserPage, UserPatch,
+};
+use crate::partition::PartitionStrategy;
+use crate::query::QueryBuilder;
+use crate::store::{self, UserStore};
+use crate::throughput::{Throughput, ThroughputReport, ThroughputScope};
+use crate::transaction::{check_partition, TransactionOp};
 use anyhow::Result;
-use azure_core::error::{ErrorKind, Result as AzureResult};
-use log::error;
+use async_trait::async_trait;
+use azure_core::prelude::{Continuation, IfMatchCondition, MaxItemCount};
+use azure_core::{RetryOptions, TransportOptions};
 
 use azure_data_cosmos::prelude::{
-    AuthorizationToken, CollectionClient, CosmosClient, DatabaseClient, Query, QueryCrossPartition,
+    AuthorizationToken, CloudLocation, CollectionClient, CosmosClient, DatabaseClient,
+    GetDocumentResponse, QueryCrossPartition,
 };
 use futures::StreamExt;
-use log::info;
+use log::{info, warn};
+use serde_json::{json, Value};
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+// how many documents migrate_partitions reads at a time
+const MIGRATION_PAGE_SIZE: u32 = 100;
+// a count query returns at most one number per partition range, so one page of this size is almost always enough
+const COUNT_PAGE_SIZE: u32 = 1000;
+
 /**
  *  this is a convinient way to pass around meta data about CosmosDb.  UserDb will also expose methods for calling
  *  cosmos (see below)
  */
 pub struct UserDb {
-    client: Option<CosmosClient>,
-    database: Option<DatabaseClient>,
-    users_collection: Option<CollectionClient>,
+    // for what the sdk can't do
+    rest: CosmosRest,
+    database: DatabaseClient,
+    users_collection: CollectionClient,
     collection_name: String,
     database_name: String,
+    partition_key_path: String,
+    strategy: PartitionStrategy,
+    // what setupdb creates the database and collection with
+    database_throughput: Option<Throughput>,
+    container_throughput: Option<Throughput>,
 }
 /**
- *  We only use the public client in this sample.
- *
- *  there are other sample out there that do ::from_resource() for the auth token.  To set this token, do to the
- *  Azure portal and pick your CosmosDb, then pick your "Keys" on the left pane.  You'll see a page that shows
- *  secrets -- "PRIMARY KEY", "SECONDARY KEY", etc.  Click on the eye for the SECONDARY KEY so you see the content
- *  in clear text, and then copy it when the devsecrets.sh script asks for the Cosmos token.  That key needs to
- *  be converted to base64 using primary_from_base64()
+ *  where the account is -- the `endpoint` setting.  it is one of the clouds by name, or the url of anything else: the
+ *  emulator on another port, a private endpoint, or a LocalCosmos in the tests
  */
-fn public_client(account: &str, token: &str) -> CosmosClient {
-    let auth_token = match AuthorizationToken::primary_from_base64(token) {
-        Ok(token) => token,
-        Err(e) => panic!("Failed to create authorization token: {}", e),
-    };
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum CosmosEndpoint {
+    // https://{account}.documents.azure.com
+    #[default]
+    Public,
+    // https://{account}.documents.azure.cn
+    China,
+    // https://{account}.documents.azure.us
+    UsGovernment,
+    // the emulator on its default port, https://localhost:8081
+    Emulator,
+    Custom(String),
+}
+
+impl CosmosEndpoint {
+    pub fn from_name(name: &str) -> Option<Self> {
+        let name = name.trim();
+        match name.to_lowercase().as_str() {
+            "public" => Some(CosmosEndpoint::Public),
+            "china" => Some(CosmosEndpoint::China),
+            "us-government" => Some(CosmosEndpoint::UsGovernment),
+            "emulator" => Some(CosmosEndpoint::Emulator),
+            _ => match reqwest::Url::parse(name) {
+                Ok(url) if matches!(url.scheme(), "http" | "https") && url.has_host() => Some(
+                    CosmosEndpoint::Custom(name.trim_end_matches('/').to_string()),
+                ),
+                _ => None,
+            },
+        }
+    }
 
-    CosmosClient::new(account, auth_token)
+    pub fn url(&self, account: &str) -> String {
+        match self {
+            CosmosEndpoint::Public => format!("https://{}.documents.azure.com", account),
+            CosmosEndpoint::China => format!("https://{}.documents.azure.cn", account),
+            CosmosEndpoint::UsGovernment => format!("https://{}.documents.azure.us", account),
+            CosmosEndpoint::Emulator => "https://localhost:8081".to_string(),
+            CosmosEndpoint::Custom(url) => url.clone(),
+        }
+    }
+}
+
+/**
+ *  the HTTP client for both the sdk and CosmosRest.  ca_certificate is a PEM file with a certificate to trust on top of
+ *  the system ones -- e.g. the emulator's self signed one, or a private endpoint's internal CA
+ */
+fn http_client(ca_certificate: Option<&Path>) -> UserDbResult<reqwest::Client> {
+    let mut builder = reqwest::Client::builder();
+    if let Some(path) = ca_certificate {
+        let problem = |e: String| {
+            UserDbError::Validation(format!(
+                "ca_certificate {} can't be used: {}",
+                path.display(),
+                e
+            ))
+        };
+        let pem = fs::read(path).map_err(|e| problem(e.to_string()))?;
+        let certificate =
+            reqwest::Certificate::from_pem(&pem).map_err(|e| problem(e.to_string()))?;
+        builder = builder.add_root_certificate(certificate);
+    }
+    builder
+        .build()
+        .map_err(|e| UserDbError::Backend(format!("could not build the HTTP client: {}", e)))
 }
 
 /**
- *  load the secrets from environment variables and store them in a CosmosSecrets struct
+ *  the client for the account at endpoint.  how it authenticates is up to the `credential` setting -- see auth.rs for
+ *  the providers and the environment variables each one needs
  */
-pub fn get_cosmos_secrets() -> Result<CosmosSecrets, Box<dyn std::error::Error>> {
-    let token = std::env::var("COSMOS_AUTH_TOKEN")
-        .map_err(|_| "Set env variable COSMOS_AUTH_TOKEN first!")?;
+fn cosmos_client(
+    account: &str,
+    endpoint: &str,
+    auth_token: AuthorizationToken,
+    http: reqwest::Client,
+) -> CosmosClient {
+    CosmosClient::builder(account, auth_token)
+        .cloud_location(CloudLocation::Custom {
+            account: account.to_string(),
+            uri: endpoint.to_string(),
+        })
+        .transport(TransportOptions::new(Arc::new(http)))
+        // RetryingStore (retry.rs) does the retries, with what cosmos says in x-ms-retry-after-ms.  the sdk's own
+        // retry policy on top of it would multiply the attempts and the waits
+        .retry(RetryOptions::none())
+        .build()
+}
 
+/**
+ *  the name of the cosmos account comes from the environment whichever credential is used
+ */
+pub fn get_cosmos_account() -> Result<String, Box<dyn std::error::Error>> {
     let account = std::env::var("COSMOS_ACCOUNT_NAME")
         .map_err(|_| "Set env variable COSMOS_ACCOUNT_NAME first!")?;
-
-    Ok(CosmosSecrets { token, account })
+    Ok(account)
 }
 
 /**
  *  this is the scruct that contains methods to manipulate cosmosdb.  the idea is to be able to write code like
  *
- *      let mut user_db = UserDb::new().await;
- *      user_db.connec();
- *      user_db.list();
- *      user_db.create(...)
+ *      let user_db = UserDb::try_new(&config).await?;
+ *      user_db.list(...);
+ *      user_db.create_user(...)
  */
-
 impl UserDb {
-    pub async fn new(database_name: &str, collection_name: &str) -> Self {
-        match get_cosmos_secrets() {
-            Ok(secrets) => {
-                let client = public_client(secrets.account.as_str(), secrets.token.as_str());
-                let database = client.database_client(database_name.to_string());
-                let collection = database.collection_client(collection_name.to_string());
-                Self {
-                    // I have my token and my account name
-                    client: Some(client),
-                    database: Some(database),
-                    users_collection: Some(collection),
-                    database_name: database_name.to_string(),
-                    collection_name: collection_name.to_string(),
+    /**
+     *  connect to cosmos with the credential from the config and the secrets from the environment.  this fails
+     *  (instead of handing back a UserDb that can't do anything) if the secrets are missing or the key is malformed,
+     *  so main() can stop with a clear message before the server starts.  note that nothing is sent to cosmos yet, so
+     *  a key that is well formed but wrong (or an Azure AD app without access) is only found on the first request.
+     */
+    pub async fn try_new(config: &Config) -> UserDbResult<Self> {
+        let account = get_cosmos_account().map_err(|e| {
+            UserDbError::Unauthorized(format!(
+                "{}  if the secrets have been set with devsecrets.sh and you are in a dev container, you need to \
+                 restart VS Code",
+                e
+            ))
+        })?;
+        let credential = auth::credential(config.credential, &|name| std::env::var(name).ok())?;
+        Self::connect(config, &account, credential)
+    }
+
+    /**
+     *  the same as try_new, with the account and the credential passed in instead of read from the environment.  the
+     *  account is at the configured endpoint, trusting the configured ca_certificate
+     */
+    fn connect(config: &Config, account: &str, credential: CosmosCredential) -> UserDbResult<Self> {
+        let endpoint = config.endpoint.url(account);
+        let http = http_client(config.ca_certificate.as_deref())?;
+        let client = cosmos_client(
+            account,
+            &endpoint,
+            credential.authorization_token()?,
+            http.clone(),
+        );
+        let database = client.database_client(config.database_name.to_string());
+        let collection = database.collection_client(config.collection_name.to_string());
+        Ok(Self {
+            rest: CosmosRest::new(http, &endpoint, credential),
+            database,
+            users_collection: collection,
+            database_name: config.database_name.to_string(),
+            collection_name: config.collection_name.to_string(),
+            partition_key_path: config.partition_key_path.to_string(),
+            strategy: config.partition_strategy,
+            database_throughput: config.database_throughput,
+            container_throughput: config.container_throughput,
+        })
+    }
+
+    async fn create_collection(&self) -> UserDbResult<()> {
+        // note: this is where the field for the partion key is set -- if you change anything, make sure this is a
+        // member of your document struct!  the path comes from the partition_key_path setting.
+        //
+        // the unique key on /email is why this goes through CosmosRest: the sdk can't create a container with a
+        // unique key policy, and it can only be set when the container is created.  cosmos enforces unique keys
+        // within a logical partition -- see check_email_is_free for what that means for each partition strategy
+        let collection = json!({
+            "id": self.collection_name,
+            "partitionKey": {
+                "paths": [self.partition_key_path],
+                "kind": "Hash",
+                "version": 2
+            },
+            "uniqueKeyPolicy": {
+                "uniqueKeys": [{ "paths": ["/email"] }]
+            },
+            "indexingPolicy": self.desired_indexing_policy()
+        });
+        self.rest
+            .create_collection(&self.database_name, &collection, self.container_throughput)
+            .await?;
+        Ok(())
+    }
+
+    /**
+     *  the offer with the throughput that the users collection runs on: its own if it has one, or else its database's
+     */
+    async fn find_offer(&self) -> UserDbResult<(ThroughputScope, Value)> {
+        let collection = self
+            .rest
+            .get_collection(&self.database_name, &self.collection_name)
+            .await?;
+        if let Some(offer) = self.rest.offer_for(rid_of(&collection)).await? {
+            return Ok((ThroughputScope::Container, offer));
+        }
+        let database = self.rest.get_database(&self.database_name).await?;
+        match self.rest.offer_for(rid_of(&database)).await? {
+            Some(offer) => Ok((ThroughputScope::Database, offer)),
+            None => Err(UserDbError::NotFound(format!(
+                "neither collection {} nor database {} has provisioned throughput.  a serverless account has none",
+                self.collection_name, self.database_name
+            ))),
+        }
+    }
+
+    /**
+     *  a collection that is already there has to be partitioned the way partition_key_path says, or every write would
+     *  fail.  the partition key can't be changed on an existing collection, so this is an error for a person to sort
+     *  out.  a missing unique key on /email only weakens the email check, so that is just a warning
+     */
+    fn check_collection(&self, existing: &Value) -> UserDbResult<()> {
+        let paths = &existing["partitionKey"]["paths"];
+        if paths != &json!([self.partition_key_path]) {
+            return Err(UserDbError::Conflict(format!(
+                "collection {} is partitioned on {} but partition_key_path is {}.  change the setting, or move the \
+                 data to a new collection",
+                self.collection_name, paths, self.partition_key_path
+            )));
+        }
+        let unique_emails = existing["uniqueKeyPolicy"]["uniqueKeys"]
+            .as_array()
+            .is_some_and(|keys| keys.iter().any(|key| key["paths"] == json!(["/email"])));
+        if !unique_emails {
+            warn!(
+                "collection {} has no unique key on /email, so two concurrent writes can give users the same email.  \
+                 a unique key can only be added by recreating the collection",
+                self.collection_name
+            );
+        }
+        let changes =
+            IndexingPolicy::of_collection(existing).changes_to(&self.desired_indexing_policy());
+        if !changes.is_empty() {
+            warn!(
+                "collection {} doesn't have the indexing policy in indexing.rs ({}).  POST /admin/indexing updates it",
+                self.collection_name,
+                changes.join(", ")
+            );
+        }
+        Ok(())
+    }
+
+    fn desired_indexing_policy(&self) -> IndexingPolicy {
+        IndexingPolicy::for_users(&self.partition_key_path)
+    }
+
+    fn indexing_report(&self, current: IndexingPolicy, changes: Vec<String>) -> IndexingReport {
+        IndexingReport {
+            up_to_date: changes.is_empty(),
+            changes,
+            current,
+            desired: self.desired_indexing_policy(),
+        }
+    }
+
+    /**
+     * Execute a query and return a single page of at most page_size users.  the continuation token that cosmos
+     * returns is passed back to the caller so that it can ask for the next page.  queries come from QueryBuilder so
+     * that every value is sent as a parameter.
+     */
+    async fn execute_query_page(
+        &self,
+        query: QueryBuilder,
+        page_size: u32,
+        continuation: Option<String>,
+    ) -> UserDbResult<UserPage> {
+        let (documents, continuation) = self
+            .query_documents_page(query, page_size, continuation)
+            .await?;
+        let mut users = Vec::new();
+        for doc in documents {
+            let user: User = serde_json::from_value(doc)?;
+            users.push(user);
+        }
+        Ok(UserPage {
+            users,
+            continuation,
+        })
+    }
+
+    /**
+     *  the first user the query finds that passes keep, or None.  a cross partition query can come back with an empty
+     *  page and a continuation while other partitions are still being read, so one page isn't enough to say there is
+     *  no such user -- this follows the continuations until it finds one or they run out
+     */
+    async fn find_first(
+        &self,
+        query: QueryBuilder,
+        page_size: u32,
+        keep: impl Fn(&User) -> bool + Send,
+    ) -> UserDbResult<Option<User>> {
+        let mut continuation = None;
+        loop {
+            let page = self
+                .execute_query_page(query.clone(), page_size, continuation)
+                .await?;
+            if let Some(user) = page.users.into_iter().find(|user| keep(user)) {
+                return Ok(Some(user));
+            }
+            match page.continuation {
+                Some(token) if !token.is_empty() => continuation = Some(token),
+                _ => return Ok(None),
+            }
+        }
+    }
+
+    /**
+     *  the same as execute_query_page, but the documents are left as JSON for callers that need to see exactly what is
+     *  stored (e.g. whether partition_key is the old number or a string)
+     */
+    async fn query_documents_page(
+        &self,
+        query: QueryBuilder,
+        page_size: u32,
+        continuation: Option<String>,
+    ) -> UserDbResult<(Vec<Value>, Option<String>)> {
+        info!("query: {}", query.text());
+        let query = query.build();
+
+        let mut builder = self
+            .users_collection
+            .query_documents(query)
+            .query_cross_partition(QueryCrossPartition::Yes)
+            .max_item_count(MaxItemCount::new(page_size as i32));
+        if let Some(token) = continuation {
+            builder = builder.continuation(Continuation::from(token));
+        }
+
+        let mut stream = builder.into_stream::<Value>();
+        match stream.next().await {
+            Some(Ok(response)) => Ok((
+                response.documents().cloned().collect(),
+                response.continuation_token.map(|token| token.as_string()),
+            )),
+            Some(Err(e)) => log_return_err!(e),
+            None => Ok((Vec::new(), None)),
+        }
+    }
+
+    /**
+     *  the WHERE clause for a UserFilter, added to query.  every value is a parameter
+     */
+    fn where_filter(&self, mut query: QueryBuilder, filter: &UserFilter) -> QueryBuilder {
+        if let Some(prefix) = &filter.name_prefix {
+            query = query.where_starts_with("name", prefix, true);
+        }
+        if let Some(email) = &filter.email {
+            query = query.where_eq("email", email.as_str());
+        }
+        if let Some(domain) = &filter.email_domain {
+            // with the email-domain strategy the domain is the partition key, so the query stays in one partition
+            if self.strategy == PartitionStrategy::EmailDomain {
+                query = query.where_eq("partition_key", domain.as_str());
+            }
+            query = query.where_ends_with("email", &format!("@{}", domain));
+        }
+        query
+    }
+
+    /**
+     *  run operations that all go to one partition as transactional batches of MAX_BATCH_OPERATIONS, with one result
+     *  per operation.  each batch is all or nothing, so when one operation fails the rest of its batch come back as
+     *  FailedDependency
+     */
+    async fn run_transactional(
+        &self,
+        partition_key: &str,
+        operations: Vec<Value>,
+    ) -> Vec<UserDbResult<BatchOperationResult>> {
+        let mut results = Vec::with_capacity(operations.len());
+        for chunk in operations.chunks(MAX_BATCH_OPERATIONS) {
+            let outcomes = self
+                .rest
+                .transactional_batch(
+                    &self.database_name,
+                    &self.collection_name,
+                    partition_key,
+                    chunk,
+                )
+                .await;
+            match outcomes {
+                Ok(outcomes) => results.extend(outcomes.into_iter().map(|outcome| {
+                    match outcome.status {
+                        200..=299 => Ok(outcome),
+                        424 => Err(UserDbError::FailedDependency(
+                            "not written because another operation in the same batch failed"
+                                .to_string(),
+                        )),
+                        status => Err(UserDbError::from_status(
+                            status,
+                            format!("batch operation failed with {}", status),
+                            outcome.retry_after_ms.map(Duration::from_millis),
+                        )),
+                    }
+                })),
+                Err(e) => {
+                    warn!("transactional batch failed: {}", e);
+                    results.extend(chunk.iter().map(|_| Err(e.clone())));
                 }
             }
-            Err(..) => {
-                error!("{}",
-                "Error getting cosmos secrets.  if they have been set with devsecrets.sh, you need to restart vscode!");
-                Self {
-                    client: None,
-                    database: None,
-                    users_collection: None,
-                    database_name: "".to_string(),
-                    collection_name: "".to_string(),
+        }
+        results
+    }
+
+    /**
+     *  a point read of one user, with its etag
+     */
+    async fn read_user(&self, user_id: &str, partition_key: &str) -> UserDbResult<User> {
+        let doc_client = self
+            .users_collection
+            .document_client(user_id, &partition_key.to_string())?;
+        match doc_client.get_document::<User>().await {
+            Ok(GetDocumentResponse::Found(response)) => {
+                let mut user = response.document.document;
+                user.etag = Some(response.document.document_attributes.etag().to_string());
+                Ok(user)
+            }
+            Ok(GetDocumentResponse::NotFound(..)) => Err(UserDbError::user_not_found(user_id)),
+            Err(e) => log_return_err!(e),
+        }
+    }
+
+    /**
+     *  one transaction operation as a batch operation, and the user it writes (None for a delete).  a batch has no
+     *  patch we can use with our etag rules, so a patch reads the user, applies the patch here and sends a replace that
+     *  only works if the user still has the etag we read
+     */
+    async fn batch_operation(
+        &self,
+        partition_key: &str,
+        operation: TransactionOp,
+    ) -> UserDbResult<(Value, Option<User>)> {
+        let (operation_type, mut user, if_match) = match operation {
+            TransactionOp::Create { user } => ("Create", User::from(user), None),
+            TransactionOp::Upsert { id, user } => ("Upsert", user.with_id(id), None),
+            TransactionOp::Replace { id, user, if_match } => {
+                ("Replace", user.with_id(id), if_match)
+            }
+            TransactionOp::Patch {
+                id,
+                patch,
+                if_match,
+            } => {
+                let mut user = self.read_user(&id, partition_key).await?;
+                if let Some(etag) = if_match.filter(|etag| etag != "*") {
+                    if user.etag.as_ref() != Some(&etag) {
+                        return Err(UserDbError::user_changed(&id));
+                    }
                 }
+                let etag = user.etag.take();
+                patch.apply_to(&mut user);
+                ("Replace", user, etag)
+            }
+            TransactionOp::Delete { id, if_match } => {
+                let operation = json!({"operationType": "Delete", "id": id});
+                return Ok((with_if_match(operation, if_match), None));
             }
+        };
+        user.partition_key = self.strategy.key_for(&user);
+        check_partition(partition_key, &user)?;
+        self.check_email_is_free(&user.email, &user.id).await?;
+        let operation = json!({
+            "operationType": operation_type,
+            "id": user.id,
+            "resourceBody": user,
+        });
+        Ok((with_if_match(operation, if_match), Some(user)))
+    }
+
+    /**
+     *  the partition a user is in.  most strategies work it out from the id; for the others we have to find the user
+     *  first
+     */
+    async fn partition_of(&self, user_id: &str) -> UserDbResult<String> {
+        match self.strategy.key_for_id(user_id) {
+            Some(partition_key) => Ok(partition_key),
+            None => Ok(self.find_user(user_id).await?.partition_key),
         }
     }
 
     /**
-     *  setup the database to make the sample work.  NOTE:  this will DELETE the database first.  to call this:
-     *
-     *  let userdb = UserDb::new();
-     *  userdb.setupdb()
+     *  the unique key policy on /email only covers one logical partition.  with the single and email-domain strategies
+     *  every user with a given email is in the same partition, so cosmos enforces it for us.  with id-hash two users
+     *  with the same email are usually in different buckets, so we look for the email across partitions before we
+     *  write.  that check and the write aren't atomic -- two requests racing with the same new email can both get
+     *  through -- so pick email-domain if the constraint has to be airtight.
      */
-    pub async fn setupdb(&self) -> AzureResult<()> {
-        info!("Deleting existing database");
+    async fn check_email_is_free(&self, email: &str, user_id: &str) -> UserDbResult<()> {
+        if self.strategy.unique_keys_are_global() {
+            return Ok(());
+        }
+        let query = QueryBuilder::select_all().where_eq("email", email);
+        match self.find_first(query, 2, |user| user.id != user_id).await? {
+            Some(..) => Err(UserDbError::email_taken(email)),
+            None => Ok(()),
+        }
+    }
 
-        match self.database.as_ref().unwrap().delete_database().await {
-            Ok(..) => info!("\tDeleted {} database", self.database_name),
-            Err(e) => {
-                if format!("{}", e).contains("404") {
-                    info!("\tDatabase {} not found", self.database_name);
-                } else {
-                    log_return_err!(e)
+    /**
+     *  the partition key can't be changed in place -- a document's identity is its id *and* its partition key.  so to
+     *  move a user we create the copy in the new partition and then delete the original (only if it still has the
+     *  etag we read).  if that delete fails the copy is removed again so the user isn't left in two places.
+     */
+    async fn move_user(&self, mut user: User, from: &str, etag: String) -> UserDbResult<User> {
+        user.etag = None;
+        let created = match self.users_collection.create_document(user.clone()).await {
+            Ok(response) => response,
+            Err(e) => match UserDbError::from(e) {
+                e if e.is_unique_key_violation() => {
+                    return Err(UserDbError::email_taken(&user.email))
                 }
+                e => log_return_err!(e),
+            },
+        };
+        let delete = self
+            .users_collection
+            .document_client(user.id.clone(), &from.to_string())?
+            .delete_document()
+            .if_match_condition(IfMatchCondition::Match(etag))
+            .await;
+        if let Err(e) = delete {
+            let copy = self
+                .users_collection
+                .document_client(user.id.clone(), &user.partition_key)?;
+            if let Err(cleanup) = copy.delete_document().await {
+                warn!(
+                    "could not remove the copy of user {} in partition {}: {}",
+                    user.id, user.partition_key, cleanup
+                );
             }
+            return match UserDbError::from(e) {
+                UserDbError::PreconditionFailed(..) => Err(UserDbError::user_changed(&user.id)),
+                e => log_return_err!(e),
+            };
         }
+        user.etag = Some(created.document_attributes.etag().to_string());
+        Ok(user)
+    }
+}
 
-        info!("Creating new database");
-        match self
-            .client
-            .as_ref()
-            .unwrap()
-            .create_database(self.database_name.to_string())
+#[async_trait]
+impl UserStore for UserDb {
+    /**
+     *  setup the database to make the sample work.  only what is missing is created, so this is safe to call on a
+     *  database that is in use.  to call this:
+     *
+     *  let userdb = UserDb::try_new(&config).await?;
+     *  userdb.setupdb()
+     */
+    async fn setupdb(&self) -> UserDbResult<SetupReport> {
+        info!(
+            "Creating database {} if it doesn't exist",
+            self.database_name
+        );
+        let database_created = match self
+            .rest
+            .create_database(&self.database_name, self.database_throughput)
             .await
         {
-            Ok(..) => info!("\tCreated database"),
+            Ok(..) => {
+                info!("\tCreated database");
+                true
+            }
+            Err(UserDbError::Conflict(..)) => {
+                info!("\tDatabase {} already exists", self.database_name);
+                false
+            }
             Err(e) => log_return_err!(e),
-        }
+        };
 
-        info!("Creating collections");
-        match self
-            .database
-            .as_ref()
-            .unwrap()
-            // note: this is where the field for the partion key is set -- if you change anything, make sure this is
-            // a member of your document struct!
-            .create_collection(self.collection_name.to_string(), "/partition_key")
+        info!(
+            "Creating collection {} if it doesn't exist",
+            self.collection_name
+        );
+        let collection_created = match self
+            .rest
+            .get_collection(&self.database_name, &self.collection_name)
             .await
         {
-            Ok(..) => {
-                info!("\tCreated {} collection", self.collection_name);
-                Ok(())
+            Ok(existing) => {
+                self.check_collection(&existing)?;
+                info!("\tCollection {} already exists", self.collection_name);
+                false
+            }
+            Err(UserDbError::NotFound(..)) => match self.create_collection().await {
+                Ok(..) => {
+                    info!("\tCreated {} collection", self.collection_name);
+                    true
+                }
+                // another instance created it between the two calls
+                Err(UserDbError::Conflict(..)) => false,
+                Err(e) => log_return_err!(e),
             },
             Err(e) => log_return_err!(e),
-        }
+        };
+        Ok(SetupReport {
+            database_created,
+            collection_created,
+        })
+    }
 
+    /**
+     *  NOTE:  this DELETES the database and everything in it, and then sets it up again
+     */
+    async fn reset(&self) -> UserDbResult<SetupReport> {
+        warn!("Deleting database {}", self.database_name);
+        match self.database.delete_database().await {
+            Ok(..) => info!("\tDeleted {} database", self.database_name),
+            Err(e) => match UserDbError::from(e) {
+                UserDbError::NotFound(..) => {
+                    info!("\tDatabase {} not found", self.database_name)
+                }
+                e => log_return_err!(e),
+            },
+        }
+        self.setupdb().await
     }
     /**
-     *  this will return *all* (non paginated) Users in the collection
+     *  this will return one page of Users in the collection.  pass the continuation from the last page to get the next.
+     *  users are spread over many partitions, so this is a cross partition query.  the filter is turned into a WHERE
+     *  clause with a parameter for every value, and the sort field can only be one that SortField lists
      */
-    pub async fn list(&self) -> AzureResult<Vec<User>> {
-        let query = r#"SELECT * FROM c WHERE c.partition_key=1"#;
-        match self.execute_query(query).await {
-            Ok(users) => Ok(users),
+    async fn list(
+        &self,
+        filter: &UserFilter,
+        page_size: u32,
+        continuation: Option<String>,
+    ) -> UserDbResult<UserPage> {
+        let mut query = self.where_filter(QueryBuilder::select_all(), filter);
+        if let Some(sort) = filter.order_by {
+            query = query.order_by(sort.field(), filter.order == SortOrder::Desc);
+        }
+        match self
+            .execute_query_page(query, page_size, continuation)
+            .await
+        {
+            Ok(page) => Ok(page),
             Err(e) => log_return_err!(e),
         }
     }
     /**
-     * Execute an arbitrary query against the user database and return a list of users
+     *  SELECT VALUE COUNT(1) with the same WHERE clause as list.  the count is added up over every page, since a cross
+     *  partition aggregate comes back as a partial count from each partition range
      */
-    async fn execute_query(&self, query_string: &str) -> AzureResult<Vec<User>> {
-        let mut users = Vec::new();
-        let query = Query::new(query_string.to_string());
-
-        let mut stream = self
-            .users_collection
-            .as_ref()
-            .unwrap()
-            .query_documents(query)
-            .query_cross_partition(QueryCrossPartition::Yes)
-            .into_stream::<serde_json::Value>();
-        //
-        // this just matches what list does, but only returns the first one
-        // we are getting an error right now, but nothing to indicate what the error is.
-        while let Some(response) = stream.next().await {
-            match response {
-                Ok(response) => {
-                    info!("\n{:#?}", response);
-                    for doc in response.documents() {
-                        // Process the document
-                        let user: User = serde_json::from_value(doc.clone())?;
-                        users.push(user);
-                    }
-                    return Ok(users); // return user if found
-                }
-                Err(e) => {
-                    log_return_err!(e)
-                }
+    async fn count(&self, filter: &UserFilter) -> UserDbResult<u64> {
+        let query = self.where_filter(QueryBuilder::select_count(), filter);
+        let mut count = 0;
+        let mut continuation = None;
+        loop {
+            let (counts, next) = self
+                .query_documents_page(query.clone(), COUNT_PAGE_SIZE, continuation)
+                .await?;
+            count += counts.iter().filter_map(Value::as_u64).sum::<u64>();
+            match next {
+                Some(token) if !token.is_empty() => continuation = Some(token),
+                _ => return Ok(count),
             }
         }
-        Err(azure_core::Error::new(ErrorKind::Other, "User not found")) // return error if user not found
     }
-
     /**
      *  an api that creates a user in the cosmosdb users collection. in this sample, we return
-     *  the full User object in the body, giving the client the partition_key and user id
+     *  the full User object in the body, giving the client the partition_key and user id.  cosmos gives the new
+     *  document its etag, which we copy onto the user we return
      */
-    pub async fn create_user(&self, user: User) -> AzureResult<()> {
+    async fn create_user(&self, mut user: User) -> UserDbResult<User> {
+        self.check_email_is_free(&user.email, &user.id).await?;
+        user.partition_key = self.strategy.key_for(&user);
+        user.etag = None;
+        match self.users_collection.create_document(user.clone()).await {
+            Ok(response) => {
+                user.etag = Some(response.document_attributes.etag().to_string());
+                Ok(user)
+            }
+            Err(e) => match UserDbError::from(e) {
+                e if e.is_unique_key_violation() => Err(UserDbError::email_taken(&user.email)),
+                UserDbError::Conflict(..) => Err(UserDbError::user_exists(&user.id)),
+                e => log_return_err!(e),
+            },
+        }
+    }
+    /**
+     *  an upsert_document.  with the email-domain strategy an existing user with a new domain would be left behind in
+     *  its old partition, so if the user exists somewhere else this is a replace, which moves it
+     */
+    async fn upsert_user(&self, user_id: &str, user: PartialUser) -> UserDbResult<User> {
+        let mut user = user.with_id(user_id.to_string());
+        user.partition_key = self.strategy.key_for(&user);
+        if self.strategy.key_for_id(user_id).is_none() {
+            match self.find_user(user_id).await {
+                Ok(existing) if existing.partition_key != user.partition_key => {
+                    let replacement = PartialUser {
+                        id: None,
+                        email: user.email,
+                        name: user.name,
+                    };
+                    return self.replace_user(user_id, replacement, None).await;
+                }
+                Ok(..) | Err(UserDbError::NotFound(..)) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        self.check_email_is_free(&user.email, &user.id).await?;
         match self
-            .database
-            .as_ref()
-            .unwrap()
-            .collection_client(self.collection_name.to_string())
+            .users_collection
             .create_document(user.clone())
+            .is_upsert(true)
             .await
         {
-            Ok(..) => match serde_json::to_string(&user) {
-                Ok(..) => Ok(()),
-                Err(e) => Err(e.into()),
+            Ok(response) => {
+                user.etag = Some(response.document_attributes.etag().to_string());
+                Ok(user)
+            }
+            Err(e) => match UserDbError::from(e) {
+                e if e.is_unique_key_violation() => Err(UserDbError::email_taken(&user.email)),
+                e => log_return_err!(e),
             },
-            Err(e) => log_return_err!(e),
         }
     }
     /**
-     *  delete the user with the unique id
+     *  a PUT is a patch that sets every field
      */
-    pub async fn delete_user(&self, unique_id: &str) -> AzureResult<()> {
-        let collection = self.users_collection.as_ref().unwrap();
-        let doc_client = collection.document_client(unique_id, &1)?;
-        match doc_client.delete_document().await {
-            Ok(..) => Ok(()),
-            Err(e) => log_return_err!(e),
-        }
+    async fn replace_user(
+        &self,
+        user_id: &str,
+        user: PartialUser,
+        if_match: Option<String>,
+    ) -> UserDbResult<User> {
+        self.patch_user(user_id, user.into(), if_match).await
     }
     /**
-     *  an api that finds a user by the id in the cosmosdb users collection.
+     *  read the current document, apply the changes, and write the whole document back with replace_document.  the
+     *  id is kept, so the user keeps its identity.  if the change puts the user in another partition (a new email
+     *  domain with the email-domain strategy) the document is moved instead.
+     *
+     *  the replace is conditional on the etag we read, so if somebody else writes the document between our read and
+     *  our write, cosmos fails the replace with a 412 instead of losing their change
      */
-    pub async fn find_user(&self, user_id: &str) -> AzureResult<User> {
-        let query = format!(r#"SELECT * FROM c WHERE c.id = '{}'"#, user_id);
-        match self.execute_query(&query).await {
-            Ok(users) => {
-                if !users.is_empty() {
-                    Ok(users.first().unwrap().clone()) // clone is necessary because `first()` returns a reference
-                } else {
-                    Err(azure_core::Error::new(ErrorKind::Other, "User not found"))
-                }
+    async fn patch_user(
+        &self,
+        user_id: &str,
+        patch: UserPatch,
+        if_match: Option<String>,
+    ) -> UserDbResult<User> {
+        let mut user = self.find_user(user_id).await?;
+        let current_etag = user.etag.take().unwrap_or_default();
+        if let Some(etag) = if_match {
+            if etag != "*" && etag != current_etag {
+                return Err(UserDbError::user_changed(user_id));
             }
-            Err(e) => log_return_err!(e),
         }
-    }
-}
-#[cfg(test)]
-mod tests {
-
-    use std::iter;
+        let new_email = patch
+            .email
+            .as_ref()
+            .filter(|email| **email != user.email)
+            .cloned();
+        if let Some(email) = new_email {
+            self.check_email_is_free(&email, user_id).await?;
+        }
+        patch.apply_to(&mut user);
 
-    use crate::utility::get_id;
+        let partition_key = self.strategy.key_for(&user);
+        if partition_key != user.partition_key {
+            let from = std::mem::replace(&mut user.partition_key, partition_key);
+            return self.move_user(user, &from, current_etag).await;
+        }
 
-    use super::*;
-    use log::trace;
-    use rand::Rng;
-    #[tokio::test]
-    async fn test_e2e() {
-        env_logger::init();
-        let _ = env_logger::builder().is_test(true).try_init();
-        // load secrets
-        let secrets = get_cosmos_secrets();
-        match secrets {
-            Ok(secrets) => trace!("Secrets found.  Account: {:?}", secrets.account),
-            Err(error) => panic!("Failed to get secrets: {}", error),
+        let doc_client = self
+            .users_collection
+            .document_client(user_id, &user.partition_key)?;
+        match doc_client
+            .replace_document(user.clone())
+            .if_match_condition(IfMatchCondition::Match(current_etag))
+            .await
+        {
+            Ok(response) => {
+                user.etag = Some(response.document_attributes.etag().to_string());
+                Ok(user)
+            }
+            Err(e) => match UserDbError::from(e) {
+                UserDbError::PreconditionFailed(..) => Err(UserDbError::user_changed(user_id)),
+                e if e.is_unique_key_violation() => Err(UserDbError::email_taken(&user.email)),
+                e => log_return_err!(e),
+            },
         }
-        let db_name = "user-test-db";
-        let collection_name = "user-test-collection";
-        // create the database -- note this will DELETE the database as well
-        let user_db = UserDb::new(db_name, collection_name).await;
-        match user_db.setupdb().await {
-            Ok(..) => trace!("created test db and collection"),
-            Err(e) => panic!("failed to setup database and collection {}", e),
-        }
-        // create users and add them to the database
-        let users = create_users();
-        for user in users {
-            let user_clone = user.clone();
-            match user_db.create_user(user_clone).await {
-                Ok(..) => trace!("created user {}", user.id),
-                Err(e) => panic!("failed to create user.  err: {}", e),
+    }
+    /**
+     *  delete the user with the unique id.  if the client sent an etag, cosmos only deletes the document if it matches
+     */
+    async fn delete_user(&self, unique_id: &str, if_match: Option<String>) -> UserDbResult<()> {
+        let partition_key = self.partition_of(unique_id).await?;
+        let doc_client = self
+            .users_collection
+            .document_client(unique_id, &partition_key)?;
+        let mut delete = doc_client.delete_document();
+        if let Some(etag) = if_match.filter(|etag| etag != "*") {
+            delete = delete.if_match_condition(IfMatchCondition::Match(etag));
+        }
+        match delete.await {
+            Ok(..) => Ok(()),
+            Err(e) => match UserDbError::from(e) {
+                UserDbError::NotFound(..) => Err(UserDbError::user_not_found(unique_id)),
+                UserDbError::PreconditionFailed(..) => Err(UserDbError::user_changed(unique_id)),
+                e => log_return_err!(e),
+            },
+        }
+    }
+    /**
+     *  an api that finds a user by the id in the cosmosdb users collection.  when the strategy gives us the partition
+     *  key from the id this is a point read of the one document, otherwise it is a query by id across partitions
+     */
+    async fn find_user(&self, user_id: &str) -> UserDbResult<User> {
+        let partition_key = match self.strategy.key_for_id(user_id) {
+            Some(partition_key) => partition_key,
+            None => {
+                let query = QueryBuilder::select_all().where_eq("id", user_id);
+                return match self.find_first(query, 1, |_| true).await? {
+                    Some(user) => Ok(user),
+                    None => Err(UserDbError::user_not_found(user_id)),
+                };
             }
+        };
+        self.read_user(user_id, &partition_key).await
+    }
+    /**
+     *  when every user goes in the same partition (always with the single strategy, and when they share a domain with
+     *  email-domain) they are written with transactional batches, which is one round trip per 100 users.  otherwise
+     *  they are created one by one, a few at a time.  batches are only used when the unique key policy covers the
+     *  whole container, because the email check that id-hash needs can't be part of a batch.  a batch is all or
+     *  nothing, so when one user in it fails the others come back FailedDependency without being written.  those are
+     *  then created one by one, so each user gets its own outcome like it would from any other store.  a throttled
+     *  batch is left to RetryingStore, which sends it again whole
+     */
+    async fn create_users(&self, mut users: Vec<User>) -> Vec<UserDbResult<User>> {
+        for user in users.iter_mut() {
+            user.partition_key = self.strategy.key_for(user);
+            user.etag = None;
         }
-
-        // get a list of all users
-        let users: Vec<User> = match user_db.list().await {
-            Ok(u) => {
-                trace!("all_users returned success");
-                u
+        let partition_key = match users.first() {
+            Some(first)
+                if users.len() > 1
+                    && self.strategy.unique_keys_are_global()
+                    && users.iter().all(|u| u.partition_key == first.partition_key) =>
+            {
+                first.partition_key.clone()
             }
-            Err(e) => panic!("failed to setup database and collection {}", e),
+            _ => return store::bounded(users.into_iter().map(|user| self.create_user(user))).await,
         };
 
-        if let Some(first_user) = users.first() {
-            let u = user_db.find_user(&first_user.id).await;
-            match u {
-                Ok(found_user) => trace!("found user with id: {}", found_user.id),
-                Err(e) => panic!("failed to find user that we just inserted. error: {}", e),
+        let operations = users
+            .iter()
+            .map(|user| json!({"operationType": "Create", "resourceBody": user}))
+            .collect();
+        let outcomes = self.run_transactional(&partition_key, operations).await;
+        let mut results = Vec::with_capacity(users.len());
+        for (mut user, outcome) in users.iter().cloned().zip(outcomes) {
+            results.push(match outcome {
+                // the body is the document as stored, _etag and all
+                Ok(BatchOperationResult {
+                    body: Some(body), ..
+                }) => serde_json::from_value(body).map_err(UserDbError::from),
+                Ok(outcome) => {
+                    user.etag = outcome.etag;
+                    Ok(user)
+                }
+                Err(e) if e.is_unique_key_violation() => Err(UserDbError::email_taken(&user.email)),
+                // a batch result is just a status, with no message to tell a taken id from a taken email.  nothing
+                // in the batch was written, so if the id is there it was there before
+                Err(UserDbError::Conflict(..)) => match self
+                    .read_user(&user.id, &partition_key)
+                    .await
+                {
+                    Ok(..) => Err(UserDbError::user_exists(&user.id)),
+                    Err(UserDbError::NotFound(..)) => Err(UserDbError::email_taken(&user.email)),
+                    Err(e) => Err(e),
+                },
+                Err(e) => Err(e),
+            });
+        }
+
+        let mut again = Vec::new();
+        for (chunk, outcomes) in results.chunks(MAX_BATCH_OPERATIONS).enumerate() {
+            let failed = outcomes.iter().any(|outcome| {
+                matches!(outcome, Err(e) if !matches!(e, UserDbError::Throttled { .. } | UserDbError::FailedDependency(..)))
+            });
+            if failed {
+                again.extend(
+                    outcomes
+                        .iter()
+                        .enumerate()
+                        .filter(|(_, outcome)| {
+                            matches!(outcome, Err(UserDbError::FailedDependency(..)))
+                        })
+                        .map(|(index, _)| chunk * MAX_BATCH_OPERATIONS + index),
+                );
             }
-        } else {
-            panic!("the list should not be empty since we just filled it up!")
         }
-        //
-        //  delete all the users
-        for user in users {
-            let result = user_db.delete_user(&user.id).await;
-            match result {
-                Ok(_) => {
-                    trace!("deleted user with id: {}", &user.id);
+        let retry: Vec<User> = again.iter().map(|index| users[*index].clone()).collect();
+        let created = store::bounded(retry.into_iter().map(|user| self.create_user(user))).await;
+        for (index, result) in again.into_iter().zip(created) {
+            results[index] = result;
+        }
+        results
+    }
+    /**
+     *  the whole transaction is one transactional batch.  every operation is checked (and a patch is read) before the
+     *  batch is sent, so most mistakes never get to cosmos.  if cosmos fails an operation, the others come back as
+     *  FailedDependency and the error of the one that failed is returned
+     */
+    async fn execute_transaction(
+        &self,
+        partition_key: &str,
+        operations: Vec<TransactionOp>,
+    ) -> UserDbResult<Vec<Option<User>>> {
+        let mut batch = Vec::with_capacity(operations.len());
+        let mut users = Vec::with_capacity(operations.len());
+        for (index, operation) in operations.into_iter().enumerate() {
+            let (operation, user) = self
+                .batch_operation(partition_key, operation)
+                .await
+                .map_err(|e| e.in_operation(index))?;
+            batch.push(operation);
+            users.push(user);
+        }
+
+        let outcomes = self.run_transactional(partition_key, batch).await;
+        let failed = outcomes
+            .iter()
+            .enumerate()
+            .find_map(|(index, outcome)| match outcome {
+                Ok(..) | Err(UserDbError::FailedDependency(..)) => None,
+                Err(e) => Some(e.clone().in_operation(index)),
+            });
+        if let Some(e) = failed {
+            return Err(e);
+        }
+        users
+            .into_iter()
+            .zip(outcomes)
+            .map(|(user, outcome)| {
+                let outcome = outcome?;
+                Ok(match (user, outcome.body) {
+                    (None, _) => None,
+                    (Some(..), Some(body)) => Some(serde_json::from_value(body)?),
+                    (Some(mut user), None) => {
+                        user.etag = outcome.etag;
+                        Some(user)
+                    }
+                })
+            })
+            .collect()
+    }
+    /**
+     *  walk every document and move the ones that aren't where the strategy puts them, including the ones from older
+     *  versions of this sample with the number 1 as their partition key.  each move is create-then-delete, so if this
+     *  stops part way a user can be in both partitions, and running it again finishes the job: the create finds the
+     *  copy already there (409) and the delete goes ahead.  reads by id use the new partition as soon as the strategy
+     *  changes, so run this right after deploying a new strategy.
+     */
+    async fn migrate_partitions(&self) -> UserDbResult<MigrationReport> {
+        info!(
+            "migrating users to the {} partition strategy",
+            self.strategy.name()
+        );
+        let mut report = MigrationReport::default();
+        // the copies land in partitions the scan may not have reached yet, so they come up again later
+        let mut moved = HashSet::new();
+        let mut continuation = None;
+        loop {
+            let (documents, next) = self
+                .query_documents_page(
+                    QueryBuilder::select_all(),
+                    MIGRATION_PAGE_SIZE,
+                    continuation,
+                )
+                .await?;
+            for doc in documents {
+                let mut user: User = serde_json::from_value(doc.clone())?;
+                let partition_key = self.strategy.key_for(&user);
+                // compare the raw JSON: 1 and "1" are different partition keys to cosmos
+                let stored_key = doc.get("partition_key").cloned().unwrap_or(Value::Null);
+                let in_place = stored_key == Value::String(partition_key.clone());
+                if in_place && moved.contains(&user.id) {
+                    continue;
                 }
-                Err(e) => {
-                    panic!("failed to delete user. error: {:#?}", e)
+                report.scanned += 1;
+                if in_place {
+                    continue;
+                }
+
+                user.partition_key = partition_key;
+                user.etag = None;
+                if let Err(e) = self.users_collection.create_document(user.clone()).await {
+                    match UserDbError::from(e) {
+                        e if e.is_unique_key_violation() => {
+                            log_return_err!(UserDbError::email_taken(&user.email))
+                        }
+                        // an earlier run got as far as the copy
+                        UserDbError::Conflict(..) => {}
+                        e => log_return_err!(e),
+                    }
+                }
+                let original = self
+                    .users_collection
+                    .document_client(user.id.clone(), &stored_key)?;
+                if let Err(e) = original.delete_document().await {
+                    match UserDbError::from(e) {
+                        UserDbError::NotFound(..) => {}
+                        e => log_return_err!(e),
+                    }
                 }
+                report.moved += 1;
+                moved.insert(user.id);
+            }
+            match next {
+                Some(token) if !token.is_empty() => continuation = Some(token),
+                _ => break,
             }
         }
+        info!("\tscanned {} users, moved {}", report.scanned, report.moved);
+        Ok(report)
+    }
 
-        // get the list of users again -- should be empty
-        let users: Vec<User> = match user_db.list().await {
-            Ok(u) => {
-                trace!("all_users returned success");
-                u
-            }
-            Err(e) => panic!("failed to setup database and collection {}", e),
+    async fn indexing_policy(&self) -> UserDbResult<IndexingReport> {
+        let collection = self
+            .rest
+            .get_collection(&self.database_name, &self.collection_name)
+            .await?;
+        let current = IndexingPolicy::of_collection(&collection);
+        let changes = current.changes_to(&self.desired_indexing_policy());
+        Ok(self.indexing_report(current, changes))
+    }
+
+    /**
+     *  the rest of the collection's definition is sent back as it was.  cosmos answers once the policy is saved, and
+     *  then reindexes in the background -- until that is done, queries that filter or sort on a newly indexed path
+     *  can miss documents
+     */
+    async fn apply_indexing_policy(&self) -> UserDbResult<IndexingReport> {
+        let mut collection = self
+            .rest
+            .get_collection(&self.database_name, &self.collection_name)
+            .await?;
+        let desired = self.desired_indexing_policy();
+        let changes = IndexingPolicy::of_collection(&collection).changes_to(&desired);
+        if changes.is_empty() {
+            return Ok(self.indexing_report(desired, changes));
+        }
+        info!(
+            "Updating the indexing policy of {}: {}",
+            self.collection_name,
+            changes.join(", ")
+        );
+        collection["indexingPolicy"] = serde_json::to_value(&desired)?;
+        let replaced = match self
+            .rest
+            .replace_collection(&self.database_name, &collection)
+            .await
+        {
+            Ok(replaced) => replaced,
+            Err(e) => log_return_err!(e),
         };
-        if users.len() != 0 {
-            panic!("we deleted all the test users but list() found some!");
+        // changes is what was done, so up_to_date says whether cosmos kept the policy as sent
+        let current = IndexingPolicy::of_collection(&replaced);
+        Ok(IndexingReport {
+            up_to_date: current.changes_to(&desired).is_empty(),
+            ..self.indexing_report(current, changes)
+        })
+    }
+
+    async fn get_throughput(&self) -> UserDbResult<ThroughputReport> {
+        let (scope, offer) = self.find_offer().await?;
+        Ok(ThroughputReport {
+            scope,
+            throughput: offer_throughput(&offer)?,
+        })
+    }
+
+    /**
+     *  cosmos applies a change in throughput right away, unless it needs more physical partitions -- then the offer
+     *  reports the new value once they have been added
+     */
+    async fn set_throughput(&self, throughput: Throughput) -> UserDbResult<ThroughputReport> {
+        let (scope, offer) = self.find_offer().await?;
+        let current = ThroughputReport {
+            scope,
+            throughput: offer_throughput(&offer)?,
+        };
+        let changed = current.change_to(throughput)?;
+        info!(
+            "Changing the {} throughput from {:?} to {:?}",
+            scope.name(),
+            current.throughput,
+            throughput
+        );
+        match self.rest.replace_offer(&offer, throughput).await {
+            Ok(..) => Ok(changed),
+            Err(e) => log_return_err!(e),
         }
     }
+}
 
-    fn create_users() -> Vec<User> {
-        let mut rng = rand::thread_rng();
-        let mut users = Vec::new();
+/**
+ *  the _rid of a cosmos resource, which is what its offer points at
+ */
+fn rid_of(resource: &Value) -> &str {
+    resource["_rid"].as_str().unwrap_or_default()
+}
+
+/**
+ *  an If-Match on a batch operation is the ifMatch field.  "*" (or none) means any version
+ */
+fn with_if_match(mut operation: Value, if_match: Option<String>) -> Value {
+    if let Some(etag) = if_match.filter(|etag| etag != "*") {
+        operation["ifMatch"] = Value::from(etag);
+    }
+    operation
+}
 
-        for _ in 0..4 {
-            let id = get_id();
-            let name: String = iter::repeat(())
-                .map(|()| rng.sample(rand::distributions::Alphanumeric))
-                .map(char::from)
-                .take(10) // Adjust as needed.
-                .collect();
-            let email = format!("{}@example.com", name);
+#[cfg(test)]
+mod tests {
 
-            users.push(User {
-                id,
-                partition_key: 1,
-                email,
-                name,
-            });
+    use super::*;
+    use crate::local_cosmos::{LocalCosmos, LOCAL_ACCOUNT};
+    use crate::store::tests::exercise_store;
+    use log::trace;
+
+    #[test]
+    fn test_endpoints() {
+        let url = |name: &str| CosmosEndpoint::from_name(name).map(|endpoint| endpoint.url("acct"));
+        assert_eq!(url("public").unwrap(), "https://acct.documents.azure.com");
+        assert_eq!(url("China").unwrap(), "https://acct.documents.azure.cn");
+        assert_eq!(
+            url("us-government").unwrap(),
+            "https://acct.documents.azure.us"
+        );
+        assert_eq!(url("emulator").unwrap(), "https://localhost:8081");
+        assert_eq!(
+            url("https://acct.privatelink.documents.azure.com:443/").unwrap(),
+            "https://acct.privatelink.documents.azure.com:443"
+        );
+        assert_eq!(url("germany"), None);
+        assert_eq!(url("ftp://localhost"), None);
+        assert_eq!(url("http://"), None);
+
+        let not_pem = std::env::temp_dir().join(format!("not-a-cert-{}.pem", std::process::id()));
+        fs::write(&not_pem, "not a certificate").unwrap();
+        assert!(matches!(
+            http_client(Some(&not_pem)),
+            Err(UserDbError::Validation(_))
+        ));
+        fs::remove_file(&not_pem).unwrap();
+        assert!(http_client(None).is_ok());
+    }
+
+    /**
+     *  the same test against a LocalCosmos, so it runs without an account.  the two partition strategies cover both
+     *  ways the batches are written
+     */
+    #[actix_web::test]
+    async fn test_e2e_local() {
+        let _ = env_logger::builder().is_test(true).try_init();
+        for strategy in ["id-hash:4", "single"] {
+            let cosmos = LocalCosmos::start();
+            let config = Config {
+                database_name: "user-test-db".to_string(),
+                collection_name: "user-test-collection".to_string(),
+                partition_strategy: PartitionStrategy::from_name(strategy).unwrap(),
+                endpoint: CosmosEndpoint::from_name(cosmos.endpoint()).unwrap(),
+                ..Config::default()
+            };
+            let user_db = UserDb::connect(&config, LOCAL_ACCOUNT, cosmos.credential()).unwrap();
+            exercise_store(&user_db).await;
         }
+    }
 
-        users
+    /**
+     *  setup, and the throughput it creates, only go through CosmosRest
+     */
+    #[actix_web::test]
+    async fn test_setup_and_throughput_local() {
+        let cosmos = LocalCosmos::start();
+        let config = Config {
+            endpoint: CosmosEndpoint::from_name(cosmos.endpoint()).unwrap(),
+            database_throughput: Some(Throughput::Autoscale(4000)),
+            ..Config::default()
+        };
+        let user_db = UserDb::connect(&config, LOCAL_ACCOUNT, cosmos.credential()).unwrap();
+        let report = user_db.setupdb().await.unwrap();
+        assert!(report.database_created && report.collection_created);
+        assert_eq!(user_db.setupdb().await.unwrap(), SetupReport::default());
+
+        let shared = ThroughputReport {
+            scope: ThroughputScope::Database,
+            throughput: Throughput::Autoscale(4000),
+        };
+        assert_eq!(user_db.get_throughput().await.unwrap(), shared);
+        let err = user_db
+            .set_throughput(Throughput::Manual(400))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, UserDbError::Validation(..)));
+        user_db
+            .set_throughput(Throughput::Autoscale(5000))
+            .await
+            .unwrap();
+        assert_eq!(
+            user_db.get_throughput().await.unwrap().throughput,
+            Throughput::Autoscale(5000)
+        );
+
+        // a second collection gets its own throughput, and that is the one that counts
+        let dedicated = Config {
+            collection_name: "dedicated".to_string(),
+            container_throughput: Some(Throughput::Manual(400)),
+            ..config.clone()
+        };
+        let user_db = UserDb::connect(&dedicated, LOCAL_ACCOUNT, cosmos.credential()).unwrap();
+        let report = user_db.setupdb().await.unwrap();
+        assert!(!report.database_created && report.collection_created);
+        assert_eq!(
+            user_db.get_throughput().await.unwrap().scope,
+            ThroughputScope::Container
+        );
+
+        // the collection is there, but partitioned on another path
+        let moved = Config {
+            partition_key_path: "/tenant".to_string(),
+            ..config
+        };
+        let user_db = UserDb::connect(&moved, LOCAL_ACCOUNT, cosmos.credential()).unwrap();
+        let err = user_db.setupdb().await.unwrap_err();
+        assert!(matches!(err, UserDbError::Conflict(..)));
+    }
+
+    /**
+     *  like setup and throughput, the indexing policy only goes through CosmosRest
+     */
+    #[actix_web::test]
+    async fn test_indexing_policy_local() {
+        let cosmos = LocalCosmos::start();
+        let config = Config {
+            endpoint: CosmosEndpoint::from_name(cosmos.endpoint()).unwrap(),
+            ..Config::default()
+        };
+        let user_db = UserDb::connect(&config, LOCAL_ACCOUNT, cosmos.credential()).unwrap();
+        user_db.setupdb().await.unwrap();
+        assert!(user_db.indexing_policy().await.unwrap().up_to_date);
+
+        // a collection made before there was an indexing policy indexes everything
+        let older = Config {
+            collection_name: "older".to_string(),
+            ..config
+        };
+        let user_db = UserDb::connect(&older, LOCAL_ACCOUNT, cosmos.credential()).unwrap();
+        user_db
+            .rest
+            .create_collection(
+                &older.database_name,
+                &json!({
+                    "id": older.collection_name,
+                    "partitionKey": {"paths": [older.partition_key_path], "kind": "Hash"}
+                }),
+                None,
+            )
+            .await
+            .unwrap();
+        user_db.setupdb().await.unwrap();
+        let report = user_db.indexing_policy().await.unwrap();
+        assert!(!report.up_to_date);
+        assert!(report
+            .changes
+            .contains(&"remove included path /*".to_string()));
+
+        let applied = user_db.apply_indexing_policy().await.unwrap();
+        assert!(applied.up_to_date);
+        assert_eq!(applied.changes, report.changes);
+        let report = user_db.indexing_policy().await.unwrap();
+        assert!(report.up_to_date && report.changes.is_empty());
+        assert_eq!(report.current, report.desired);
+    }
+
+    /**
+     *  the same checks against a real account.  it needs COSMOS_ACCOUNT_NAME and the secrets for the credential, so
+     *  it only runs when asked for: cargo test -- --ignored test_e2e
+     */
+    #[tokio::test]
+    #[ignore = "needs a real Cosmos account"]
+    async fn test_e2e() {
+        let _ = env_logger::builder().is_test(true).try_init();
+        // load secrets
+        match get_cosmos_account() {
+            Ok(account) => trace!("Secrets found.  Account: {:?}", account),
+            Err(error) => panic!("Failed to get secrets: {}", error),
+        }
+        let config = Config {
+            database_name: "user-test-db".to_string(),
+            collection_name: "user-test-collection".to_string(),
+            ..Config::default()
+        };
+        // create the database -- note this will DELETE the database as well
+        let user_db = match UserDb::try_new(&config).await {
+            Ok(user_db) => user_db,
+            Err(error) => panic!("Failed to connect to cosmos: {}", error),
+        };
+        exercise_store(&user_db).await;
     }
 }
@@ -0,0 +1,309 @@
+/**
+ *  the error type for everything the stores do.  cosmos errors are sorted into these variants by their HTTP status,
+ *  and the in-memory store returns the same variants, so the WebApi can turn any failure into the right status code
+ *  without knowing which backend it came from.  because this implements actix's ResponseError, handlers can just
+ *  return Result<HttpResponse, UserDbError> and use ?.
+ */
+use crate::users::UserResponse;
+use actix_web::http::header;
+use actix_web::{HttpResponse, ResponseError};
+use azure_core::error::ErrorKind;
+use azure_core::StatusCode;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::time::Duration;
+
+#[derive(Debug, Clone)]
+pub enum UserDbError {
+    // there is no user with that id
+    NotFound(String),
+    // a document with that id already exists
+    Conflict(String),
+    // the document changed since the client read it (the If-Match etag is stale)
+    PreconditionFailed(String),
+    // cosmos is rate limiting us.  retry_after is how long it asked us to wait, if it told us
+    Throttled {
+        message: String,
+        retry_after: Option<Duration>,
+    },
+    // the credentials were rejected
+    Unauthorized(String),
+    // the caller isn't allowed to do this, e.g. a reset without the right confirmation token
+    Forbidden(String),
+    // the request itself is bad
+    Validation(String),
+    // the request body is in a format we don't read
+    UnsupportedMediaType(String),
+    // the request parsed, but some of its fields break the rules in validation.rs
+    InvalidFields(Vec<FieldError>),
+    // this was part of an atomic batch and another operation in the batch failed, so it was rolled back
+    FailedDependency(String),
+    // cosmos (or the network on the way to it) is having a moment: a 503, a timeout, a dropped connection.  trying
+    // again later will probably work
+    Unavailable(String),
+    // anything else that went wrong talking to the store
+    Backend(String),
+}
+
+pub type UserDbResult<T> = Result<T, UserDbError>;
+
+/**
+ *  one broken validation rule.  code is stable for clients to match on (e.g. "email", "length"), message is for people
+ */
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct FieldError {
+    pub field: String,
+    pub code: String,
+    pub message: String,
+}
+
+impl UserDbError {
+    /**
+     *  the messages for the errors that both stores return, so the client sees the same text from either one
+     */
+    pub fn user_not_found(user_id: &str) -> Self {
+        UserDbError::NotFound(format!("User {} not found", user_id))
+    }
+
+    pub fn user_exists(user_id: &str) -> Self {
+        UserDbError::Conflict(format!("User with id {} already exists", user_id))
+    }
+
+    pub fn email_taken(email: &str) -> Self {
+        UserDbError::Conflict(format!("A user with email {} already exists", email))
+    }
+
+    pub fn user_changed(user_id: &str) -> Self {
+        UserDbError::PreconditionFailed(format!(
+            "User {} has been changed since it was read",
+            user_id
+        ))
+    }
+
+    /**
+     *  the same error, saying which operation of a transaction it came from
+     */
+    pub fn in_operation(self, index: usize) -> Self {
+        let context = |message: String| {
+            format!(
+                "operation {} failed, so the transaction wrote nothing: {}",
+                index, message
+            )
+        };
+        match self {
+            UserDbError::NotFound(message) => UserDbError::NotFound(context(message)),
+            UserDbError::Conflict(message) => UserDbError::Conflict(context(message)),
+            UserDbError::PreconditionFailed(message) => {
+                UserDbError::PreconditionFailed(context(message))
+            }
+            UserDbError::Throttled {
+                message,
+                retry_after,
+            } => UserDbError::Throttled {
+                message: context(message),
+                retry_after,
+            },
+            UserDbError::Unauthorized(message) => UserDbError::Unauthorized(context(message)),
+            UserDbError::Forbidden(message) => UserDbError::Forbidden(context(message)),
+            UserDbError::Validation(message) => UserDbError::Validation(context(message)),
+            UserDbError::UnsupportedMediaType(message) => {
+                UserDbError::UnsupportedMediaType(context(message))
+            }
+            UserDbError::FailedDependency(message) => {
+                UserDbError::FailedDependency(context(message))
+            }
+            UserDbError::Unavailable(message) => UserDbError::Unavailable(context(message)),
+            UserDbError::Backend(message) => UserDbError::Backend(context(message)),
+            UserDbError::InvalidFields(errors) => UserDbError::InvalidFields(errors),
+        }
+    }
+
+    /**
+     *  the HTTP status the WebApi answers with for this error
+     */
+    pub fn status(&self) -> StatusCode {
+        match self {
+            UserDbError::NotFound(..) => StatusCode::NotFound,
+            UserDbError::Conflict(..) => StatusCode::Conflict,
+            UserDbError::PreconditionFailed(..) => StatusCode::PreconditionFailed,
+            UserDbError::Throttled { .. } => StatusCode::TooManyRequests,
+            UserDbError::Unauthorized(..) => StatusCode::Unauthorized,
+            UserDbError::Forbidden(..) => StatusCode::Forbidden,
+            UserDbError::Validation(..) => StatusCode::BadRequest,
+            UserDbError::UnsupportedMediaType(..) => StatusCode::UnsupportedMediaType,
+            UserDbError::InvalidFields(..) => StatusCode::UnprocessableEntity,
+            UserDbError::FailedDependency(..) => StatusCode::FailedDependency,
+            UserDbError::Unavailable(..) => StatusCode::ServiceUnavailable,
+            UserDbError::Backend(..) => StatusCode::InternalServerError,
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        match self {
+            UserDbError::NotFound(message)
+            | UserDbError::Conflict(message)
+            | UserDbError::PreconditionFailed(message)
+            | UserDbError::Throttled { message, .. }
+            | UserDbError::Unauthorized(message)
+            | UserDbError::Forbidden(message)
+            | UserDbError::Validation(message)
+            | UserDbError::UnsupportedMediaType(message)
+            | UserDbError::FailedDependency(message)
+            | UserDbError::Unavailable(message)
+            | UserDbError::Backend(message) => message,
+            UserDbError::InvalidFields(..) => {
+                "some fields are not valid.  see errors for the details"
+            }
+        }
+    }
+}
+
+impl fmt::Display for UserDbError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message())
+    }
+}
+
+impl std::error::Error for UserDbError {}
+
+/**
+ *  sort an error from the cosmos sdk by the status code cosmos sent back
+ */
+impl From<azure_core::Error> for UserDbError {
+    fn from(err: azure_core::Error) -> Self {
+        let message = err.to_string();
+        let status = match err.kind() {
+            ErrorKind::HttpResponse { status, .. } => *status as u16,
+            // we couldn't get a token to send in the first place
+            ErrorKind::Credential => return UserDbError::Unauthorized(message),
+            // the connection failed or dropped, which is usually over by the next try
+            ErrorKind::Io => return UserDbError::Unavailable(message),
+            _ => return UserDbError::Backend(message),
+        };
+        let retry_after = retry_after(&message);
+        UserDbError::from_status(status, message, retry_after)
+    }
+}
+
+impl UserDbError {
+    /**
+     *  the error for a failed cosmos response, whether it came through the sdk or from one of our own REST calls
+     */
+    pub fn from_status(status: u16, message: String, retry_after: Option<Duration>) -> Self {
+        match status {
+            404 => UserDbError::NotFound(message),
+            409 => UserDbError::Conflict(message),
+            412 => UserDbError::PreconditionFailed(message),
+            429 => UserDbError::Throttled {
+                message,
+                retry_after,
+            },
+            401 | 403 => UserDbError::Unauthorized(message),
+            400 => UserDbError::Validation(message),
+            424 => UserDbError::FailedDependency(message),
+            // 449 is cosmos for "retry with": a write collided with another one in flight
+            408 | 449 | 503 => UserDbError::Unavailable(message),
+            _ => UserDbError::Backend(message),
+        }
+    }
+
+    /**
+     *  cosmos answers 409 both for a duplicate id and for a duplicate unique key, and only the message tells them apart
+     */
+    pub fn is_unique_key_violation(&self) -> bool {
+        matches!(self, UserDbError::Conflict(message) if message.contains("Unique index constraint"))
+    }
+}
+
+impl From<serde_json::Error> for UserDbError {
+    fn from(err: serde_json::Error) -> Self {
+        UserDbError::Backend(format!("failed to read or write a user document: {}", err))
+    }
+}
+
+/**
+ *  cosmos says how long to back off in the x-ms-retry-after-ms header.  the sdk doesn't expose the headers of a failed
+ *  response, but it does include them in the error text, so we read it from there
+ */
+fn retry_after(message: &str) -> Option<Duration> {
+    let start = message.find("x-ms-retry-after-ms")? + "x-ms-retry-after-ms".len();
+    let millis: String = message[start..]
+        .chars()
+        .skip_while(|c| !c.is_ascii_digit())
+        .take_while(|c| c.is_ascii_digit())
+        .collect();
+    millis.parse().ok().map(Duration::from_millis)
+}
+
+impl ResponseError for UserDbError {
+    fn status_code(&self) -> actix_web::http::StatusCode {
+        actix_web::http::StatusCode::from_u16(self.status() as u16)
+            .unwrap_or(actix_web::http::StatusCode::INTERNAL_SERVER_ERROR)
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut builder = HttpResponse::build(self.status_code());
+        builder.content_type("application/json");
+        if let UserDbError::Throttled {
+            retry_after: Some(retry_after),
+            ..
+        } = self
+        {
+            // Retry-After is in whole seconds, so round up
+            let seconds = (retry_after.as_millis() as u64).div_ceil(1000);
+            builder.insert_header((header::RETRY_AFTER, seconds.to_string()));
+        }
+        let errors = match self {
+            UserDbError::InvalidFields(errors) => errors.clone(),
+            _ => Vec::new(),
+        };
+        builder.json(UserResponse {
+            message: self.message().to_string(),
+            status: self.status(),
+            body: "".to_owned(),
+            errors,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status_codes() {
+        let err = UserDbError::from(azure_core::Error::new(
+            ErrorKind::HttpResponse {
+                status: StatusCode::Conflict,
+                error_code: None,
+            },
+            "Entity with the specified id already exists in the system.",
+        ));
+        assert!(matches!(err, UserDbError::Conflict(..)));
+        assert_eq!(err.status_code().as_u16(), 409);
+
+        let err = UserDbError::from(azure_core::Error::new(ErrorKind::Io, "connection reset"));
+        assert_eq!(err.status_code().as_u16(), 503);
+
+        let err = UserDbError::from(azure_core::Error::new(ErrorKind::Other, "unexpected"));
+        assert_eq!(err.status_code().as_u16(), 500);
+
+        // an operation rolled back with the rest of its transactional batch
+        let err = UserDbError::from_status(424, "failed dependency".to_string(), None);
+        assert_eq!(err.status_code().as_u16(), 424);
+    }
+
+    #[test]
+    fn test_retry_after() {
+        let message = "HttpError { Status: 429, Headers: [ x-ms-retry-after-ms:1500 ] }";
+        assert_eq!(retry_after(message), Some(Duration::from_millis(1500)));
+
+        let err = UserDbError::Throttled {
+            message: message.to_string(),
+            retry_after: Some(Duration::from_millis(1500)),
+        };
+        let response = err.error_response();
+        assert_eq!(response.status().as_u16(), 429);
+        assert_eq!(response.headers().get(header::RETRY_AFTER).unwrap(), "2");
+    }
+}
@@ -0,0 +1,219 @@
+/**
+ *  a store that fails on purpose.  FaultyStore wraps another store and makes some of its calls fail with the errors
+ *  cosmos gives when it is busy, before they reach the store, so the retry layer (retry.rs) can be tried out against
+ *  the memory backend.  the `fault_injection` setting turns it on, e.g. "throttle=0.2,unavailable=0.05" fails 20% of
+ *  calls with a 429 and 5% with a 503.  it is off by default and is not meant for production.
+ *
+ *  the batch calls aren't overridden, so they fall back to UserStore's one-user-at-a-time versions and each user can
+ *  fail on its own.
+ */
+use crate::error::{UserDbError, UserDbResult};
+use crate::indexing::IndexingReport;
+use crate::models::{
+    MigrationReport, PartialUser, SetupReport, User, UserFilter, UserPage, UserPatch,
+};
+use crate::store::UserStore;
+use crate::throughput::{Throughput, ThroughputReport};
+use crate::transaction::TransactionOp;
+use async_trait::async_trait;
+use rand::Rng;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// what an injected 429 asks the client to wait
+const INJECTED_RETRY_AFTER: Duration = Duration::from_millis(10);
+
+/**
+ *  the fraction (0 to 1) of calls that fail with each error
+ */
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct FaultPlan {
+    pub throttle: f64,
+    pub unavailable: f64,
+}
+
+impl FaultPlan {
+    /**
+     *  the `fault_injection` setting: "off", or throttle=<rate>,unavailable=<rate>.  the rates can't add up to more
+     *  than 1
+     */
+    pub fn from_spec(spec: &str) -> Option<Self> {
+        let spec = spec.trim().to_lowercase();
+        let mut plan = FaultPlan::default();
+        if spec == "off" {
+            return Some(plan);
+        }
+        for part in spec.split(',') {
+            let (name, rate) = part.split_once('=')?;
+            let rate: f64 = rate.trim().parse().ok()?;
+            if !(0.0..=1.0).contains(&rate) {
+                return None;
+            }
+            match name.trim() {
+                "throttle" => plan.throttle = rate,
+                "unavailable" => plan.unavailable = rate,
+                _ => return None,
+            }
+        }
+        (plan.throttle + plan.unavailable <= 1.0).then_some(plan)
+    }
+
+    pub fn is_off(&self) -> bool {
+        self.throttle == 0.0 && self.unavailable == 0.0
+    }
+}
+
+pub struct FaultyStore {
+    inner: Arc<dyn UserStore>,
+    plan: FaultPlan,
+    // errors to return on the next calls, in order, before the plan is used
+    scripted: Mutex<VecDeque<UserDbError>>,
+}
+
+impl FaultyStore {
+    pub fn new(inner: Arc<dyn UserStore>, plan: FaultPlan) -> Self {
+        Self {
+            inner,
+            plan,
+            scripted: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /**
+     *  make the next call fail with error, whatever the plan says.  for tests that need to know exactly what fails
+     */
+    #[cfg(test)]
+    pub fn fail_next(&self, error: UserDbError) {
+        self.scripted.lock().unwrap().push_back(error);
+    }
+
+    pub fn throttled() -> UserDbError {
+        UserDbError::Throttled {
+            message: "injected fault: request rate is large".to_string(),
+            retry_after: Some(INJECTED_RETRY_AFTER),
+        }
+    }
+
+    pub fn unavailable() -> UserDbError {
+        UserDbError::Unavailable("injected fault: service unavailable".to_string())
+    }
+
+    fn inject(&self) -> UserDbResult<()> {
+        if let Some(error) = self.scripted.lock().unwrap().pop_front() {
+            return Err(error);
+        }
+        let roll: f64 = rand::thread_rng().gen();
+        if roll < self.plan.throttle {
+            Err(Self::throttled())
+        } else if roll < self.plan.throttle + self.plan.unavailable {
+            Err(Self::unavailable())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[async_trait]
+impl UserStore for FaultyStore {
+    async fn setupdb(&self) -> UserDbResult<SetupReport> {
+        self.inject()?;
+        self.inner.setupdb().await
+    }
+
+    async fn reset(&self) -> UserDbResult<SetupReport> {
+        self.inject()?;
+        self.inner.reset().await
+    }
+
+    async fn list(
+        &self,
+        filter: &UserFilter,
+        page_size: u32,
+        continuation: Option<String>,
+    ) -> UserDbResult<UserPage> {
+        self.inject()?;
+        self.inner.list(filter, page_size, continuation).await
+    }
+
+    async fn count(&self, filter: &UserFilter) -> UserDbResult<u64> {
+        self.inject()?;
+        self.inner.count(filter).await
+    }
+
+    async fn create_user(&self, user: User) -> UserDbResult<User> {
+        self.inject()?;
+        self.inner.create_user(user).await
+    }
+
+    async fn upsert_user(&self, user_id: &str, user: PartialUser) -> UserDbResult<User> {
+        self.inject()?;
+        self.inner.upsert_user(user_id, user).await
+    }
+
+    async fn replace_user(
+        &self,
+        user_id: &str,
+        user: PartialUser,
+        if_match: Option<String>,
+    ) -> UserDbResult<User> {
+        self.inject()?;
+        self.inner.replace_user(user_id, user, if_match).await
+    }
+
+    async fn patch_user(
+        &self,
+        user_id: &str,
+        patch: UserPatch,
+        if_match: Option<String>,
+    ) -> UserDbResult<User> {
+        self.inject()?;
+        self.inner.patch_user(user_id, patch, if_match).await
+    }
+
+    async fn delete_user(&self, unique_id: &str, if_match: Option<String>) -> UserDbResult<()> {
+        self.inject()?;
+        self.inner.delete_user(unique_id, if_match).await
+    }
+
+    async fn find_user(&self, user_id: &str) -> UserDbResult<User> {
+        self.inject()?;
+        self.inner.find_user(user_id).await
+    }
+
+    async fn execute_transaction(
+        &self,
+        partition_key: &str,
+        operations: Vec<TransactionOp>,
+    ) -> UserDbResult<Vec<Option<User>>> {
+        self.inject()?;
+        self.inner
+            .execute_transaction(partition_key, operations)
+            .await
+    }
+
+    async fn migrate_partitions(&self) -> UserDbResult<MigrationReport> {
+        self.inject()?;
+        self.inner.migrate_partitions().await
+    }
+
+    async fn get_throughput(&self) -> UserDbResult<ThroughputReport> {
+        self.inject()?;
+        self.inner.get_throughput().await
+    }
+
+    async fn set_throughput(&self, throughput: Throughput) -> UserDbResult<ThroughputReport> {
+        self.inject()?;
+        self.inner.set_throughput(throughput).await
+    }
+
+    async fn indexing_policy(&self) -> UserDbResult<IndexingReport> {
+        self.inject()?;
+        self.inner.indexing_policy().await
+    }
+
+    async fn apply_indexing_policy(&self) -> UserDbResult<IndexingReport> {
+        self.inject()?;
+        self.inner.apply_indexing_policy().await
+    }
+}
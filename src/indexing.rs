@@ -0,0 +1,278 @@
+/**
+ *  the indexing policy of the users container.  by default cosmos indexes every path of every document, and each of
+ *  those index entries costs RUs on every write.  the queries QueryBuilder writes only filter and sort on a few
+ *  fields, so the policy below indexes just those:
+ *
+ *      id, name, email     range indexes, for the filters (=, STARTSWITH, ENDSWITH) and ORDER BY on one field
+ *      the partition key   for the queries that look inside one partition
+ *      name + email        composite indexes, both ways, for sorting on the two together
+ *      everything else     excluded, and no spatial indexes
+ *
+ *  setupdb creates the container with it.  GET /admin/indexing shows how the container's policy differs from it, and
+ *  POST /admin/indexing replaces the container's policy with it.  cosmos then reindexes in the background.
+ */
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeSet;
+
+// cosmos adds this to every policy whether it was asked for or not
+const ETAG_PATH: &str = "/\"_etag\"/?";
+
+/**
+ *  the indexingPolicy of a container, in the shape cosmos reads and writes it
+ */
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct IndexingPolicy {
+    pub indexing_mode: String,
+    pub automatic: bool,
+    #[serde(default)]
+    pub included_paths: Vec<IndexPath>,
+    #[serde(default)]
+    pub excluded_paths: Vec<IndexPath>,
+    #[serde(default)]
+    pub composite_indexes: Vec<Vec<CompositePath>>,
+    // the sample has no geography fields, so this is always empty here.  a container can have some, so keep them
+    #[serde(default)]
+    pub spatial_indexes: Vec<Value>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct IndexPath {
+    pub path: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct CompositePath {
+    pub path: String,
+    #[serde(default = "ascending")]
+    pub order: String,
+}
+
+fn ascending() -> String {
+    "ascending".to_string()
+}
+
+/**
+ *  what GET and POST /admin/indexing return.  changes is what POST would change (GET) or did change (POST), one line
+ *  each, and is empty when the container already has the desired policy
+ */
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IndexingReport {
+    pub up_to_date: bool,
+    pub changes: Vec<String>,
+    pub current: IndexingPolicy,
+    pub desired: IndexingPolicy,
+}
+
+impl IndexingPolicy {
+    /**
+     *  the policy for the users container, partitioned on partition_key_path
+     */
+    pub fn for_users(partition_key_path: &str) -> Self {
+        let range = |path: &str| IndexPath {
+            path: format!("{}/?", path),
+        };
+        let composite = |order: &str| {
+            vec!["/name", "/email"]
+                .into_iter()
+                .map(|path| CompositePath {
+                    path: path.to_string(),
+                    order: order.to_string(),
+                })
+                .collect()
+        };
+        IndexingPolicy {
+            indexing_mode: "consistent".to_string(),
+            automatic: true,
+            included_paths: vec![
+                range("/id"),
+                range("/name"),
+                range("/email"),
+                range(partition_key_path),
+            ],
+            excluded_paths: vec![
+                IndexPath {
+                    path: "/*".to_string(),
+                },
+                IndexPath {
+                    path: ETAG_PATH.to_string(),
+                },
+            ],
+            composite_indexes: vec![composite("ascending"), composite("descending")],
+            spatial_indexes: Vec::new(),
+        }
+    }
+
+    /**
+     *  the policy cosmos gives a container that is created without one: every path
+     */
+    pub fn index_everything() -> Self {
+        IndexingPolicy {
+            indexing_mode: "consistent".to_string(),
+            automatic: true,
+            included_paths: vec![IndexPath {
+                path: "/*".to_string(),
+            }],
+            excluded_paths: vec![IndexPath {
+                path: ETAG_PATH.to_string(),
+            }],
+            composite_indexes: Vec::new(),
+            spatial_indexes: Vec::new(),
+        }
+    }
+
+    /**
+     *  the policy in a container definition.  a container without one indexes everything
+     */
+    pub fn of_collection(collection: &Value) -> Self {
+        serde_json::from_value(collection["indexingPolicy"].clone())
+            .unwrap_or_else(|_| IndexingPolicy::index_everything())
+    }
+
+    /**
+     *  what has to change to turn self into desired.  the order of paths and indexes doesn't matter to cosmos, so it
+     *  doesn't matter here
+     */
+    pub fn changes_to(&self, desired: &IndexingPolicy) -> Vec<String> {
+        let mut changes = Vec::new();
+        if self.indexing_mode != desired.indexing_mode {
+            changes.push(format!(
+                "indexing mode {} -> {}",
+                self.indexing_mode, desired.indexing_mode
+            ));
+        }
+        if self.automatic != desired.automatic {
+            changes.push(format!(
+                "automatic {} -> {}",
+                self.automatic, desired.automatic
+            ));
+        }
+        let paths = |paths: &[IndexPath]| -> BTreeSet<String> {
+            paths.iter().map(|path| path.path.clone()).collect()
+        };
+        diff(
+            &mut changes,
+            "included path",
+            paths(&self.included_paths),
+            paths(&desired.included_paths),
+        );
+        diff(
+            &mut changes,
+            "excluded path",
+            paths(&self.excluded_paths),
+            paths(&desired.excluded_paths),
+        );
+        let composites = |indexes: &[Vec<CompositePath>]| -> BTreeSet<String> {
+            indexes
+                .iter()
+                .map(|index| {
+                    let paths: Vec<String> = index
+                        .iter()
+                        .map(|path| format!("{} {}", path.path, path.order.to_lowercase()))
+                        .collect();
+                    format!("({})", paths.join(", "))
+                })
+                .collect()
+        };
+        diff(
+            &mut changes,
+            "composite index",
+            composites(&self.composite_indexes),
+            composites(&desired.composite_indexes),
+        );
+        let spatial = |indexes: &[Value]| -> BTreeSet<String> {
+            indexes
+                .iter()
+                .map(|index| index["path"].to_string())
+                .collect()
+        };
+        diff(
+            &mut changes,
+            "spatial index",
+            spatial(&self.spatial_indexes),
+            spatial(&desired.spatial_indexes),
+        );
+        changes
+    }
+}
+
+/**
+ *  "add <what> x" for everything only in desired and "remove <what> x" for everything only in current
+ */
+fn diff(
+    changes: &mut Vec<String>,
+    what: &str,
+    current: BTreeSet<String>,
+    desired: BTreeSet<String>,
+) {
+    for removed in current.difference(&desired) {
+        changes.push(format!("remove {} {}", what, removed));
+    }
+    for added in desired.difference(&current) {
+        changes.push(format!("add {} {}", what, added));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_changes() {
+        let desired = IndexingPolicy::for_users("/partition_key");
+        assert!(desired.changes_to(&desired).is_empty());
+
+        let mut reordered = desired.clone();
+        reordered.included_paths.reverse();
+        assert!(reordered.changes_to(&desired).is_empty());
+
+        let changes = IndexingPolicy::index_everything().changes_to(&desired);
+        assert_eq!(
+            changes,
+            vec![
+                "remove included path /*",
+                "add included path /email/?",
+                "add included path /id/?",
+                "add included path /name/?",
+                "add included path /partition_key/?",
+                "add excluded path /*",
+                "add composite index (/name ascending, /email ascending)",
+                "add composite index (/name descending, /email descending)",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_cosmos_shape() {
+        let policy = IndexingPolicy::for_users("/partition_key");
+        let value = serde_json::to_value(&policy).unwrap();
+        assert_eq!(value["indexingMode"], "consistent");
+        assert_eq!(
+            value["includedPaths"][3],
+            json!({"path": "/partition_key/?"})
+        );
+        assert_eq!(
+            value["compositeIndexes"][0][1],
+            json!({"path": "/email", "order": "ascending"})
+        );
+
+        // what cosmos sends back for a container that was created without a policy
+        let collection = json!({"id": "users"});
+        assert_eq!(
+            IndexingPolicy::of_collection(&collection),
+            IndexingPolicy::index_everything()
+        );
+        let collection = json!({"indexingPolicy": {
+            "indexingMode": "consistent",
+            "automatic": true,
+            "includedPaths": [{"path": "/*", "indexes": []}],
+            "excludedPaths": [{"path": "/\"_etag\"/?"}]
+        }});
+        assert_eq!(
+            IndexingPolicy::of_collection(&collection),
+            IndexingPolicy::index_everything()
+        );
+    }
+}
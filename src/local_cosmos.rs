@@ -0,0 +1,1392 @@
+/**
+ *  a cosmos account for tests.  LocalCosmos runs an HTTP server in the test process that speaks the part of the Cosmos
+ *  REST API that UserDb uses, so the real sdk and CosmosRest code paths can be tested without an Azure subscription:
+ *
+ *      - creating, reading and deleting databases
+ *      - creating, reading and replacing containers, with a partition key path, unique keys and an indexing policy.
+ *        ORDER BY needs the field to be indexed, like it does in cosmos
+ *      - manual and autoscale throughput on databases and containers, and reading and replacing their offers
+ *      - creating, upserting, reading, replacing and deleting documents, with etags and If-Match
+ *      - the queries QueryBuilder writes, with parameters, paging and continuation tokens
+ *      - transactional batches
+ *
+ *  every request has to carry a master key signature for the server's key, and every response has the headers that
+ *  the sdk reads from a real one.  everything is kept in memory and is gone when the test's runtime stops.
+ *
+ *      let cosmos = LocalCosmos::start();
+ *      config.endpoint = CosmosEndpoint::from_name(cosmos.endpoint()).unwrap();
+ *      let user_db = UserDb::connect(&config, LOCAL_ACCOUNT, cosmos.credential())?;
+ *
+ *  this is not an emulator -- anything UserDb doesn't send (e.g. a query QueryBuilder can't write) gets a 400 saying
+ *  LocalCosmos doesn't support it, so the tests show when this needs to learn something new.
+ */
+use crate::auth::CosmosCredential;
+use crate::cosmos_rest::MAX_BATCH_OPERATIONS;
+use actix_web::http::{Method, StatusCode};
+use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use serde_json::{json, Value};
+use sha2::Sha256;
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::sync::atomic::{self, AtomicU64};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const LOCAL_ACCOUNT: &str = "local";
+
+// the page size when a query doesn't send x-ms-max-item-count
+const DEFAULT_MAX_ITEM_COUNT: usize = 100;
+
+pub struct LocalCosmos {
+    endpoint: String,
+    key: String,
+}
+
+impl LocalCosmos {
+    /**
+     *  start a server with an empty account on a free port.  it runs on the current actix runtime, so call this from
+     *  an #[actix_web::test]
+     */
+    pub fn start() -> Self {
+        let key = rand::random::<[u8; 32]>();
+        let state = web::Data::new(State {
+            key: key.to_vec(),
+            databases: Mutex::default(),
+            offers: Mutex::default(),
+            lsn: AtomicU64::new(0),
+        });
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(state.clone())
+                .app_data(web::PayloadConfig::new(4 * 1024 * 1024))
+                .default_service(web::to(handle))
+        })
+        .workers(1)
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let endpoint = format!("http://{}", server.addrs()[0]);
+        actix_web::rt::spawn(server.run());
+        LocalCosmos {
+            endpoint,
+            key: STANDARD.encode(key),
+        }
+    }
+
+    pub fn endpoint(&self) -> &str {
+        &self.endpoint
+    }
+
+    /**
+     *  the master key credential that this server accepts
+     */
+    pub fn credential(&self) -> CosmosCredential {
+        CosmosCredential::MasterKey(self.key.clone())
+    }
+}
+
+struct State {
+    key: Vec<u8>,
+    databases: Mutex<BTreeMap<String, Database>>,
+    // the throughput of the databases and containers that have their own
+    offers: Mutex<Vec<Value>>,
+    lsn: AtomicU64,
+}
+
+struct Database {
+    resource: Value,
+    collections: BTreeMap<String, Collection>,
+}
+
+#[derive(Clone)]
+struct Collection {
+    resource: Value,
+    partition_key_path: String,
+    // each unique key is a list of paths whose values together can't repeat within a logical partition
+    unique_keys: Vec<Vec<String>>,
+    // by (partition key as JSON, id)
+    documents: BTreeMap<(String, String), Value>,
+}
+
+/**
+ *  what a request gets back, before the headers every response has are added
+ */
+struct Reply {
+    status: StatusCode,
+    body: Option<Value>,
+    headers: Vec<(&'static str, String)>,
+}
+
+impl Reply {
+    fn new(status: StatusCode, body: Option<Value>) -> Self {
+        Reply {
+            status,
+            body,
+            headers: Vec::new(),
+        }
+    }
+
+    fn header(mut self, name: &'static str, value: String) -> Self {
+        self.headers.push((name, value));
+        self
+    }
+
+    /**
+     *  a document or other resource, with its etag in the headers the way cosmos sends it
+     */
+    fn resource(status: StatusCode, resource: Value) -> Self {
+        let etag = resource["_etag"].as_str().unwrap_or_default().to_string();
+        Reply::new(status, Some(resource)).header("etag", etag)
+    }
+}
+
+/**
+ *  an error, with the status, code and message cosmos would send
+ */
+#[derive(Debug)]
+struct Failure {
+    status: StatusCode,
+    code: &'static str,
+    message: String,
+}
+
+impl Failure {
+    fn new(status: StatusCode, code: &'static str, message: impl Into<String>) -> Self {
+        Failure {
+            status,
+            code,
+            message: message.into(),
+        }
+    }
+
+    fn bad_request(message: impl Into<String>) -> Self {
+        Failure::new(StatusCode::BAD_REQUEST, "BadRequest", message)
+    }
+
+    fn not_found() -> Self {
+        Failure::new(
+            StatusCode::NOT_FOUND,
+            "NotFound",
+            "Entity with the specified id does not exist in the system.",
+        )
+    }
+
+    fn conflict(message: &str) -> Self {
+        Failure::new(StatusCode::CONFLICT, "Conflict", message)
+    }
+
+    fn into_reply(self) -> Reply {
+        Reply::new(
+            self.status,
+            Some(json!({"code": self.code, "message": self.message})),
+        )
+    }
+}
+
+async fn handle(req: HttpRequest, body: web::Bytes, state: web::Data<State>) -> HttpResponse {
+    let segments: Vec<&str> = req.path().split('/').filter(|s| !s.is_empty()).collect();
+    let (resource_type, resource_link) = resource_of(&segments);
+    let reply = state
+        .check_signature(&req, &resource_type, &resource_link)
+        .and_then(|()| match body.is_empty() {
+            true => Ok(Value::Null),
+            false => serde_json::from_slice(&body)
+                .map_err(|e| Failure::bad_request(format!("the body is not JSON: {}", e))),
+        })
+        .and_then(|body| state.route(&req, &segments, body))
+        .unwrap_or_else(Failure::into_reply);
+    state.respond(reply, &resource_link)
+}
+
+/**
+ *  the resource type and link that a request's signature covers.  a path that ends in an id is that resource
+ *  (dbs/db/colls/users -> "colls", "dbs/db/colls/users"); one that ends in a type is the feed of that type in its
+ *  parent (dbs/db/colls -> "colls", "dbs/db").  offers are addressed by _rid, and for those the link is the _rid in
+ *  lower case
+ */
+fn resource_of(segments: &[&str]) -> (String, String) {
+    if let ["offers", rid] = segments {
+        return ("offers".to_string(), rid.to_lowercase());
+    }
+    match segments.len() {
+        0 => (String::new(), String::new()),
+        n if n % 2 == 1 => (segments[n - 1].to_string(), segments[..n - 1].join("/")),
+        n => (segments[n - 2].to_string(), segments.join("/")),
+    }
+}
+
+fn header<'a>(req: &'a HttpRequest, name: &str) -> Option<&'a str> {
+    req.headers()
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+}
+
+/**
+ *  the value of x-ms-documentdb-partitionkey, which is a JSON array with the one key in it
+ */
+fn partition_key(req: &HttpRequest) -> Result<Option<Value>, Failure> {
+    match header(req, "x-ms-documentdb-partitionkey") {
+        Some(text) => match serde_json::from_str::<Vec<Value>>(text) {
+            Ok(mut keys) if keys.len() == 1 => Ok(keys.pop()),
+            _ => Err(Failure::bad_request(format!(
+                "partition key {} is not valid",
+                text
+            ))),
+        },
+        None => Ok(None),
+    }
+}
+
+fn required_partition_key(req: &HttpRequest) -> Result<Value, Failure> {
+    partition_key(req)?.ok_or_else(|| {
+        Failure::bad_request("PartitionKey value must be supplied for this operation.")
+    })
+}
+
+fn is_set(req: &HttpRequest, name: &str) -> bool {
+    header(req, name).is_some_and(|value| value.eq_ignore_ascii_case("true"))
+}
+
+fn random_rid() -> String {
+    STANDARD.encode(rand::random::<[u8; 8]>())
+}
+
+/**
+ *  the content of the offer for a new database or container, from its throughput header.  None if it has none
+ */
+fn offer_content(req: &HttpRequest) -> Result<Option<Value>, Failure> {
+    let bad = |header: &str| Failure::bad_request(format!("{} is not valid", header));
+    if let Some(ru) = header(req, "x-ms-offer-throughput") {
+        let ru: u64 = ru.parse().map_err(|_| bad("x-ms-offer-throughput"))?;
+        return Ok(Some(json!({"offerThroughput": ru})));
+    }
+    match header(req, "x-ms-cosmos-offer-autopilot-settings") {
+        Some(settings) => {
+            let settings: Value = serde_json::from_str(settings)
+                .map_err(|_| bad("x-ms-cosmos-offer-autopilot-settings"))?;
+            let max = settings["maxThroughput"]
+                .as_u64()
+                .ok_or_else(|| bad("x-ms-cosmos-offer-autopilot-settings"))?;
+            Ok(Some(autoscale_content(max)))
+        }
+        None => Ok(None),
+    }
+}
+
+/**
+ *  autoscale runs at a tenth of the maximum while nothing is happening
+ */
+fn autoscale_content(max: u64) -> Value {
+    json!({"offerThroughput": max / 10, "offerAutopilotSettings": {"maxThroughput": max}})
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or_default()
+}
+
+/**
+ *  add the system properties cosmos puts on everything it stores
+ */
+fn stamp(resource: &mut Value, self_link: String, rid: String) {
+    resource["_rid"] = Value::from(rid);
+    resource["_self"] = Value::from(self_link);
+    resource["_etag"] = Value::from(format!("\"{:016x}\"", rand::random::<u64>()));
+    resource["_ts"] = Value::from(now());
+}
+
+impl State {
+    /**
+     *  check the authorization header the way cosmos does for a master key: it is
+     *  type=master&ver=1.0&sig=<signature>, url encoded, and the signature has to be the HMAC of the verb, resource
+     *  type, resource link and x-ms-date
+     */
+    fn check_signature(
+        &self,
+        req: &HttpRequest,
+        resource_type: &str,
+        resource_link: &str,
+    ) -> Result<(), Failure> {
+        let unauthorized =
+            |message: &str| Failure::new(StatusCode::UNAUTHORIZED, "Unauthorized", message);
+        let date = header(req, "x-ms-date").ok_or_else(|| unauthorized("x-ms-date is missing"))?;
+        let authorization = header(req, "authorization")
+            .ok_or_else(|| unauthorized("the authorization header is missing"))?;
+        let authorization: String =
+            form_urlencoded::parse(format!("a={}", authorization).as_bytes())
+                .map(|(_, value)| value.into_owned())
+                .next()
+                .unwrap_or_default();
+        let signature = authorization
+            .strip_prefix("type=master&ver=1.0&sig=")
+            .ok_or_else(|| unauthorized("LocalCosmos only takes master key signatures"))?;
+
+        let string_to_sign = format!(
+            "{}\n{}\n{}\n{}\n\n",
+            req.method().as_str().to_lowercase(),
+            resource_type.to_lowercase(),
+            resource_link,
+            date.to_lowercase()
+        );
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC takes a key of any size");
+        mac.update(string_to_sign.as_bytes());
+        if STANDARD.encode(mac.finalize().into_bytes()) != signature {
+            return Err(unauthorized(&format!(
+                "The input authorization token can't serve the request. The wanted payload is built by the \
+                 following string: {:?}",
+                string_to_sign
+            )));
+        }
+        Ok(())
+    }
+
+    fn add_offer(&self, resource: &Value, content: Option<Value>) {
+        if let Some(content) = content {
+            // offer ids are short and url safe, unlike the other _rids
+            let rid = format!("{:08x}", rand::random::<u32>());
+            let mut offer = json!({
+                "id": rid,
+                "offerVersion": "V2",
+                "offerType": "Invalid",
+                "offerResourceId": resource["_rid"],
+                "resource": resource["_self"],
+                "content": content,
+            });
+            stamp(&mut offer, format!("offers/{}/", rid), rid);
+            self.offers.lock().unwrap().push(offer);
+        }
+    }
+
+    /**
+     *  the only query on offers is the one CosmosRest::offer_for sends
+     */
+    fn query_offers(&self, req: &HttpRequest, body: &Value) -> Result<Reply, Failure> {
+        if !is_set(req, "x-ms-documentdb-isquery")
+            || body["query"] != "SELECT * FROM root WHERE root.offerResourceId = @rid"
+        {
+            return Err(Failure::bad_request(format!(
+                "LocalCosmos doesn't support this request on offers: {}",
+                body
+            )));
+        }
+        let rid = &body["parameters"][0]["value"];
+        let offers: Vec<Value> = self
+            .offers
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|offer| &offer["offerResourceId"] == rid)
+            .cloned()
+            .collect();
+        Ok(Reply::new(
+            StatusCode::OK,
+            Some(json!({"_rid": "", "Offers": offers, "_count": offers.len()})),
+        ))
+    }
+
+    /**
+     *  cosmos takes a new throughput of the same kind.  a switch between manual and autoscale is a separate migration
+     */
+    fn replace_offer(&self, rid: &str, body: &Value) -> Result<Reply, Failure> {
+        let mut offers = self.offers.lock().unwrap();
+        let offer = offers
+            .iter_mut()
+            .find(|offer| offer["_rid"] == rid)
+            .ok_or_else(Failure::not_found)?;
+        let is_autoscale = |content: &Value| content.get("offerAutopilotSettings").is_some();
+        let content = &body["content"];
+        if is_autoscale(content) != is_autoscale(&offer["content"]) {
+            return Err(Failure::bad_request(
+                "Offer replace can't switch between manual and autoscale throughput.",
+            ));
+        }
+        let content = match content["offerAutopilotSettings"]["maxThroughput"].as_u64() {
+            Some(max) => autoscale_content(max),
+            None => {
+                let ru = content["offerThroughput"]
+                    .as_u64()
+                    .ok_or_else(|| Failure::bad_request("the offer has no offerThroughput"))?;
+                json!({ "offerThroughput": ru })
+            }
+        };
+        offer["content"] = content;
+        offer["_etag"] = Value::from(format!("\"{:016x}\"", rand::random::<u64>()));
+        Ok(Reply::resource(StatusCode::OK, offer.clone()))
+    }
+
+    fn route(&self, req: &HttpRequest, segments: &[&str], body: Value) -> Result<Reply, Failure> {
+        let mut databases = self.databases.lock().unwrap();
+        let method = req.method().clone();
+        match (method, segments) {
+            (Method::POST, ["dbs"]) => {
+                let id = id_of(&body)?;
+                if databases.contains_key(&id) {
+                    return Err(Failure::conflict(
+                        "Entity with the specified id already exists in the system.",
+                    ));
+                }
+                let content = offer_content(req)?;
+                let mut resource = json!({"id": id, "_colls": "colls/", "_users": "users/"});
+                let rid = random_rid();
+                stamp(&mut resource, format!("dbs/{}/", rid), rid);
+                self.add_offer(&resource, content);
+                databases.insert(
+                    id,
+                    Database {
+                        resource: resource.clone(),
+                        collections: BTreeMap::new(),
+                    },
+                );
+                Ok(Reply::resource(StatusCode::CREATED, resource))
+            }
+            (Method::GET, ["dbs", database]) => {
+                let database = databases.get(*database).ok_or_else(Failure::not_found)?;
+                Ok(Reply::resource(StatusCode::OK, database.resource.clone()))
+            }
+            (Method::DELETE, ["dbs", database]) => {
+                let database = databases.remove(*database).ok_or_else(Failure::not_found)?;
+                // the offers of the database and its containers go with it
+                let self_link = database.resource["_self"].as_str().unwrap_or_default();
+                self.offers.lock().unwrap().retain(|offer| {
+                    !offer["resource"]
+                        .as_str()
+                        .unwrap_or_default()
+                        .starts_with(self_link)
+                });
+                Ok(Reply::new(StatusCode::NO_CONTENT, None))
+            }
+            (Method::POST, ["dbs", database, "colls"]) => {
+                let content = offer_content(req)?;
+                let reply = databases
+                    .get_mut(*database)
+                    .ok_or_else(Failure::not_found)?
+                    .create_collection(body)?;
+                if let Some(collection) = &reply.body {
+                    self.add_offer(collection, content);
+                }
+                Ok(reply)
+            }
+            (Method::POST, ["offers"]) => self.query_offers(req, &body),
+            (Method::PUT, ["offers", rid]) => self.replace_offer(rid, &body),
+            (Method::PUT, ["dbs", database, "colls", collection]) => databases
+                .get_mut(*database)
+                .and_then(|database| database.collections.get_mut(*collection))
+                .ok_or_else(Failure::not_found)?
+                .replace_definition(body),
+            (Method::GET, ["dbs", database, "colls", collection]) => {
+                let collection = databases
+                    .get(*database)
+                    .and_then(|database| database.collections.get(*collection))
+                    .ok_or_else(Failure::not_found)?;
+                Ok(Reply::resource(StatusCode::OK, collection.resource.clone()))
+            }
+            (Method::POST, ["dbs", database, "colls", collection, "docs"]) => {
+                let collection = collection_mut(&mut databases, database, collection)?;
+                let is_query = is_set(req, "x-ms-documentdb-isquery")
+                    || header(req, "content-type")
+                        .is_some_and(|value| value.starts_with("application/query+json"));
+                if is_set(req, "x-ms-cosmos-is-batch-request") {
+                    collection.batch(&required_partition_key(req)?, body)
+                } else if is_query {
+                    collection.query(req, body)
+                } else {
+                    let upsert = is_set(req, "x-ms-documentdb-is-upsert");
+                    let (status, document) =
+                        collection.create(&required_partition_key(req)?, body, upsert)?;
+                    Ok(Reply::resource(status, document))
+                }
+            }
+            (Method::GET, ["dbs", database, "colls", collection, "docs", id]) => {
+                let collection = collection_mut(&mut databases, database, collection)?;
+                let document = collection.get(&required_partition_key(req)?, id)?;
+                Ok(Reply::resource(StatusCode::OK, document.clone()))
+            }
+            (Method::PUT, ["dbs", database, "colls", collection, "docs", id]) => {
+                let collection = collection_mut(&mut databases, database, collection)?;
+                let document = collection.replace(
+                    &required_partition_key(req)?,
+                    id,
+                    body,
+                    header(req, "if-match"),
+                )?;
+                Ok(Reply::resource(StatusCode::OK, document))
+            }
+            (Method::DELETE, ["dbs", database, "colls", collection, "docs", id]) => {
+                let collection = collection_mut(&mut databases, database, collection)?;
+                collection.delete(&required_partition_key(req)?, id, header(req, "if-match"))?;
+                Ok(Reply::new(StatusCode::NO_CONTENT, None))
+            }
+            (method, _) => Err(Failure::bad_request(format!(
+                "LocalCosmos doesn't support {} {}",
+                method,
+                req.path()
+            ))),
+        }
+    }
+
+    /**
+     *  add the headers the sdk expects on every response.  the values are made up, but they parse the way real ones
+     *  do
+     */
+    fn respond(&self, reply: Reply, resource_link: &str) -> HttpResponse {
+        let lsn = self.lsn.fetch_add(1, atomic::Ordering::SeqCst) + 1;
+        let date = httpdate::fmt_http_date(SystemTime::now());
+        let activity_id = format!("{:032x}", rand::random::<u128>());
+        let activity_id = format!(
+            "{}-{}-{}-{}-{}",
+            &activity_id[..8],
+            &activity_id[8..12],
+            &activity_id[12..16],
+            &activity_id[16..20],
+            &activity_id[20..]
+        );
+        let mut response = HttpResponse::build(reply.status);
+        let lsn = lsn.to_string();
+        let headers = vec![
+            ("date", date.clone()),
+            ("x-ms-activity-id", activity_id),
+            ("x-ms-request-charge", "1".to_string()),
+            ("x-ms-request-duration-ms", "0.1".to_string()),
+            ("x-ms-session-token", format!("0:-1#{}", lsn)),
+            ("x-ms-last-state-change-utc", date),
+            (
+                "x-ms-resource-quota",
+                "documentSize=10240;documentsSize=10485760;documentsCount=-1;collectionSize=10485760;"
+                    .to_string(),
+            ),
+            (
+                "x-ms-resource-usage",
+                "documentSize=0;documentsSize=1;documentsCount=1;collectionSize=1;".to_string(),
+            ),
+            ("x-ms-schemaversion", "1.14".to_string()),
+            ("x-ms-alt-content-path", resource_link.to_string()),
+            ("x-ms-content-path", "AAAAAA==".to_string()),
+            ("lsn", lsn.clone()),
+            ("x-ms-lsn", lsn.clone()),
+            ("x-ms-item-lsn", lsn.clone()),
+            ("x-ms-quorum-acked-lsn", lsn.clone()),
+            ("x-ms-global-committed-lsn", lsn.clone()),
+            ("x-ms-cosmos-llsn", lsn.clone()),
+            ("x-ms-cosmos-item-llsn", lsn.clone()),
+            ("x-ms-cosmos-quorum-acked-llsn", lsn),
+            ("x-ms-current-write-quorum", "1".to_string()),
+            ("x-ms-current-replica-set-size", "1".to_string()),
+            ("x-ms-xp-role", "1".to_string()),
+            ("x-ms-number-of-read-regions", "0".to_string()),
+            ("x-ms-transport-request-id", "1".to_string()),
+            ("x-ms-documentdb-partitionkeyrangeid", "0".to_string()),
+            ("x-ms-serviceversion", "version=2.14.0.0".to_string()),
+            ("x-ms-gatewayversion", "version=2.14.0".to_string()),
+        ];
+        for header in headers.into_iter().chain(reply.headers) {
+            response.insert_header(header);
+        }
+        match reply.body {
+            Some(body) => response
+                .content_type("application/json")
+                .body(body.to_string()),
+            None => response.finish(),
+        }
+    }
+}
+
+fn collection_mut<'a>(
+    databases: &'a mut BTreeMap<String, Database>,
+    database: &str,
+    collection: &str,
+) -> Result<&'a mut Collection, Failure> {
+    databases
+        .get_mut(database)
+        .and_then(|database| database.collections.get_mut(collection))
+        .ok_or_else(Failure::not_found)
+}
+
+fn id_of(resource: &Value) -> Result<String, Failure> {
+    match resource.get("id").and_then(Value::as_str) {
+        Some(id) if !id.is_empty() => Ok(id.to_string()),
+        _ => Err(Failure::bad_request(
+            "The input content is invalid because the required properties - 'id; ' - are missing",
+        )),
+    }
+}
+
+impl Database {
+    fn create_collection(&mut self, mut resource: Value) -> Result<Reply, Failure> {
+        let id = id_of(&resource)?;
+        if self.collections.contains_key(&id) {
+            return Err(Failure::conflict(
+                "Entity with the specified id already exists in the system.",
+            ));
+        }
+        let partition_key_path = resource["partitionKey"]["paths"][0]
+            .as_str()
+            .ok_or_else(|| Failure::bad_request("a container needs a partitionKey path"))?
+            .to_string();
+        let unique_keys = resource["uniqueKeyPolicy"]["uniqueKeys"]
+            .as_array()
+            .map(|keys| {
+                keys.iter()
+                    .map(|key| {
+                        key["paths"]
+                            .as_array()
+                            .map(|paths| {
+                                paths
+                                    .iter()
+                                    .filter_map(|path| path.as_str().map(str::to_string))
+                                    .collect()
+                            })
+                            .unwrap_or_default()
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        let rid = random_rid();
+        let self_link = format!(
+            "{}colls/{}/",
+            self.resource["_self"].as_str().unwrap_or_default(),
+            rid
+        );
+        stamp(&mut resource, self_link, rid);
+        resource["_docs"] = Value::from("docs/");
+        if resource.get("indexingPolicy").is_none() {
+            resource["indexingPolicy"] = json!({
+                "indexingMode": "consistent",
+                "automatic": true,
+                "includedPaths": [{"path": "/*"}],
+                "excludedPaths": [{"path": "/\"_etag\"/?"}]
+            });
+        }
+        self.collections.insert(
+            id,
+            Collection {
+                resource: resource.clone(),
+                partition_key_path,
+                unique_keys,
+                documents: BTreeMap::new(),
+            },
+        );
+        Ok(Reply::resource(StatusCode::CREATED, resource))
+    }
+}
+
+impl Collection {
+    /**
+     *  only the indexing policy can change.  the partition key and unique keys are fixed when the container is created
+     */
+    fn replace_definition(&mut self, body: Value) -> Result<Reply, Failure> {
+        for fixed in &["partitionKey", "uniqueKeyPolicy"] {
+            if body
+                .get(fixed)
+                .is_some_and(|value| value != &self.resource[fixed])
+            {
+                return Err(Failure::bad_request(format!(
+                    "{} of a container can't be changed",
+                    fixed
+                )));
+            }
+        }
+        if let Some(policy) = body.get("indexingPolicy") {
+            self.resource["indexingPolicy"] = policy.clone();
+        }
+        self.resource["_etag"] = Value::from(format!("\"{:016x}\"", rand::random::<u64>()));
+        Ok(Reply::resource(StatusCode::OK, self.resource.clone()))
+    }
+
+    /**
+     *  whether field has a range index.  the most specific path that matches it decides, so /name/? included beats the
+     *  root path excluded
+     */
+    fn is_indexed(&self, field: &str) -> bool {
+        let policy = &self.resource["indexingPolicy"];
+        let specificity = |paths: &Value| {
+            paths
+                .as_array()?
+                .iter()
+                .filter_map(|path| match path["path"].as_str()? {
+                    "/*" => Some(0),
+                    path if path == format!("/{}/?", field) || path == format!("/{}/*", field) => {
+                        Some(1)
+                    }
+                    _ => None,
+                })
+                .max()
+        };
+        match (
+            specificity(&policy["includedPaths"]),
+            specificity(&policy["excludedPaths"]),
+        ) {
+            (Some(included), Some(excluded)) => included > excluded,
+            (included, _) => included.is_some(),
+        }
+    }
+
+    fn key(partition_key: &Value, id: &str) -> (String, String) {
+        (partition_key.to_string(), id.to_string())
+    }
+
+    fn get(&self, partition_key: &Value, id: &str) -> Result<&Value, Failure> {
+        self.documents
+            .get(&Collection::key(partition_key, id))
+            .ok_or_else(Failure::not_found)
+    }
+
+    fn check_if_match(document: &Value, if_match: Option<&str>) -> Result<(), Failure> {
+        match if_match {
+            Some(etag) if etag != "*" && document["_etag"] != etag => Err(Failure::new(
+                StatusCode::PRECONDITION_FAILED,
+                "PreconditionFailed",
+                "Operation cannot be performed because one of the specified precondition is not met.",
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /**
+     *  write document, after checking that it belongs in partition_key and that it doesn't repeat a unique key
+     */
+    fn store(&mut self, partition_key: &Value, mut document: Value) -> Result<Value, Failure> {
+        let id = id_of(&document)?;
+        if !document.is_object() {
+            return Err(Failure::bad_request("a document has to be a JSON object"));
+        }
+        if document.pointer(&self.partition_key_path) != Some(partition_key) {
+            return Err(Failure::bad_request(
+                "PartitionKey extracted from document doesn't match the one specified in the header.",
+            ));
+        }
+        let key = Collection::key(partition_key, &id);
+        for paths in &self.unique_keys {
+            let values: Vec<Option<&Value>> =
+                paths.iter().map(|path| document.pointer(path)).collect();
+            let taken = self.documents.iter().any(|((other_key, other_id), other)| {
+                *other_key == key.0
+                    && *other_id != id
+                    && paths
+                        .iter()
+                        .map(|path| other.pointer(path))
+                        .eq(values.iter().cloned())
+            });
+            if taken {
+                return Err(Failure::conflict("Unique index constraint violation."));
+            }
+        }
+        let rid = random_rid();
+        let self_link = format!(
+            "{}docs/{}/",
+            self.resource["_self"].as_str().unwrap_or_default(),
+            rid
+        );
+        stamp(&mut document, self_link, rid);
+        document["_attachments"] = Value::from("attachments/");
+        self.documents.insert(key, document.clone());
+        Ok(document)
+    }
+
+    fn create(
+        &mut self,
+        partition_key: &Value,
+        document: Value,
+        upsert: bool,
+    ) -> Result<(StatusCode, Value), Failure> {
+        let exists = self
+            .documents
+            .contains_key(&Collection::key(partition_key, &id_of(&document)?));
+        if exists && !upsert {
+            return Err(Failure::conflict(
+                "Entity with the specified id already exists in the system.",
+            ));
+        }
+        let status = if exists {
+            StatusCode::OK
+        } else {
+            StatusCode::CREATED
+        };
+        Ok((status, self.store(partition_key, document)?))
+    }
+
+    fn replace(
+        &mut self,
+        partition_key: &Value,
+        id: &str,
+        document: Value,
+        if_match: Option<&str>,
+    ) -> Result<Value, Failure> {
+        Collection::check_if_match(self.get(partition_key, id)?, if_match)?;
+        if id_of(&document)? != id {
+            return Err(Failure::bad_request(
+                "the id in the body is not the id of the document being replaced",
+            ));
+        }
+        self.store(partition_key, document)
+    }
+
+    fn delete(
+        &mut self,
+        partition_key: &Value,
+        id: &str,
+        if_match: Option<&str>,
+    ) -> Result<(), Failure> {
+        Collection::check_if_match(self.get(partition_key, id)?, if_match)?;
+        self.documents.remove(&Collection::key(partition_key, id));
+        Ok(())
+    }
+
+    /**
+     *  run the operations on a copy, and keep it only if every one of them worked.  like cosmos, a failed batch answers
+     *  with the status of the operation that failed, and every other operation is a 424
+     */
+    fn batch(&mut self, partition_key: &Value, operations: Value) -> Result<Reply, Failure> {
+        let operations = operations
+            .as_array()
+            .filter(|operations| (1..=MAX_BATCH_OPERATIONS).contains(&operations.len()))
+            .ok_or_else(|| {
+                Failure::bad_request(format!(
+                    "a batch is an array of 1 to {} operations",
+                    MAX_BATCH_OPERATIONS
+                ))
+            })?;
+        let mut copy = self.clone();
+        let mut results = Vec::new();
+        for (index, operation) in operations.iter().enumerate() {
+            match copy.apply(partition_key, operation) {
+                Ok((status, document)) => results.push(json!({
+                    "statusCode": status.as_u16(),
+                    "requestCharge": 1.0,
+                    "eTag": document.as_ref().map(|document| document["_etag"].clone()),
+                    "resourceBody": document,
+                })),
+                Err(failure) => {
+                    let results: Vec<Value> = (0..operations.len())
+                        .map(|other| {
+                            let status = match other == index {
+                                true => failure.status,
+                                false => StatusCode::FAILED_DEPENDENCY,
+                            };
+                            json!({"statusCode": status.as_u16(), "requestCharge": 1.0})
+                        })
+                        .collect();
+                    return Ok(Reply::new(failure.status, Some(Value::from(results))));
+                }
+            }
+        }
+        *self = copy;
+        Ok(Reply::new(StatusCode::OK, Some(Value::from(results))))
+    }
+
+    fn apply(
+        &mut self,
+        partition_key: &Value,
+        operation: &Value,
+    ) -> Result<(StatusCode, Option<Value>), Failure> {
+        let id = operation["id"].as_str().unwrap_or_default();
+        let if_match = operation["ifMatch"].as_str();
+        let body = operation["resourceBody"].clone();
+        match operation["operationType"].as_str() {
+            Some("Create") => self
+                .create(partition_key, body, false)
+                .map(|(status, document)| (status, Some(document))),
+            Some("Upsert") => self
+                .create(partition_key, body, true)
+                .map(|(status, document)| (status, Some(document))),
+            Some("Replace") => self
+                .replace(partition_key, id, body, if_match)
+                .map(|document| (StatusCode::OK, Some(document))),
+            Some("Delete") => self
+                .delete(partition_key, id, if_match)
+                .map(|()| (StatusCode::NO_CONTENT, None)),
+            Some("Read") => self
+                .get(partition_key, id)
+                .map(|document| (StatusCode::OK, Some(document.clone()))),
+            other => Err(Failure::bad_request(format!(
+                "LocalCosmos doesn't support the batch operation {:?}",
+                other
+            ))),
+        }
+    }
+
+    /**
+     *  run a query from QueryBuilder.  the continuation token is just how many results came before the page
+     */
+    fn query(&self, req: &HttpRequest, body: Value) -> Result<Reply, Failure> {
+        let query = Query::parse(&body)?;
+        let partition_key = partition_key(req)?.map(|key| key.to_string());
+        let mut found: Vec<&Value> = self
+            .documents
+            .iter()
+            .filter(|((key, _), _)| partition_key.as_ref().is_none_or(|wanted| key == wanted))
+            .map(|(_, document)| document)
+            .filter(|document| query.matches(document))
+            .collect();
+        if query.count {
+            return Ok(Reply::new(
+                StatusCode::OK,
+                Some(
+                    json!({"_rid": self.resource["_rid"], "Documents": [found.len()], "_count": 1}),
+                ),
+            )
+            .header("x-ms-item-count", "1".to_string()));
+        }
+        if let Some((field, descending)) = &query.order_by {
+            if !self.is_indexed(field) {
+                return Err(Failure::bad_request(format!(
+                    "Order-by over correlated collections is not supported.  the index for {} is excluded",
+                    field
+                )));
+            }
+            found.sort_by(|a, b| {
+                let order = compare(a.get(field), b.get(field));
+                if *descending {
+                    order.reverse()
+                } else {
+                    order
+                }
+            });
+        }
+
+        let start = match header(req, "x-ms-continuation") {
+            Some(token) => token.parse::<usize>().map_err(|_| {
+                Failure::bad_request(format!("continuation {} is not valid", token))
+            })?,
+            None => 0,
+        };
+        let page_size = header(req, "x-ms-max-item-count")
+            .and_then(|count| count.parse::<i64>().ok())
+            .filter(|count| *count > 0)
+            .map_or(DEFAULT_MAX_ITEM_COUNT, |count| count as usize);
+        let page: Vec<Value> = found
+            .iter()
+            .skip(start)
+            .take(page_size)
+            .map(|document| (*document).clone())
+            .collect();
+        let next = start + page.len();
+        let mut reply = Reply::new(
+            StatusCode::OK,
+            Some(json!({"_rid": self.resource["_rid"], "Documents": page, "_count": page.len()})),
+        )
+        .header("x-ms-item-count", page.len().to_string());
+        if next < found.len() {
+            reply = reply.header("x-ms-continuation", next.to_string());
+        }
+        Ok(reply)
+    }
+}
+
+/**
+ *  the order cosmos sorts values of different types in: missing, null, booleans, numbers, then strings
+ */
+fn compare(a: Option<&Value>, b: Option<&Value>) -> Ordering {
+    let rank = |value: Option<&Value>| match value {
+        None => 0,
+        Some(Value::Null) => 1,
+        Some(Value::Bool(..)) => 2,
+        Some(Value::Number(..)) => 3,
+        Some(Value::String(..)) => 4,
+        Some(..) => 5,
+    };
+    match (a, b) {
+        (Some(Value::Bool(a)), Some(Value::Bool(b))) => a.cmp(b),
+        (Some(Value::Number(a)), Some(Value::Number(b))) => a
+            .as_f64()
+            .partial_cmp(&b.as_f64())
+            .unwrap_or(Ordering::Equal),
+        (Some(Value::String(a)), Some(Value::String(b))) => a.cmp(b),
+        _ => rank(a).cmp(&rank(b)),
+    }
+}
+
+/**
+ *  a query as QueryBuilder writes it: SELECT * or SELECT VALUE COUNT(1), conditions ANDed together, and at most one
+ *  ORDER BY
+ */
+struct Query {
+    count: bool,
+    conditions: Vec<Condition>,
+    order_by: Option<(String, bool)>,
+}
+
+enum Condition {
+    Equals(String, Value),
+    StartsWith(String, String, bool),
+    EndsWith(String, String),
+}
+
+impl Query {
+    fn parse(body: &Value) -> Result<Query, Failure> {
+        let text = body["query"]
+            .as_str()
+            .ok_or_else(|| Failure::bad_request("the body of a query needs a query"))?;
+        let params: BTreeMap<&str, &Value> = body["parameters"]
+            .as_array()
+            .map(|params| {
+                params
+                    .iter()
+                    .filter_map(|param| Some((param["name"].as_str()?, param.get("value")?)))
+                    .collect()
+            })
+            .unwrap_or_default();
+        let unsupported =
+            || Failure::bad_request(format!("LocalCosmos doesn't support the query {}", text));
+
+        let (count, rest) = if let Some(rest) = text.strip_prefix("SELECT * FROM c") {
+            (false, rest)
+        } else if let Some(rest) = text.strip_prefix("SELECT VALUE COUNT(1) FROM c") {
+            (true, rest)
+        } else {
+            return Err(unsupported());
+        };
+        let (rest, order_by) = match rest.split_once(" ORDER BY ") {
+            Some((rest, order_by)) => {
+                let descending = match order_by.split_once(' ') {
+                    Some((_, "ASC")) => false,
+                    Some((_, "DESC")) => true,
+                    _ => return Err(unsupported()),
+                };
+                let field = order_by
+                    .split(' ')
+                    .next()
+                    .and_then(|field| field.strip_prefix("c."))
+                    .ok_or_else(unsupported)?;
+                (rest, Some((field.to_string(), descending)))
+            }
+            None => (rest, None),
+        };
+        let mut conditions = Vec::new();
+        if !rest.trim().is_empty() {
+            let clauses = rest.trim().strip_prefix("WHERE ").ok_or_else(unsupported)?;
+            for clause in clauses.split(" AND ") {
+                conditions.push(Condition::parse(clause, &params).ok_or_else(unsupported)?);
+            }
+        }
+        Ok(Query {
+            count,
+            conditions,
+            order_by,
+        })
+    }
+
+    fn matches(&self, document: &Value) -> bool {
+        self.conditions
+            .iter()
+            .all(|condition| condition.matches(document))
+    }
+}
+
+impl Condition {
+    fn parse(clause: &str, params: &BTreeMap<&str, &Value>) -> Option<Condition> {
+        let field = |field: &str| field.strip_prefix("c.").map(str::to_string);
+        let text = |param: &str| params.get(param)?.as_str().map(str::to_string);
+        if let Some(args) = clause
+            .strip_prefix("STARTSWITH(")
+            .and_then(|args| args.strip_suffix(')'))
+        {
+            return match args.split(", ").collect::<Vec<_>>().as_slice() {
+                [name, param] => Some(Condition::StartsWith(field(name)?, text(param)?, false)),
+                [name, param, "true"] => {
+                    Some(Condition::StartsWith(field(name)?, text(param)?, true))
+                }
+                _ => None,
+            };
+        }
+        if let Some(args) = clause
+            .strip_prefix("ENDSWITH(")
+            .and_then(|args| args.strip_suffix(')'))
+        {
+            let (name, param) = args.split_once(", ")?;
+            return Some(Condition::EndsWith(field(name)?, text(param)?));
+        }
+        let (name, param) = clause.split_once(" = ")?;
+        Some(Condition::Equals(
+            field(name)?,
+            (*params.get(param)?).clone(),
+        ))
+    }
+
+    fn matches(&self, document: &Value) -> bool {
+        let text = |field: &str| document.get(field).and_then(Value::as_str);
+        match self {
+            Condition::Equals(field, value) => document.get(field) == Some(value),
+            Condition::StartsWith(field, prefix, true) => text(field)
+                .is_some_and(|text| text.to_lowercase().starts_with(&prefix.to_lowercase())),
+            Condition::StartsWith(field, prefix, false) => {
+                text(field).is_some_and(|text| text.starts_with(prefix.as_str()))
+            }
+            Condition::EndsWith(field, suffix) => {
+                text(field).is_some_and(|text| text.ends_with(suffix.as_str()))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cosmos_rest::{offer_throughput, CosmosRest};
+    use crate::error::UserDbError;
+    use crate::indexing::IndexingPolicy;
+    use crate::throughput::Throughput;
+    use reqwest::Method;
+
+    async fn create_container(rest: &CosmosRest) {
+        rest.send(Method::POST, "dbs", "", "dbs", Some(&json!({"id": "db"})))
+            .await
+            .unwrap();
+        rest.create_collection(
+            "db",
+            &json!({
+                "id": "users",
+                "partitionKey": {"paths": ["/partition_key"], "kind": "Hash"},
+                "uniqueKeyPolicy": {"uniqueKeys": [{"paths": ["/email"]}]}
+            }),
+            None,
+        )
+        .await
+        .unwrap();
+    }
+
+    fn create(id: &str, email: &str) -> Value {
+        json!({
+            "operationType": "Create",
+            "resourceBody": {"id": id, "partition_key": "p", "email": email, "name": id},
+        })
+    }
+
+    #[actix_web::test]
+    async fn test_requests_must_be_signed() {
+        let cosmos = LocalCosmos::start();
+        let stranger = CosmosRest::new(
+            reqwest::Client::new(),
+            cosmos.endpoint(),
+            CosmosCredential::MasterKey(STANDARD.encode("not the key")),
+        );
+        let err = stranger
+            .send(Method::POST, "dbs", "", "dbs", Some(&json!({"id": "db"})))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, UserDbError::Unauthorized(..)));
+
+        let rest = CosmosRest::new(
+            reqwest::Client::new(),
+            cosmos.endpoint(),
+            cosmos.credential(),
+        );
+        create_container(&rest).await;
+        let err = rest
+            .send(Method::POST, "dbs", "", "dbs", Some(&json!({"id": "db"})))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, UserDbError::Conflict(..)));
+        let database = rest
+            .send(Method::GET, "dbs", "dbs/db", "dbs/db", None)
+            .await
+            .unwrap();
+        assert_eq!(database["id"], "db");
+        let collection = rest.get_collection("db", "users").await.unwrap();
+        assert_eq!(
+            collection["partitionKey"]["paths"],
+            json!(["/partition_key"])
+        );
+        let err = rest.get_collection("db", "missing").await.unwrap_err();
+        assert!(matches!(err, UserDbError::NotFound(..)));
+    }
+
+    #[actix_web::test]
+    async fn test_throughput() {
+        let cosmos = LocalCosmos::start();
+        let rest = CosmosRest::new(
+            reqwest::Client::new(),
+            cosmos.endpoint(),
+            cosmos.credential(),
+        );
+        let database = rest
+            .create_database("db", Some(Throughput::Autoscale(4000)))
+            .await
+            .unwrap();
+        let collection = rest
+            .create_collection(
+                "db",
+                &json!({"id": "users", "partitionKey": {"paths": ["/partition_key"], "kind": "Hash"}}),
+                Some(Throughput::Manual(400)),
+            )
+            .await
+            .unwrap();
+
+        let offer = rest
+            .offer_for(database["_rid"].as_str().unwrap())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            offer_throughput(&offer).unwrap(),
+            Throughput::Autoscale(4000)
+        );
+        let offer = rest
+            .offer_for(collection["_rid"].as_str().unwrap())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(offer_throughput(&offer).unwrap(), Throughput::Manual(400));
+
+        let offer = rest
+            .replace_offer(&offer, Throughput::Manual(1000))
+            .await
+            .unwrap();
+        assert_eq!(offer_throughput(&offer).unwrap(), Throughput::Manual(1000));
+        let err = rest
+            .replace_offer(&offer, Throughput::Autoscale(4000))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, UserDbError::Validation(..)));
+
+        // a container without its own throughput has no offer
+        let shared = rest
+            .create_collection(
+                "db",
+                &json!({"id": "shared", "partitionKey": {"paths": ["/partition_key"], "kind": "Hash"}}),
+                None,
+            )
+            .await
+            .unwrap();
+        let none = rest
+            .offer_for(shared["_rid"].as_str().unwrap())
+            .await
+            .unwrap();
+        assert!(none.is_none());
+    }
+
+    #[actix_web::test]
+    async fn test_indexing_policy() {
+        let cosmos = LocalCosmos::start();
+        let rest = CosmosRest::new(
+            reqwest::Client::new(),
+            cosmos.endpoint(),
+            cosmos.credential(),
+        );
+        create_container(&rest).await;
+        let mut collection = rest.get_collection("db", "users").await.unwrap();
+        assert_eq!(
+            IndexingPolicy::of_collection(&collection),
+            IndexingPolicy::index_everything()
+        );
+
+        collection["indexingPolicy"] =
+            serde_json::to_value(IndexingPolicy::for_users("/partition_key")).unwrap();
+        let replaced = rest.replace_collection("db", &collection).await.unwrap();
+        let indexed = Collection {
+            resource: replaced,
+            partition_key_path: "/partition_key".to_string(),
+            unique_keys: Vec::new(),
+            documents: BTreeMap::new(),
+        };
+        assert!(indexed.is_indexed("name"));
+        assert!(!indexed.is_indexed("age"));
+
+        collection["partitionKey"]["paths"] = json!(["/tenant"]);
+        let err = rest
+            .replace_collection("db", &collection)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, UserDbError::Validation(..)));
+    }
+
+    #[actix_web::test]
+    async fn test_batches_are_all_or_nothing() {
+        let cosmos = LocalCosmos::start();
+        let rest = CosmosRest::new(
+            reqwest::Client::new(),
+            cosmos.endpoint(),
+            cosmos.credential(),
+        );
+        create_container(&rest).await;
+
+        let results = rest
+            .transactional_batch(
+                "db",
+                "users",
+                "p",
+                &[create("a", "a@example.com"), create("b", "b@example.com")],
+            )
+            .await
+            .unwrap();
+        assert!(results.iter().all(|result| result.status == 201));
+        let etag = results[0].etag.clone().unwrap();
+        assert_eq!(results[0].body.as_ref().unwrap()["_etag"], etag.as_str());
+
+        // the email is taken, so the delete before it is rolled back too
+        let results = rest
+            .transactional_batch(
+                "db",
+                "users",
+                "p",
+                &[
+                    json!({"operationType": "Delete", "id": "a", "ifMatch": etag}),
+                    create("c", "b@example.com"),
+                ],
+            )
+            .await
+            .unwrap();
+        let statuses: Vec<u16> = results.iter().map(|result| result.status).collect();
+        assert_eq!(statuses, vec![424, 409]);
+        let results = rest
+            .transactional_batch(
+                "db",
+                "users",
+                "p",
+                &[json!({"operationType": "Read", "id": "a"})],
+            )
+            .await
+            .unwrap();
+        assert_eq!(results[0].status, 200);
+
+        // a document has to be in the batch's partition
+        let results = rest
+            .transactional_batch("db", "users", "q", &[create("d", "d@example.com")])
+            .await
+            .unwrap();
+        assert_eq!(results[0].status, 400);
+    }
+
+    #[test]
+    fn test_queries() {
+        let documents: Vec<Value> = ["Bob", "ann", "Andy"]
+            .iter()
+            .map(|name| json!({"id": name.to_lowercase(), "name": name, "email": format!("{}@example.com", name)}))
+            .collect();
+        let run = |text: &str, params: Value| -> Vec<String> {
+            let query = Query::parse(&json!({"query": text, "parameters": params})).unwrap();
+            let mut found: Vec<&Value> = documents.iter().filter(|d| query.matches(d)).collect();
+            if let Some((field, descending)) = &query.order_by {
+                found.sort_by(|a, b| compare(a.get(field), b.get(field)));
+                if *descending {
+                    found.reverse();
+                }
+            }
+            found
+                .iter()
+                .map(|d| d["id"].as_str().unwrap().to_string())
+                .collect()
+        };
+
+        assert_eq!(
+            run(
+                "SELECT * FROM c WHERE STARTSWITH(c.name, @p0, true) ORDER BY c.name DESC",
+                json!([{"name": "@p0", "value": "AN"}])
+            ),
+            vec!["ann", "andy"]
+        );
+        assert_eq!(
+            run(
+                "SELECT * FROM c WHERE c.id = @p0 AND ENDSWITH(c.email, @p1)",
+                json!([{"name": "@p0", "value": "bob"}, {"name": "@p1", "value": "@example.com"}])
+            ),
+            vec!["bob"]
+        );
+        assert!(Query::parse(&json!({"query": "SELECT c.id FROM c"})).is_err());
+        assert!(Query::parse(&json!({"query": "SELECT * FROM c WHERE c.id = @missing"})).is_err());
+    }
+}
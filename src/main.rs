@@ -3,102 +3,116 @@
  */
 //
 //  rust wants modules in the same directory declared.
+mod auth;
+mod config;
+mod cosmos_rest;
 mod cosmosdb;
+mod error;
+mod faults;
+mod indexing;
+#[cfg(test)]
+mod local_cosmos;
+mod memorydb;
 mod models;
+mod partition;
+mod query;
+mod retry;
+mod store;
+mod throughput;
+mod transaction;
 mod users;
 mod utility;
+mod validation;
 
 // dependencies...
 use actix_cors::Cors;
 use actix_web::{web, App, HttpServer};
-use cosmosdb::get_cosmos_secrets;
-use log::{trace};
-use once_cell::sync::OnceCell;
-use std::env;
+use config::Config;
+use log::info;
+use std::sync::Arc;
 
-
-/**
- *  Code to pick a port in a threadsafe way -- either specified in an environment variable named COSMOS_RUST_SAMPLE_PORT
- *  or 8080
- */
-static PORT: OnceCell<String> = OnceCell::new();
-
-#[allow(unused_macros)]
-#[macro_export]
-macro_rules! safe_set_port {
-    () => {{
-        let port: String;
-        match PORT.get() {
-            Some(val) => {
-                port = val.to_string();
-            }
-            None => {
-                match env::var("COSMOS_RUST_SAMPLE_PORT") {
-                    Ok(val) => port = val.to_string(),
-                    Err(_e) => port = "8080".to_string(),
-                }
-                println!("setting port to: {}", port);
-                match PORT.set(port.clone()) {
-                    Ok(_) => {}
-                    Err(e) => {
-                        println!("error setting port: {:?}", e.to_string());
-                    }
-                }
-            }
-        };
-        port
-    }};
-}
 /**
  *  main:  entry point that sets up the web service
  */
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     //
-    //   turn on max logging by setting web, server, and rust to trace level logging
-
-    env::set_var("RUST_LOG", "actix_web=trace,actix_server=trace,rust=trace");
-    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+    //  everything we need to know to start comes from Config -- see config.rs for the file, environment variables and
+    //  flags it reads.  if anything is wrong, say what and stop before we start listening
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(error) => {
+            eprintln!("{:#}", error);
+            std::process::exit(1);
+        }
+    };
 
+    env_logger::Builder::new()
+        .parse_filters(&config.log_level)
+        .init();
+    info!("configuration: {:?}", config);
 
-    let port: String = safe_set_port!();
-    // this looks up env variables and puts them into a rust structt - if they aren't set, we error out
-    let secrets = get_cosmos_secrets();
-    match secrets {
-        Ok(secrets) => trace!("Secrets found.  Account: {:?}", secrets.account),
-        Err(error) => panic!("Failed to get secrets: {}\n\
-                              If you are running in a dev container for the \
-                              first time, you need to restart VS Code.", error),
-    }
-    
-
-    // normally you would set the RUST_LOG environment variable in the process that this app is running in, and then
-    // you'd have this code that checks for it and errors out if it doesn't exist.  this value is set above, so it will
-    // always be set, but this is here for completeness
-    let rust_log = env::var("RUST_LOG");
-    match rust_log {
-        Ok(value) => trace!("RUST_LOG: {}", value),
-        Err(_) => trace!("RUST_LOG is not set"),
-    }
+    //
+    //  build the store once -- every worker shares it via web::Data so we don't reconnect on every request.  if we
+    //  can't (e.g. the cosmos secrets are missing or malformed) there is no point in starting the server
+    let retry_metrics = Arc::new(retry::RetryMetrics::default());
+    let user_store = match store::create_store(&config, retry_metrics.clone()).await {
+        Ok(user_store) => web::Data::from(user_store),
+        Err(error) => {
+            eprintln!("Failed to create the {:?} store: {}", config.backend, error);
+            std::process::exit(1);
+        }
+    };
+    let bind_address = (config.bind_address.clone(), config.port);
+    let config = web::Data::new(config);
+    let retry_metrics = web::Data::from(retry_metrics);
 
     //
     // set up the HttpServer
 
-    HttpServer::new(|| {
+    HttpServer::new(move || {
         App::new()
             .wrap(Cors::permissive())
+            .app_data(user_store.clone())
+            .app_data(config.clone())
+            .app_data(retry_metrics.clone())
+            .app_data(web::QueryConfig::default().error_handler(users::query_error))
             .service(
                 web::scope("/api").service(
                     web::scope("/v1")
                         .route("/users", web::get().to(users::list_users))
                         .route("/users", web::post().to(users::create))
+                        .route("/users/count", web::get().to(users::count_users))
+                        .service(
+                            web::resource("/users:batch")
+                                .app_data(users::batch_json_config())
+                                .route(web::post().to(users::create_batch))
+                                .route(web::delete().to(users::delete_batch)),
+                        )
+                        .route("/users/{id}", web::put().to(users::replace))
+                        .route("/users/{id}", web::patch().to(users::patch))
                         .route("/users/{id}", web::delete().to(users::delete))
                         .route("/users/{id}", web::get().to(users::find_user_by_id))
-                        .route("/setup", web::post().to(users::setup)),
+                        .service(
+                            web::resource("/partitions/{partition_key}/transaction")
+                                .app_data(users::batch_json_config())
+                                .route(web::post().to(users::transaction)),
+                        )
+                        .route("/setup", web::post().to(users::setup))
+                        .route("/reset", web::post().to(users::reset))
+                        .route("/migrate", web::post().to(users::migrate))
+                        .route("/admin/throughput", web::get().to(users::get_throughput))
+                        .route("/admin/throughput", web::put().to(users::set_throughput))
+                        .route("/admin/indexing", web::get().to(users::indexing_policy))
+                        .route(
+                            "/admin/indexing",
+                            web::post().to(users::apply_indexing_policy),
+                        )
+                        .route("/metrics/retries", web::get().to(users::retry_metrics)),
                 ),
             )
     })
-    .bind(format!("0.0.0.0:{}", port))?
+    .bind(bind_address)?
     .run()
     .await
 }
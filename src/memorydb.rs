@@ -0,0 +1,653 @@
+/**
+ *  an in-memory implementation of UserStore.  nothing here talks to Azure -- the users live in a map that is owned by
+ *  the process, so this is useful to run the WebApi (and the tests) offline.  the behavior is meant to match UserDb:
+ *  reset wipes everything, ids and emails must be unique, and find/delete fail if the id doesn't exist.  users are keyed by id
+ *  alone, so the partition strategy only decides what ends up in partition_key.
+ */
+use crate::error::{UserDbError, UserDbResult};
+use crate::indexing::IndexingReport;
+use crate::models::{
+    MigrationReport, PartialUser, SetupReport, SortField, SortOrder, User, UserFilter, UserPage,
+    UserPatch,
+};
+use crate::partition::PartitionStrategy;
+use crate::store::UserStore;
+use crate::throughput::{Throughput, ThroughputReport, ThroughputScope};
+use crate::transaction::{check_partition, TransactionOp};
+use async_trait::async_trait;
+use log::info;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+pub struct MemoryUserDb {
+    users: Mutex<BTreeMap<String, User>>,
+    // every write gets the next version, which is what the etag is made from
+    version: AtomicU64,
+    strategy: PartitionStrategy,
+    collection_name: String,
+    database_name: String,
+    // nothing is throttled here, this is only kept so that the throughput endpoints work the same as with cosmos
+    throughput: Mutex<Option<ThroughputReport>>,
+}
+
+impl MemoryUserDb {
+    pub fn new(database_name: &str, collection_name: &str, strategy: PartitionStrategy) -> Self {
+        Self {
+            users: Mutex::new(BTreeMap::new()),
+            version: AtomicU64::new(0),
+            strategy,
+            database_name: database_name.to_string(),
+            collection_name: collection_name.to_string(),
+            throughput: Mutex::new(None),
+        }
+    }
+
+    /**
+     *  the throughput the database and collection were set up with, like UserDb's setupdb does.  the collection's own
+     *  throughput is the one that counts
+     */
+    pub fn with_throughput(
+        self,
+        database: Option<Throughput>,
+        container: Option<Throughput>,
+    ) -> Self {
+        let report = match (database, container) {
+            (_, Some(throughput)) => Some(ThroughputReport {
+                scope: ThroughputScope::Container,
+                throughput,
+            }),
+            (Some(throughput), None) => Some(ThroughputReport {
+                scope: ThroughputScope::Database,
+                throughput,
+            }),
+            (None, None) => None,
+        };
+        *self.throughput.lock().unwrap() = report;
+        self
+    }
+
+    fn no_throughput(&self) -> UserDbError {
+        UserDbError::NotFound(format!(
+            "neither collection {} nor database {} has provisioned throughput",
+            self.collection_name, self.database_name
+        ))
+    }
+
+    /**
+     *  the map is looked through for every query, so there are no indexes to manage
+     */
+    fn no_indexing_policy(&self) -> UserDbError {
+        UserDbError::NotFound(format!(
+            "collection {} is in memory and has no indexing policy",
+            self.collection_name
+        ))
+    }
+
+    /**
+     *  etags are opaque quoted strings, the same shape that cosmos uses
+     */
+    fn next_etag(&self) -> Option<String> {
+        let version = self.version.fetch_add(1, Ordering::SeqCst) + 1;
+        Some(format!("\"{:016x}\"", version))
+    }
+
+    /*
+     *  the writes work on a map that is passed in, so that a transaction can run them on a copy
+     */
+    fn insert(&self, users: &mut BTreeMap<String, User>, mut user: User) -> UserDbResult<User> {
+        if users.contains_key(&user.id) {
+            return Err(UserDbError::user_exists(&user.id));
+        }
+        check_email_is_free(users, &user.email, &user.id)?;
+        user.partition_key = self.strategy.key_for(&user);
+        user.etag = self.next_etag();
+        users.insert(user.id.clone(), user.clone());
+        Ok(user)
+    }
+
+    fn update(
+        &self,
+        users: &mut BTreeMap<String, User>,
+        user_id: &str,
+        patch: UserPatch,
+        if_match: &Option<String>,
+    ) -> UserDbResult<User> {
+        if let Some(email) = &patch.email {
+            check_email_is_free(users, email, user_id)?;
+        }
+        match users.get_mut(user_id) {
+            Some(user) => {
+                check_etag(user, if_match)?;
+                patch.apply_to(user);
+                // a new email can mean a new partition (see UserDb::patch_user)
+                user.partition_key = self.strategy.key_for(user);
+                user.etag = self.next_etag();
+                Ok(user.clone())
+            }
+            None => Err(UserDbError::user_not_found(user_id)),
+        }
+    }
+
+    fn remove(
+        users: &mut BTreeMap<String, User>,
+        user_id: &str,
+        if_match: &Option<String>,
+    ) -> UserDbResult<()> {
+        match users.get(user_id) {
+            Some(user) => check_etag(user, if_match)?,
+            None => return Err(UserDbError::user_not_found(user_id)),
+        }
+        users.remove(user_id);
+        Ok(())
+    }
+
+    /**
+     *  one operation of a transaction.  like cosmos, a user that exists but is in another partition is not found, and
+     *  a write can't move a user out of the transaction's partition
+     */
+    fn apply(
+        &self,
+        users: &mut BTreeMap<String, User>,
+        partition_key: &str,
+        operation: TransactionOp,
+    ) -> UserDbResult<Option<User>> {
+        let user = match operation {
+            TransactionOp::Create { user } => {
+                let mut user = User::from(user);
+                user.partition_key = self.strategy.key_for(&user);
+                check_partition(partition_key, &user)?;
+                self.insert(users, user)?
+            }
+            TransactionOp::Upsert { id, user } => {
+                let mut user = user.with_id(id);
+                user.partition_key = self.strategy.key_for(&user);
+                check_partition(partition_key, &user)?;
+                match users.get(&user.id) {
+                    Some(existing) => {
+                        check_partition(partition_key, existing)?;
+                        let id = user.id.clone();
+                        let patch = UserPatch {
+                            email: Some(user.email),
+                            name: Some(user.name),
+                        };
+                        self.update(users, &id, patch, &None)?
+                    }
+                    None => self.insert(users, user)?,
+                }
+            }
+            TransactionOp::Replace { id, user, if_match } => {
+                in_partition(users, partition_key, &id)?;
+                self.update(users, &id, user.into(), &if_match)?
+            }
+            TransactionOp::Patch {
+                id,
+                patch,
+                if_match,
+            } => {
+                in_partition(users, partition_key, &id)?;
+                self.update(users, &id, patch, &if_match)?
+            }
+            TransactionOp::Delete { id, if_match } => {
+                in_partition(users, partition_key, &id)?;
+                Self::remove(users, &id, &if_match)?;
+                return Ok(None);
+            }
+        };
+        check_partition(partition_key, &user)?;
+        Ok(Some(user))
+    }
+}
+
+/**
+ *  cosmos looks for a document by id *and* partition key, so a user in another partition isn't there
+ */
+fn in_partition(
+    users: &BTreeMap<String, User>,
+    partition_key: &str,
+    user_id: &str,
+) -> UserDbResult<()> {
+    match users.get(user_id) {
+        Some(user) if user.partition_key == partition_key => Ok(()),
+        _ => Err(UserDbError::user_not_found(user_id)),
+    }
+}
+
+/**
+ *  the same rule as the unique key on /email in cosmos, except that here it always covers every user.  user_id is the
+ *  user being written, which is allowed to keep its own email
+ */
+fn check_email_is_free(
+    users: &BTreeMap<String, User>,
+    email: &str,
+    user_id: &str,
+) -> UserDbResult<()> {
+    if users
+        .values()
+        .any(|user| user.email == email && user.id != user_id)
+    {
+        return Err(UserDbError::email_taken(email));
+    }
+    Ok(())
+}
+
+/**
+ *  if the client sent an etag it has to match the stored one.  "*" matches anything
+ */
+fn check_etag(user: &User, if_match: &Option<String>) -> UserDbResult<()> {
+    match if_match {
+        Some(etag) if etag != "*" && user.etag.as_ref() != Some(etag) => {
+            Err(UserDbError::user_changed(&user.id))
+        }
+        _ => Ok(()),
+    }
+}
+
+#[async_trait]
+impl UserStore for MemoryUserDb {
+    /**
+     *  the map is the database and collection, and it always exists
+     */
+    async fn setupdb(&self) -> UserDbResult<SetupReport> {
+        Ok(SetupReport::default())
+    }
+
+    async fn reset(&self) -> UserDbResult<SetupReport> {
+        info!(
+            "Resetting in-memory database {} collection {}",
+            self.database_name, self.collection_name
+        );
+        self.users.lock().unwrap().clear();
+        Ok(SetupReport {
+            database_created: true,
+            collection_created: true,
+        })
+    }
+
+    /**
+     *  the users are sorted by the field the filter asks for (id if it doesn't) and then by id, so that users with the
+     *  same name still have a fixed order.  the continuation token is the sort value and id of the last user on the
+     *  previous page, as JSON, and the next page starts after it
+     */
+    async fn list(
+        &self,
+        filter: &UserFilter,
+        page_size: u32,
+        continuation: Option<String>,
+    ) -> UserDbResult<UserPage> {
+        let users = self.users.lock().unwrap();
+        let sort = filter.order_by.unwrap_or(SortField::Id);
+        let key = |user: &User| (sort.value_of(user).to_string(), user.id.clone());
+
+        let mut matching: Vec<&User> = users.values().filter(|user| filter.matches(user)).collect();
+        matching.sort_by_key(|user| key(user));
+        if filter.order == SortOrder::Desc {
+            matching.reverse();
+        }
+
+        let start = match continuation {
+            Some(token) => {
+                let last: (String, String) = serde_json::from_str(&token).map_err(|_| {
+                    UserDbError::Validation(format!(
+                        "'{}' is not a valid continuation token",
+                        token
+                    ))
+                })?;
+                matching
+                    .iter()
+                    .position(|user| match filter.order {
+                        SortOrder::Asc => key(user) > last,
+                        SortOrder::Desc => key(user) < last,
+                    })
+                    .unwrap_or(matching.len())
+            }
+            None => 0,
+        };
+        let page: Vec<User> = matching[start..]
+            .iter()
+            .take(page_size as usize)
+            .map(|user| (*user).clone())
+            .collect();
+        let continuation = if start + page.len() < matching.len() {
+            page.last()
+                .map(|user| serde_json::to_string(&key(user)))
+                .transpose()?
+        } else {
+            None
+        };
+        Ok(UserPage {
+            users: page,
+            continuation,
+        })
+    }
+
+    async fn count(&self, filter: &UserFilter) -> UserDbResult<u64> {
+        let users = self.users.lock().unwrap();
+        Ok(users.values().filter(|user| filter.matches(user)).count() as u64)
+    }
+
+    async fn create_user(&self, user: User) -> UserDbResult<User> {
+        self.insert(&mut self.users.lock().unwrap(), user)
+    }
+
+    async fn upsert_user(&self, user_id: &str, user: PartialUser) -> UserDbResult<User> {
+        let mut users = self.users.lock().unwrap();
+        if users.contains_key(user_id) {
+            self.update(&mut users, user_id, user.into(), &None)
+        } else {
+            self.insert(&mut users, user.with_id(user_id.to_string()))
+        }
+    }
+
+    async fn replace_user(
+        &self,
+        user_id: &str,
+        user: PartialUser,
+        if_match: Option<String>,
+    ) -> UserDbResult<User> {
+        self.patch_user(user_id, user.into(), if_match).await
+    }
+
+    async fn patch_user(
+        &self,
+        user_id: &str,
+        patch: UserPatch,
+        if_match: Option<String>,
+    ) -> UserDbResult<User> {
+        self.update(&mut self.users.lock().unwrap(), user_id, patch, &if_match)
+    }
+
+    async fn delete_user(&self, unique_id: &str, if_match: Option<String>) -> UserDbResult<()> {
+        Self::remove(&mut self.users.lock().unwrap(), unique_id, &if_match)
+    }
+
+    async fn find_user(&self, user_id: &str) -> UserDbResult<User> {
+        match self.users.lock().unwrap().get(user_id) {
+            Some(user) => Ok(user.clone()),
+            None => Err(UserDbError::user_not_found(user_id)),
+        }
+    }
+
+    /**
+     *  the operations run on a copy of the map, which replaces the real one only if they all work.  the lock is held
+     *  the whole time, so no other write can get in between
+     */
+    async fn execute_transaction(
+        &self,
+        partition_key: &str,
+        operations: Vec<TransactionOp>,
+    ) -> UserDbResult<Vec<Option<User>>> {
+        let mut users = self.users.lock().unwrap();
+        let mut copy = users.clone();
+        let mut results = Vec::with_capacity(operations.len());
+        for (index, operation) in operations.into_iter().enumerate() {
+            let result = self
+                .apply(&mut copy, partition_key, operation)
+                .map_err(|e| e.in_operation(index))?;
+            results.push(result);
+        }
+        *users = copy;
+        Ok(results)
+    }
+
+    async fn get_throughput(&self) -> UserDbResult<ThroughputReport> {
+        (*self.throughput.lock().unwrap()).ok_or_else(|| self.no_throughput())
+    }
+
+    async fn set_throughput(&self, throughput: Throughput) -> UserDbResult<ThroughputReport> {
+        let mut current = self.throughput.lock().unwrap();
+        let changed = current
+            .ok_or_else(|| self.no_throughput())?
+            .change_to(throughput)?;
+        *current = Some(changed);
+        Ok(changed)
+    }
+
+    async fn indexing_policy(&self) -> UserDbResult<IndexingReport> {
+        Err(self.no_indexing_policy())
+    }
+
+    async fn apply_indexing_policy(&self) -> UserDbResult<IndexingReport> {
+        Err(self.no_indexing_policy())
+    }
+
+    async fn migrate_partitions(&self) -> UserDbResult<MigrationReport> {
+        let mut report = MigrationReport::default();
+        for user in self.users.lock().unwrap().values_mut() {
+            report.scanned += 1;
+            let partition_key = self.strategy.key_for(user);
+            if user.partition_key != partition_key {
+                user.partition_key = partition_key;
+                user.etag = self.next_etag();
+                report.moved += 1;
+            }
+        }
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::tests::exercise_store;
+
+    #[tokio::test]
+    async fn test_e2e_memory() {
+        let _ = env_logger::builder().is_test(true).try_init();
+        let user_db = MemoryUserDb::new(
+            "user-test-db",
+            "user-test-collection",
+            PartitionStrategy::default(),
+        );
+        exercise_store(&user_db).await;
+    }
+
+    #[tokio::test]
+    async fn test_list_pages() {
+        let user_db = MemoryUserDb::new(
+            "user-test-db",
+            "user-test-collection",
+            PartitionStrategy::default(),
+        );
+        for user in crate::store::tests::create_users() {
+            user_db.create_user(user).await.unwrap();
+        }
+        let first = user_db.list(&UserFilter::default(), 3, None).await.unwrap();
+        assert_eq!(first.users.len(), 3);
+        let second = user_db
+            .list(&UserFilter::default(), 3, first.continuation)
+            .await
+            .unwrap();
+        assert_eq!(second.users.len(), 1);
+        assert!(second.continuation.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_filter_and_sort() {
+        let user_db = MemoryUserDb::new(
+            "user-test-db",
+            "user-test-collection",
+            PartitionStrategy::default(),
+        );
+        for (name, email) in [
+            ("Ann", "ann@contoso.com"),
+            ("anna", "anna@fabrikam.com"),
+            ("Bob", "bob@contoso.com"),
+            ("Andy", "andy@contoso.com"),
+        ] {
+            let user = PartialUser {
+                id: None,
+                name: name.to_string(),
+                email: email.to_string(),
+            };
+            user_db.create_user(user.into()).await.unwrap();
+        }
+
+        // a page of one at a time, so every page after the first starts from a continuation
+        let filter = UserFilter {
+            name_prefix: Some("AN".to_string()),
+            order_by: Some(SortField::Name),
+            order: SortOrder::Desc,
+            ..UserFilter::default()
+        };
+        let mut names = Vec::new();
+        let mut continuation = None;
+        loop {
+            let page = user_db.list(&filter, 1, continuation).await.unwrap();
+            names.extend(page.users.into_iter().map(|user| user.name));
+            continuation = page.continuation;
+            if continuation.is_none() {
+                break;
+            }
+        }
+        assert_eq!(names, vec!["anna", "Ann", "Andy"]);
+
+        let filter = UserFilter {
+            email_domain: Some("contoso.com".to_string()),
+            order_by: Some(SortField::Email),
+            ..UserFilter::default()
+        };
+        let page = user_db.list(&filter, 10, None).await.unwrap();
+        let emails: Vec<String> = page.users.into_iter().map(|user| user.email).collect();
+        assert_eq!(
+            emails,
+            vec!["andy@contoso.com", "ann@contoso.com", "bob@contoso.com"]
+        );
+
+        let err = user_db
+            .list(&UserFilter::default(), 1, Some("not json".to_string()))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, UserDbError::Validation(_)));
+    }
+
+    #[tokio::test]
+    async fn test_transaction_is_all_or_nothing() {
+        let user_db = MemoryUserDb::new(
+            "user-test-db",
+            "user-test-collection",
+            PartitionStrategy::EmailDomain,
+        );
+        let user_db: &dyn UserStore = &user_db;
+        let ann = PartialUser {
+            id: None,
+            name: "ann".to_string(),
+            email: "ann@contoso.com".to_string(),
+        };
+        let bob = PartialUser {
+            id: None,
+            name: "bob".to_string(),
+            email: "bob@contoso.com".to_string(),
+        };
+        let results = user_db
+            .transaction("contoso.com")
+            .create(ann.clone())
+            .upsert("bob", bob.clone())
+            .commit()
+            .await
+            .unwrap();
+        let ann_id = results[0].as_ref().unwrap().id.clone();
+
+        // the second create takes ann's email, so the delete is rolled back too
+        let failed = user_db
+            .transaction("contoso.com")
+            .delete(&ann_id, None)
+            .create(PartialUser {
+                id: None,
+                name: "copy".to_string(),
+                email: "bob@contoso.com".to_string(),
+            })
+            .commit()
+            .await
+            .unwrap_err();
+        assert!(matches!(failed, UserDbError::Conflict(..)));
+        assert!(failed.message().starts_with("operation 1 failed"));
+        assert_eq!(user_db.count(&UserFilter::default()).await.unwrap(), 2);
+
+        // a transaction can't touch users in another partition, or move one there
+        let moved = UserPatch {
+            email: Some("bob@fabrikam.com".to_string()),
+            name: None,
+        };
+        let failed = user_db
+            .transaction("contoso.com")
+            .patch("bob", moved, None)
+            .commit()
+            .await;
+        assert!(matches!(failed, Err(UserDbError::Validation(..))));
+        let failed = user_db
+            .transaction("fabrikam.com")
+            .delete("bob", None)
+            .commit()
+            .await;
+        assert!(matches!(failed, Err(UserDbError::NotFound(..))));
+        assert_eq!(
+            user_db.find_user("bob").await.unwrap().email,
+            "bob@contoso.com"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_id_rejected() {
+        let user_db = MemoryUserDb::new(
+            "user-test-db",
+            "user-test-collection",
+            PartitionStrategy::default(),
+        );
+        let user = crate::store::tests::create_users().remove(0);
+        user_db.create_user(user.clone()).await.unwrap();
+        assert!(matches!(
+            user_db.create_user(user).await,
+            Err(UserDbError::Conflict(..))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_partitions_follow_the_strategy() {
+        let user_db = MemoryUserDb::new(
+            "user-test-db",
+            "user-test-collection",
+            PartitionStrategy::EmailDomain,
+        );
+        let mut user = crate::store::tests::create_users().remove(0);
+        user.email = "someone@Contoso.com".to_string();
+        let user = user_db.create_user(user).await.unwrap();
+        assert_eq!(user.partition_key, "contoso.com");
+
+        let patch = UserPatch {
+            email: Some("someone@fabrikam.com".to_string()),
+            name: None,
+        };
+        let user = user_db.patch_user(&user.id, patch, None).await.unwrap();
+        assert_eq!(user.partition_key, "fabrikam.com");
+
+        // documents from an older strategy get moved, and a second run finds nothing to do
+        user_db
+            .users
+            .lock()
+            .unwrap()
+            .get_mut(&user.id)
+            .unwrap()
+            .partition_key = "1".to_string();
+        let report = user_db.migrate_partitions().await.unwrap();
+        assert_eq!(
+            report,
+            MigrationReport {
+                scanned: 1,
+                moved: 1
+            }
+        );
+        let report = user_db.migrate_partitions().await.unwrap();
+        assert_eq!(
+            report,
+            MigrationReport {
+                scanned: 1,
+                moved: 0
+            }
+        );
+        assert_eq!(
+            user_db.find_user(&user.id).await.unwrap().partition_key,
+            "fabrikam.com"
+        );
+    }
+}
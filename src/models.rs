@@ -1,18 +1,25 @@
+use crate::error::{FieldError, UserDbError};
 use crate::utility::get_id;
+use crate::validation::{
+    no_control_characters, normalize_email, normalize_text, valid_id, Normalize,
+};
 /**
  * this is the module where I define the structures needed for the data in Cosmos
  */
 use azure_data_cosmos::CosmosEntity;
-use serde::{Deserialize, Serialize};
+use serde::de::Error;
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::Value;
+use validator::Validate;
 
 /**
  *  Every CosmosDb document needs to define the partition_key.  In Rust we do this via this trait.
  */
 impl CosmosEntity for User {
-    type Entity = u64;
+    type Entity = String;
 
     fn partition_key(&self) -> Self::Entity {
-        self.partition_key
+        self.partition_key.clone()
     }
 }
 
@@ -20,16 +27,40 @@ impl CosmosEntity for User {
  * this is the document stored in cosmosdb.  the "id" field and the "partition_key" field are "special" in that the
  * system needs them. if id is not specified, cosmosdb will create a guild for the id (and create an 'id' field), You
  * can partition on any value, but it should be something that works well with the partion scheme that cosmos uses.
- * for this sample, we assume the db size is small, so we just partion on a number that the sample always sets to 1
+ * the store sets partition_key from its PartitionStrategy (see partition.rs) when the user is created.  documents
+ * written by older versions of this sample have the number 1 there, which we still read (as "1") so that they show up
+ * in lists until they are migrated.
  *
+ * cosmos also adds system fields to every document.  we keep "_etag", which changes every time the document is
+ * written, so that clients can send it back in an If-Match header and not overwrite somebody else's change.
  */
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct User {
     pub id: String,
-    pub partition_key: u64,
+    #[serde(deserialize_with = "deserialize_partition_key")]
+    pub partition_key: String,
     pub email: String,
     pub name: String,
+    #[serde(rename = "_etag", default, skip_serializing_if = "Option::is_none")]
+    pub etag: Option<String>,
+}
+
+/**
+ *  the partition key is a string now, but it used to be the number 1
+ */
+fn deserialize_partition_key<'de, D>(deserializer: D) -> Result<String, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match Value::deserialize(deserializer)? {
+        Value::String(key) => Ok(key),
+        Value::Number(key) => Ok(key.to_string()),
+        other => Err(D::Error::custom(format!(
+            "partition_key should be a string, not {}",
+            other
+        ))),
+    }
 }
 
 /**
@@ -38,39 +69,293 @@ pub struct User {
  *  form data because it doesn't make the URL longer, it doesn't require sharing a structure with the client, and it
  *  scales as more profile information is added (simply add more name/value pairs to the form).   actix_web will deserialize
  *  the form data to a structure, which I called PartialUser because it contains the data that the client can create,
- *  in particular it does not have the partition_key or the id
+ *  in particular it does not have the partition_key.  it can have the id: a client that picks its own id on create can
+ *  safely retry the POST, since a second create with the same id is a conflict instead of a second user.  on a PUT the
+ *  id is in the url, and an id in the body has to match it.
+ *
+ *  the #[validate] rules are checked (after normalize) by UserBody before anything is written -- see validation.rs.
+ *  254 is the longest email address SMTP can deliver to.
  */
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize, Validate)]
 pub struct PartialUser {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[validate(custom = "valid_id")]
+    pub id: Option<String>,
+    #[validate(
+        length(max = 254, message = "must be at most 254 characters"),
+        email(message = "is not a valid email address")
+    )]
     pub email: String,
+    #[validate(
+        length(min = 1, max = 100, message = "must be 1 to 100 characters"),
+        custom = "no_control_characters"
+    )]
     pub name: String,
 }
 
+impl Normalize for PartialUser {
+    fn normalize(&mut self) {
+        self.email = normalize_email(&self.email);
+        self.name = normalize_text(&self.name);
+    }
+}
+
 /**
  *  this trait makes it easy to write code to convert from a PartialUser to a User
  */
-
 impl From<PartialUser> for User {
-    fn from(client_player: PartialUser) -> Self {
-        // You will generate the player_id and number here
-        let id = get_id();
-        let partition_key = 1;
+    fn from(mut client_player: PartialUser) -> Self {
+        // You will generate the player_id here, unless the client picked one.  the store fills in the partition_key
+        let id = client_player.id.take().unwrap_or_else(get_id);
+        client_player.with_id(id)
+    }
+}
 
+impl PartialUser {
+    /**
+     *  the user with this id, for when the id isn't a new one (e.g. an upsert).  an id in the body is ignored
+     */
+    pub fn with_id(self, id: String) -> User {
         User {
             id,
-            partition_key,
-            email: client_player.email,
-            name: client_player.name,
+            partition_key: String::new(),
+            email: self.email,
+            name: self.name,
+            etag: None,
+        }
+    }
+}
+
+/**
+ *  the body of a PATCH to /users/{id}.  any field that is left out keeps its current value.  a PUT uses a PartialUser
+ *  instead, which is turned into a UserPatch with every field set.  the rules are the same as PartialUser's, but only
+ *  for the fields that are set
+ */
+#[derive(Debug, Clone, Deserialize, Serialize, Default, Validate)]
+pub struct UserPatch {
+    #[validate(
+        length(max = 254, message = "must be at most 254 characters"),
+        email(message = "is not a valid email address")
+    )]
+    pub email: Option<String>,
+    #[validate(
+        length(min = 1, max = 100, message = "must be 1 to 100 characters"),
+        custom = "no_control_characters"
+    )]
+    pub name: Option<String>,
+}
+
+impl Normalize for UserPatch {
+    fn normalize(&mut self) {
+        self.email = self.email.as_deref().map(normalize_email);
+        self.name = self.name.as_deref().map(normalize_text);
+    }
+}
+
+impl UserPatch {
+    /**
+     *  copy the fields that were set onto the user.  the id and partition_key never change
+     */
+    pub fn apply_to(self, user: &mut User) {
+        if let Some(email) = self.email {
+            user.email = email;
+        }
+        if let Some(name) = self.name {
+            user.name = name;
+        }
+    }
+}
+
+impl From<PartialUser> for UserPatch {
+    fn from(user: PartialUser) -> Self {
+        UserPatch {
+            email: Some(user.email),
+            name: Some(user.name),
+        }
+    }
+}
+
+/**
+ *  what GET /users can filter and sort on.  every field is optional and the filters that are set are ANDed.  the
+ *  fields a client can sort on are an enum so that only the ones we list here can ever end up in a query.
+ */
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UserFilter {
+    // names that start with this, ignoring case
+    pub name_prefix: Option<String>,
+    // this exact email
+    pub email: Option<String>,
+    // emails at this domain, e.g. example.com
+    pub email_domain: Option<String>,
+    pub order_by: Option<SortField>,
+    pub order: SortOrder,
+}
+
+impl UserFilter {
+    /**
+     *  the values are compared with stored users, so they get the same normalization the users did
+     */
+    pub fn normalized(self) -> Self {
+        UserFilter {
+            name_prefix: self.name_prefix.as_deref().map(normalize_text),
+            email: self.email.as_deref().map(normalize_email),
+            email_domain: self.email_domain.as_deref().map(|domain| {
+                normalize_text(domain)
+                    .trim_start_matches('@')
+                    .to_lowercase()
+            }),
+            ..self
         }
     }
+
+    /**
+     *  does this user pass every filter that is set?  this is what the in-memory store uses, and it has to agree with
+     *  the SQL UserDb sends
+     */
+    pub fn matches(&self, user: &User) -> bool {
+        let name_ok = self
+            .name_prefix
+            .as_ref()
+            .is_none_or(|prefix| user.name.to_lowercase().starts_with(&prefix.to_lowercase()));
+        let email_ok = self.email.as_ref().is_none_or(|email| user.email == *email);
+        let domain_ok = self
+            .email_domain
+            .as_ref()
+            .is_none_or(|domain| user.email.ends_with(&format!("@{}", domain)));
+        name_ok && email_ok && domain_ok
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SortField {
+    Id,
+    Name,
+    Email,
+}
+
+impl SortField {
+    /**
+     *  the document field to sort on
+     */
+    pub fn field(&self) -> &'static str {
+        match self {
+            SortField::Id => "id",
+            SortField::Name => "name",
+            SortField::Email => "email",
+        }
+    }
+
+    pub fn value_of<'a>(&self, user: &'a User) -> &'a str {
+        match self {
+            SortField::Id => &user.id,
+            SortField::Name => &user.name,
+            SortField::Email => &user.email,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+/**
+ *  one page of users from a list call.  continuation is None on the last page, otherwise pass it back to list to get
+ *  the next page.  for CosmosDb this is the continuation token that cosmos hands back, which is opaque to the client.
+ */
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct UserPage {
+    pub users: Vec<User>,
+    pub continuation: Option<String>,
+}
+
+/**
+ *  what happened to one item of a batch create or delete.  index is its position in the request, status is the HTTP
+ *  status it would have got on its own (201, 409, 422, ...), and a failed item has the same message and errors as the
+ *  matching single user call
+ */
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct BatchItemResult {
+    pub index: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    pub status: u16,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<User>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<FieldError>,
+}
+
+impl BatchItemResult {
+    pub fn succeeded(index: usize, id: String, status: u16, user: Option<User>) -> Self {
+        Self {
+            index,
+            id: Some(id),
+            status,
+            user,
+            message: None,
+            errors: Vec::new(),
+        }
+    }
+
+    pub fn failed(index: usize, id: Option<String>, error: &UserDbError) -> Self {
+        Self {
+            index,
+            id,
+            status: error.status() as u16,
+            user: None,
+            message: Some(error.message().to_string()),
+            errors: match error {
+                UserDbError::InvalidFields(errors) => errors.clone(),
+                _ => Vec::new(),
+            },
+        }
+    }
+
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+}
+
+/**
+ *  the body of a batch response: how many items worked and what happened to each one, in request order
+ */
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct BatchReport {
+    pub succeeded: usize,
+    pub failed: usize,
+    pub items: Vec<BatchItemResult>,
+}
+
+/**
+ *  the body of GET /users/count
+ */
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+pub struct UserCount {
+    pub count: u64,
+}
+
+/**
+ *  what UserStore::setupdb (or reset) had to create.  false means it was already there
+ */
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+pub struct SetupReport {
+    pub database_created: bool,
+    pub collection_created: bool,
 }
 
 /**
- *  the .devcontainer/required-secrets.json contains the list of secrets needed to run this application.  this stuctu
- *  holds them so that they are more convinient to use
+ *  what UserStore::migrate_partitions did.  scanned is every document it looked at, moved is the ones that were in
+ *  the wrong partition for the current strategy and have been copied to the right one
  */
-#[derive(Debug, Serialize, Deserialize)]
-pub struct CosmosSecrets {
-    pub token: String,
-    pub account: String,
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+pub struct MigrationReport {
+    pub scanned: u64,
+    pub moved: u64,
 }
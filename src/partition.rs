@@ -0,0 +1,174 @@
+/**
+ *  how users are spread over cosmos logical partitions.  a logical partition tops out at 20 GB and at the throughput
+ *  of one physical partition, so putting every user in partition_key 1 (what this sample used to do) stops scaling
+ *  long before the container does.  the `partition_strategy` setting picks one of:
+ *
+ *      single          everything in partition "1".  only for tiny databases
+ *      id-hash:<n>     a stable hash of the id, in n buckets (id-hash alone is id-hash:64).  spreads writes evenly and
+ *                      the key can be worked out from the id, so reads and deletes by id stay point operations
+ *      email-domain    the lower cased domain of the email.  keeps a company's users together, which is good for
+ *                      B2B tenants and bad for gmail.com -- a big domain is a hot partition.  the key can't be worked
+ *                      out from the id, so a read by id is a query across partitions
+ *
+ *  the strategy is used by every store for create, find, replace, patch, delete and list.  documents that were written
+ *  under a different strategy (including the old numeric partition_key 1) are moved by UserStore::migrate_partitions.
+ */
+use crate::models::User;
+
+pub const DEFAULT_ID_HASH_BUCKETS: u32 = 64;
+pub const MAX_ID_HASH_BUCKETS: u32 = 10_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartitionStrategy {
+    Single,
+    IdHash { buckets: u32 },
+    EmailDomain,
+}
+
+impl Default for PartitionStrategy {
+    fn default() -> Self {
+        PartitionStrategy::IdHash {
+            buckets: DEFAULT_ID_HASH_BUCKETS,
+        }
+    }
+}
+
+impl PartitionStrategy {
+    /**
+     *  the names used by the `partition_strategy` setting
+     */
+    pub fn from_name(name: &str) -> Option<Self> {
+        let name = name.trim().to_lowercase();
+        match name.split_once(':') {
+            Some(("id-hash", buckets)) => match buckets.trim().parse::<u32>() {
+                Ok(buckets) if (1..=MAX_ID_HASH_BUCKETS).contains(&buckets) => {
+                    Some(PartitionStrategy::IdHash { buckets })
+                }
+                _ => None,
+            },
+            Some(..) => None,
+            None => match name.as_str() {
+                "single" => Some(PartitionStrategy::Single),
+                "id-hash" => Some(PartitionStrategy::default()),
+                "email-domain" => Some(PartitionStrategy::EmailDomain),
+                _ => None,
+            },
+        }
+    }
+
+    pub fn name(&self) -> String {
+        match self {
+            PartitionStrategy::Single => "single".to_string(),
+            PartitionStrategy::IdHash { buckets } => format!("id-hash:{}", buckets),
+            PartitionStrategy::EmailDomain => "email-domain".to_string(),
+        }
+    }
+
+    /**
+     *  the partition key this user belongs in
+     */
+    pub fn key_for(&self, user: &User) -> String {
+        match self {
+            PartitionStrategy::EmailDomain => email_domain(&user.email),
+            _ => self
+                .key_for_id(&user.id)
+                .expect("single and id-hash only need the id"),
+        }
+    }
+
+    /**
+     *  cosmos enforces unique keys (like the one on /email) within a logical partition.  they are only unique across the
+     *  whole container when every document with the same value is in the same partition
+     */
+    pub fn unique_keys_are_global(&self) -> bool {
+        match self {
+            PartitionStrategy::Single | PartitionStrategy::EmailDomain => true,
+            PartitionStrategy::IdHash { .. } => false,
+        }
+    }
+
+    /**
+     *  the partition key for a user id, if the strategy can work it out from the id alone.  None means the caller has to
+     *  look the user up to find its partition
+     */
+    pub fn key_for_id(&self, user_id: &str) -> Option<String> {
+        match self {
+            PartitionStrategy::Single => Some("1".to_string()),
+            PartitionStrategy::IdHash { buckets } => {
+                Some((fnv1a(user_id.as_bytes()) % *buckets as u64).to_string())
+            }
+            PartitionStrategy::EmailDomain => None,
+        }
+    }
+}
+
+/**
+ *  FNV-1a.  the bucket is stored in the document, so the hash has to give the same answer in every process and every
+ *  build -- which std's DefaultHasher doesn't promise
+ */
+fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    hash
+}
+
+fn email_domain(email: &str) -> String {
+    match email.trim().rsplit_once('@') {
+        Some((_, domain)) if !domain.is_empty() => domain.to_lowercase(),
+        _ => "no-domain".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(id: &str, email: &str) -> User {
+        User {
+            id: id.to_string(),
+            partition_key: String::new(),
+            email: email.to_string(),
+            name: "name".to_string(),
+            etag: None,
+        }
+    }
+
+    #[test]
+    fn test_names() {
+        assert_eq!(
+            PartitionStrategy::from_name("id-hash"),
+            Some(PartitionStrategy::IdHash { buckets: 64 })
+        );
+        assert_eq!(
+            PartitionStrategy::from_name("ID-HASH:8"),
+            Some(PartitionStrategy::IdHash { buckets: 8 })
+        );
+        assert_eq!(PartitionStrategy::from_name("id-hash:0"), None);
+        assert_eq!(PartitionStrategy::from_name("single:2"), None);
+        assert_eq!(
+            PartitionStrategy::from_name("email-domain"),
+            Some(PartitionStrategy::EmailDomain)
+        );
+    }
+
+    #[test]
+    fn test_keys() {
+        let strategy = PartitionStrategy::IdHash { buckets: 16 };
+        let key = strategy.key_for(&user("8f1b2c", "a@example.com"));
+        // the same id always hashes to the same bucket, and the bucket is in range
+        assert_eq!(Some(key.clone()), strategy.key_for_id("8f1b2c"));
+        assert!(key.parse::<u32>().unwrap() < 16);
+        assert_eq!(fnv1a(b"a"), 0xaf63_dc4c_8601_ec8c);
+
+        let strategy = PartitionStrategy::EmailDomain;
+        assert_eq!(
+            strategy.key_for(&user("1", "Bob@Contoso.COM")),
+            "contoso.com"
+        );
+        assert_eq!(strategy.key_for(&user("1", "bob")), "no-domain");
+        assert_eq!(strategy.key_for_id("1"), None);
+    }
+}
@@ -0,0 +1,165 @@
+/**
+ *  a small builder for Cosmos SQL.  values never get formatted into the SQL text -- each one becomes a named parameter
+ *  (@p0, @p1, ...) and is sent next to the query with Query::with_params, so a value like "x' OR 1=1 --" is just a
+ *  string that doesn't match anything.  field names are &'static str so that they can only come from our code -- what
+ *  a client asks to filter or sort on is mapped to one of them first (see SortField).
+ *
+ *      let query = QueryBuilder::select_all()
+ *          .where_eq("partition_key", "7")
+ *          .where_starts_with("name", "jo", true)
+ *          .order_by("name", false)
+ *          .build();
+ */
+use azure_data_cosmos::prelude::{Param, Query};
+use serde_json::Value;
+
+#[derive(Debug, Clone)]
+pub struct QueryBuilder {
+    select: &'static str,
+    conditions: Vec<String>,
+    params: Vec<(String, Value)>,
+    order_by: Option<String>,
+}
+
+impl QueryBuilder {
+    /**
+     *  SELECT * FROM c
+     */
+    pub fn select_all() -> Self {
+        Self {
+            select: "SELECT * FROM c",
+            conditions: Vec::new(),
+            params: Vec::new(),
+            order_by: None,
+        }
+    }
+
+    /**
+     *  SELECT VALUE COUNT(1) FROM c.  the results are plain numbers, not documents -- and a cross partition count can
+     *  come back as one partial count per partition range, so the caller has to add them all up
+     */
+    pub fn select_count() -> Self {
+        Self {
+            select: "SELECT VALUE COUNT(1) FROM c",
+            ..Self::select_all()
+        }
+    }
+
+    /**
+     *  adds "c.<field> = @pN".  conditions are ANDed together
+     */
+    pub fn where_eq(mut self, field: &'static str, value: impl Into<Value>) -> Self {
+        let name = self.add_param(value.into());
+        self.conditions.push(format!("c.{} = {}", field, name));
+        self
+    }
+
+    /**
+     *  adds "STARTSWITH(c.<field>, @pN)", or "STARTSWITH(c.<field>, @pN, true)" to ignore case
+     */
+    pub fn where_starts_with(
+        mut self,
+        field: &'static str,
+        prefix: &str,
+        ignore_case: bool,
+    ) -> Self {
+        let name = self.add_param(Value::from(prefix));
+        let ignore_case = if ignore_case { ", true" } else { "" };
+        self.conditions
+            .push(format!("STARTSWITH(c.{}, {}{})", field, name, ignore_case));
+        self
+    }
+
+    /**
+     *  adds "ENDSWITH(c.<field>, @pN)"
+     */
+    pub fn where_ends_with(mut self, field: &'static str, suffix: &str) -> Self {
+        let name = self.add_param(Value::from(suffix));
+        self.conditions
+            .push(format!("ENDSWITH(c.{}, {})", field, name));
+        self
+    }
+
+    /**
+     *  sort by one field.  cosmos can only sort on a field with a range index: the policy in indexing.rs has one for
+     *  each SortField.  sorting by more than one field needs a composite index for exactly those fields
+     */
+    pub fn order_by(mut self, field: &'static str, descending: bool) -> Self {
+        let direction = if descending { "DESC" } else { "ASC" };
+        self.order_by = Some(format!("c.{} {}", field, direction));
+        self
+    }
+
+    fn add_param(&mut self, value: Value) -> String {
+        let name = format!("@p{}", self.params.len());
+        self.params.push((name.clone(), value));
+        name
+    }
+
+    /**
+     *  the SQL text, with parameter names where the values go
+     */
+    pub fn text(&self) -> String {
+        let mut text = self.select.to_string();
+        if !self.conditions.is_empty() {
+            text = format!("{} WHERE {}", text, self.conditions.join(" AND "));
+        }
+        if let Some(order_by) = &self.order_by {
+            text = format!("{} ORDER BY {}", text, order_by);
+        }
+        text
+    }
+
+    pub fn build(self) -> Query {
+        let text = self.text();
+        let params = self
+            .params
+            .into_iter()
+            .map(|(name, value)| Param::new(name, value))
+            .collect();
+        Query::with_params(text, params)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_values_become_parameters() {
+        let builder = QueryBuilder::select_all()
+            .where_eq("partition_key", 1)
+            .where_eq("id", "x' OR 1=1 --");
+        assert_eq!(
+            builder.text(),
+            "SELECT * FROM c WHERE c.partition_key = @p0 AND c.id = @p1"
+        );
+        assert_eq!(builder.params[1].1, Value::from("x' OR 1=1 --"));
+    }
+
+    #[test]
+    fn test_functions_and_order() {
+        let builder = QueryBuilder::select_all()
+            .where_starts_with("name", "Jo", true)
+            .where_ends_with("email", "@example.com")
+            .order_by("email", true);
+        assert_eq!(
+            builder.text(),
+            "SELECT * FROM c WHERE STARTSWITH(c.name, @p0, true) AND ENDSWITH(c.email, @p1) ORDER BY c.email DESC"
+        );
+    }
+
+    #[test]
+    fn test_count() {
+        let builder = QueryBuilder::select_count().where_eq("email", "a@example.com");
+        assert_eq!(
+            builder.text(),
+            "SELECT VALUE COUNT(1) FROM c WHERE c.email = @p0"
+        );
+    }
+
+    #[test]
+    fn test_no_conditions() {
+        assert_eq!(QueryBuilder::select_all().text(), "SELECT * FROM c");
+    }
+}
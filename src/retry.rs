@@ -0,0 +1,561 @@
+/**
+ *  retries for the errors that go away by themselves.  RetryingStore wraps the store that create_store builds, so
+ *  every operation the handlers call goes through it:
+ *
+ *      - a 429 (UserDbError::Throttled) means cosmos didn't run the request, so any operation is retried.  the wait is
+ *        what cosmos asked for in x-ms-retry-after-ms, or the backoff below if it didn't say
+ *      - a 503, a timeout or a dropped connection (UserDbError::Unavailable) may have happened after the write was
+ *        done, so only the operations that are safe to run twice are retried: reads, upserts, setup, setting the
+ *        throughput or the indexing policy, and the migration
+ *      - anything else is returned right away
+ *
+ *  the backoff doubles from base_delay up to max_delay, and each wait is a random time between half of that and all of
+ *  it, so clients that were throttled together don't all come back at the same moment.  an operation gives up after
+ *  max_attempts tries, or when the next wait would take it past its budget, and returns the last error.  the `retry`
+ *  setting changes the policy (see RetryPolicy::from_spec), and RetryMetrics counts what happened for
+ *  GET /metrics/retries.
+ */
+use crate::error::{UserDbError, UserDbResult};
+use crate::indexing::IndexingReport;
+use crate::models::{
+    MigrationReport, PartialUser, SetupReport, User, UserFilter, UserPage, UserPatch,
+};
+use crate::store::UserStore;
+use crate::throughput::{Throughput, ThroughputReport};
+use crate::transaction::TransactionOp;
+use async_trait::async_trait;
+use log::warn;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+pub const MAX_RETRY_ATTEMPTS: u32 = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    // tries in all, including the first.  1 turns retries off
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    // the longest an operation keeps retrying, counted from its first try
+    pub budget: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 5,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(5),
+            budget: Duration::from_secs(30),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /**
+     *  the `retry` setting: "off", or any of attempts=<n>,base_delay_ms=<ms>,max_delay_ms=<ms>,budget_ms=<ms>
+     *  separated by commas, e.g. "attempts=3,budget_ms=5000".  what isn't given keeps its default
+     */
+    pub fn from_spec(spec: &str) -> Option<Self> {
+        let spec = spec.trim().to_lowercase();
+        let mut policy = RetryPolicy::default();
+        if spec == "off" {
+            policy.max_attempts = 1;
+            return Some(policy);
+        }
+        for part in spec.split(',') {
+            let (name, value) = part.split_once('=')?;
+            let value: u64 = value.trim().parse().ok()?;
+            match name.trim() {
+                "attempts" => policy.max_attempts = u32::try_from(value).ok()?,
+                "base_delay_ms" => policy.base_delay = Duration::from_millis(value),
+                "max_delay_ms" => policy.max_delay = Duration::from_millis(value),
+                "budget_ms" => policy.budget = Duration::from_millis(value),
+                _ => return None,
+            }
+        }
+        let valid = (1..=MAX_RETRY_ATTEMPTS).contains(&policy.max_attempts)
+            && !policy.base_delay.is_zero()
+            && policy.max_delay >= policy.base_delay;
+        valid.then_some(policy)
+    }
+
+    /**
+     *  how long to wait before try number attempt + 1, when cosmos didn't say
+     */
+    fn backoff(&self, attempt: u32) -> Duration {
+        let doubled = self
+            .base_delay
+            .saturating_mul(1 << (attempt - 1).min(16))
+            .min(self.max_delay);
+        let half = doubled.as_millis() as u64 / 2;
+        Duration::from_millis(half + rand::thread_rng().gen_range(0..=half))
+    }
+}
+
+/**
+ *  counters for every RetryingStore operation since the process started
+ */
+#[derive(Debug, Default)]
+pub struct RetryMetrics {
+    retries: AtomicU64,
+    throttled: AtomicU64,
+    unavailable: AtomicU64,
+    gave_up: AtomicU64,
+    waited_ms: AtomicU64,
+}
+
+/**
+ *  the body of GET /metrics/retries.  throttled and unavailable count the errors that were retried, gave_up counts
+ *  the operations that still failed after their last try, and waited_ms is the total time spent waiting
+ */
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RetryMetricsSnapshot {
+    pub retries: u64,
+    pub throttled: u64,
+    pub unavailable: u64,
+    pub gave_up: u64,
+    pub waited_ms: u64,
+}
+
+impl RetryMetrics {
+    pub fn snapshot(&self) -> RetryMetricsSnapshot {
+        RetryMetricsSnapshot {
+            retries: self.retries.load(Ordering::Relaxed),
+            throttled: self.throttled.load(Ordering::Relaxed),
+            unavailable: self.unavailable.load(Ordering::Relaxed),
+            gave_up: self.gave_up.load(Ordering::Relaxed),
+            waited_ms: self.waited_ms.load(Ordering::Relaxed),
+        }
+    }
+
+    fn record_retry(&self, error: &UserDbError, delay: Duration) {
+        self.retries.fetch_add(1, Ordering::Relaxed);
+        let counter = match error {
+            UserDbError::Throttled { .. } => &self.throttled,
+            _ => &self.unavailable,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        self.waited_ms
+            .fetch_add(delay.as_millis() as u64, Ordering::Relaxed);
+    }
+}
+
+pub struct RetryingStore {
+    inner: Arc<dyn UserStore>,
+    policy: RetryPolicy,
+    metrics: Arc<RetryMetrics>,
+}
+
+impl RetryingStore {
+    pub fn new(inner: Arc<dyn UserStore>, policy: RetryPolicy, metrics: Arc<RetryMetrics>) -> Self {
+        Self {
+            inner,
+            policy,
+            metrics,
+        }
+    }
+
+    /**
+     *  how long to wait before trying again after error, or None to give up and return it
+     */
+    fn delay_before_retry(
+        &self,
+        error: &UserDbError,
+        idempotent: bool,
+        attempt: u32,
+        started: Instant,
+    ) -> Option<Duration> {
+        let retry_after = match error {
+            UserDbError::Throttled { retry_after, .. } => *retry_after,
+            UserDbError::Unavailable(..) if idempotent => None,
+            _ => return None,
+        };
+        let delay = retry_after.unwrap_or_else(|| self.policy.backoff(attempt));
+        if attempt >= self.policy.max_attempts || started.elapsed() + delay > self.policy.budget {
+            self.metrics.gave_up.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+        self.metrics.record_retry(error, delay);
+        Some(delay)
+    }
+
+    async fn run<T, F, Fut>(
+        &self,
+        operation: &str,
+        idempotent: bool,
+        mut call: F,
+    ) -> UserDbResult<T>
+    where
+        F: FnMut() -> Fut + Send,
+        Fut: Future<Output = UserDbResult<T>> + Send,
+        T: Send,
+    {
+        let started = Instant::now();
+        let mut attempt = 1;
+        loop {
+            let error = match call().await {
+                Ok(result) => return Ok(result),
+                Err(error) => error,
+            };
+            let delay = match self.delay_before_retry(&error, idempotent, attempt, started) {
+                Some(delay) => delay,
+                None => return Err(error),
+            };
+            warn!(
+                "{} failed on try {} of {}, trying again in {:?}: {}",
+                operation, attempt, self.policy.max_attempts, delay, error
+            );
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+
+    /**
+     *  the same as run, for the batch operations that have a result per item: only the items that were throttled are
+     *  sent again, and the wait is the longest that any of them asked for.  in a transactional batch a throttled
+     *  operation fails the rest with FailedDependency without running them, so when anything was throttled those go
+     *  again too
+     */
+    async fn run_each<I, T, F, Fut>(
+        &self,
+        operation: &str,
+        items: Vec<I>,
+        mut call: F,
+    ) -> Vec<UserDbResult<T>>
+    where
+        I: Clone + Send + Sync,
+        F: FnMut(Vec<I>) -> Fut + Send,
+        Fut: Future<Output = Vec<UserDbResult<T>>> + Send,
+        T: Send,
+    {
+        let started = Instant::now();
+        let mut results: Vec<Option<UserDbResult<T>>> = items.iter().map(|_| None).collect();
+        let mut pending: Vec<usize> = (0..items.len()).collect();
+        let mut attempt = 1;
+        while !pending.is_empty() {
+            let outcomes = call(pending.iter().map(|index| items[*index].clone()).collect()).await;
+            let waits: Vec<Option<Duration>> = outcomes
+                .iter()
+                .map(|outcome| match outcome {
+                    Err(error) => self.delay_before_retry(error, false, attempt, started),
+                    Ok(..) => None,
+                })
+                .collect();
+            let throttled = waits.iter().any(Option::is_some);
+            let mut retry = Vec::new();
+            let mut delay = Duration::ZERO;
+            for ((index, outcome), wait) in pending.into_iter().zip(outcomes).zip(waits) {
+                let dependent =
+                    throttled && matches!(outcome, Err(UserDbError::FailedDependency(..)));
+                if wait.is_some() || dependent {
+                    delay = delay.max(wait.unwrap_or_default());
+                    retry.push(index);
+                    continue;
+                }
+                results[index] = Some(outcome);
+            }
+            if !retry.is_empty() {
+                warn!(
+                    "{} items of {} failed on try {}, trying them again in {:?}",
+                    retry.len(),
+                    operation,
+                    attempt,
+                    delay
+                );
+                tokio::time::sleep(delay).await;
+            }
+            pending = retry;
+            attempt += 1;
+        }
+        results
+            .into_iter()
+            .map(|result| result.expect("every item gets a result"))
+            .collect()
+    }
+}
+
+#[async_trait]
+impl UserStore for RetryingStore {
+    async fn setupdb(&self) -> UserDbResult<SetupReport> {
+        self.run("setupdb", true, || self.inner.setupdb()).await
+    }
+
+    async fn reset(&self) -> UserDbResult<SetupReport> {
+        self.run("reset", false, || self.inner.reset()).await
+    }
+
+    async fn list(
+        &self,
+        filter: &UserFilter,
+        page_size: u32,
+        continuation: Option<String>,
+    ) -> UserDbResult<UserPage> {
+        self.run("list", true, || {
+            self.inner.list(filter, page_size, continuation.clone())
+        })
+        .await
+    }
+
+    async fn count(&self, filter: &UserFilter) -> UserDbResult<u64> {
+        self.run("count", true, || self.inner.count(filter)).await
+    }
+
+    async fn create_user(&self, user: User) -> UserDbResult<User> {
+        self.run("create_user", false, || {
+            self.inner.create_user(user.clone())
+        })
+        .await
+    }
+
+    async fn upsert_user(&self, user_id: &str, user: PartialUser) -> UserDbResult<User> {
+        self.run("upsert_user", true, || {
+            self.inner.upsert_user(user_id, user.clone())
+        })
+        .await
+    }
+
+    async fn replace_user(
+        &self,
+        user_id: &str,
+        user: PartialUser,
+        if_match: Option<String>,
+    ) -> UserDbResult<User> {
+        self.run("replace_user", false, || {
+            self.inner
+                .replace_user(user_id, user.clone(), if_match.clone())
+        })
+        .await
+    }
+
+    async fn patch_user(
+        &self,
+        user_id: &str,
+        patch: UserPatch,
+        if_match: Option<String>,
+    ) -> UserDbResult<User> {
+        self.run("patch_user", false, || {
+            self.inner
+                .patch_user(user_id, patch.clone(), if_match.clone())
+        })
+        .await
+    }
+
+    async fn delete_user(&self, unique_id: &str, if_match: Option<String>) -> UserDbResult<()> {
+        self.run("delete_user", false, || {
+            self.inner.delete_user(unique_id, if_match.clone())
+        })
+        .await
+    }
+
+    async fn find_user(&self, user_id: &str) -> UserDbResult<User> {
+        self.run("find_user", true, || self.inner.find_user(user_id))
+            .await
+    }
+
+    async fn create_users(&self, users: Vec<User>) -> Vec<UserDbResult<User>> {
+        self.run_each("create_users", users, |users| {
+            self.inner.create_users(users)
+        })
+        .await
+    }
+
+    async fn delete_users(&self, user_ids: Vec<String>) -> Vec<UserDbResult<()>> {
+        self.run_each("delete_users", user_ids, |ids| self.inner.delete_users(ids))
+            .await
+    }
+
+    async fn execute_transaction(
+        &self,
+        partition_key: &str,
+        operations: Vec<TransactionOp>,
+    ) -> UserDbResult<Vec<Option<User>>> {
+        self.run("execute_transaction", false, || {
+            self.inner
+                .execute_transaction(partition_key, operations.clone())
+        })
+        .await
+    }
+
+    async fn migrate_partitions(&self) -> UserDbResult<MigrationReport> {
+        self.run("migrate_partitions", true, || {
+            self.inner.migrate_partitions()
+        })
+        .await
+    }
+
+    async fn get_throughput(&self) -> UserDbResult<ThroughputReport> {
+        self.run("get_throughput", true, || self.inner.get_throughput())
+            .await
+    }
+
+    // setting the same throughput twice is the same as setting it once
+    async fn set_throughput(&self, throughput: Throughput) -> UserDbResult<ThroughputReport> {
+        self.run("set_throughput", true, || {
+            self.inner.set_throughput(throughput)
+        })
+        .await
+    }
+
+    async fn indexing_policy(&self) -> UserDbResult<IndexingReport> {
+        self.run("indexing_policy", true, || self.inner.indexing_policy())
+            .await
+    }
+
+    // it always ends with the same policy, however many times it runs
+    async fn apply_indexing_policy(&self) -> UserDbResult<IndexingReport> {
+        self.run("apply_indexing_policy", true, || {
+            self.inner.apply_indexing_policy()
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::faults::{FaultPlan, FaultyStore};
+    use crate::memorydb::MemoryUserDb;
+    use crate::partition::PartitionStrategy;
+    use crate::store::tests::create_users;
+
+    fn faulty_store() -> Arc<FaultyStore> {
+        Arc::new(FaultyStore::new(
+            Arc::new(MemoryUserDb::new(
+                "retry-test-db",
+                "retry-test-collection",
+                PartitionStrategy::default(),
+            )),
+            FaultPlan::default(),
+        ))
+    }
+
+    fn retrying(
+        inner: Arc<FaultyStore>,
+        policy: RetryPolicy,
+    ) -> (RetryingStore, Arc<RetryMetrics>) {
+        let metrics = Arc::new(RetryMetrics::default());
+        (RetryingStore::new(inner, policy, metrics.clone()), metrics)
+    }
+
+    #[test]
+    fn test_policy_spec() {
+        assert_eq!(RetryPolicy::from_spec("off").unwrap().max_attempts, 1);
+        let policy = RetryPolicy::from_spec("attempts=3, budget_ms=5000").unwrap();
+        assert_eq!(policy.max_attempts, 3);
+        assert_eq!(policy.budget, Duration::from_secs(5));
+        assert_eq!(policy.base_delay, RetryPolicy::default().base_delay);
+        assert!(RetryPolicy::from_spec("attempts=0").is_none());
+        assert!(RetryPolicy::from_spec("base_delay_ms=500,max_delay_ms=100").is_none());
+        assert!(RetryPolicy::from_spec("retries=3").is_none());
+
+        // the backoff doubles, with jitter in the upper half, and stops at max_delay
+        let policy = RetryPolicy::default();
+        for (attempt, full) in [(1, 100), (2, 200), (3, 400), (10, 5000)] {
+            let delay = policy.backoff(attempt).as_millis() as u64;
+            assert!((full / 2..=full).contains(&delay), "{} {}", attempt, delay);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_transient_errors_are_retried() {
+        let faults = faulty_store();
+        let (store, metrics) = retrying(faults.clone(), RetryPolicy::default());
+        let user = create_users().remove(0);
+
+        // a create is retried when it was throttled...
+        faults.fail_next(FaultyStore::throttled());
+        faults.fail_next(FaultyStore::throttled());
+        store.create_user(user.clone()).await.unwrap();
+
+        // ...but not when it may have happened already
+        faults.fail_next(FaultyStore::unavailable());
+        let err = store
+            .create_user(create_users().remove(1))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, UserDbError::Unavailable(..)));
+
+        // a read can always be retried
+        faults.fail_next(FaultyStore::unavailable());
+        assert_eq!(store.find_user(&user.id).await.unwrap().id, user.id);
+
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.retries, 3);
+        assert_eq!(snapshot.throttled, 2);
+        assert_eq!(snapshot.unavailable, 1);
+        assert_eq!(snapshot.gave_up, 0);
+        // two injected 429s ask for 10ms each, and the first backoff is 50 to 100ms
+        assert!((70..=120).contains(&snapshot.waited_ms));
+    }
+
+    #[tokio::test]
+    async fn test_retries_give_up() {
+        let faults = faulty_store();
+        let policy = RetryPolicy::from_spec("attempts=3,base_delay_ms=1,max_delay_ms=1").unwrap();
+        let (store, metrics) = retrying(faults.clone(), policy);
+        for _ in 0..3 {
+            faults.fail_next(FaultyStore::throttled());
+        }
+        let err = store.count(&UserFilter::default()).await.unwrap_err();
+        assert!(matches!(err, UserDbError::Throttled { .. }));
+        assert_eq!(metrics.snapshot().retries, 2);
+        assert_eq!(metrics.snapshot().gave_up, 1);
+
+        // the budget runs out before the wait cosmos asked for is over
+        let policy = RetryPolicy::from_spec("budget_ms=5").unwrap();
+        let (store, _) = retrying(faults.clone(), policy);
+        faults.fail_next(FaultyStore::throttled());
+        assert!(store.count(&UserFilter::default()).await.is_err());
+        assert_eq!(store.count(&UserFilter::default()).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_only_throttled_batch_items_are_retried() {
+        let faults = faulty_store();
+        let (store, metrics) = retrying(faults.clone(), RetryPolicy::default());
+        let users = create_users();
+        // the batch falls back to one create per user, so these fail the first two of them
+        faults.fail_next(FaultyStore::throttled());
+        faults.fail_next(FaultyStore::unavailable());
+        let results = store.create_users(users.clone()).await;
+        assert_eq!(results.len(), users.len());
+        let failed: Vec<usize> = (0..results.len())
+            .filter(|i| results[*i].is_err())
+            .collect();
+        assert_eq!(failed.len(), 1);
+        assert!(matches!(
+            results[failed[0]],
+            Err(UserDbError::Unavailable(..))
+        ));
+        assert_eq!(metrics.snapshot().throttled, 1);
+        assert_eq!(
+            store.count(&UserFilter::default()).await.unwrap(),
+            users.len() as u64 - 1
+        );
+    }
+
+    #[tokio::test]
+    async fn test_batch_items_that_depend_on_a_throttled_one_are_retried() {
+        let faults = faulty_store();
+        let (store, _) = retrying(faults.clone(), RetryPolicy::default());
+        let users = create_users();
+        // what a transactional batch answers when one of its operations is throttled
+        faults.fail_next(FaultyStore::throttled());
+        faults.fail_next(UserDbError::FailedDependency(
+            "not written because another operation in the same batch failed".to_string(),
+        ));
+        let results = store.create_users(users.clone()).await;
+        assert!(results.iter().all(|result| result.is_ok()));
+
+        // without anything throttled, a FailedDependency is what happened
+        faults.fail_next(UserDbError::FailedDependency("injected".to_string()));
+        let results = store.delete_users(vec![users[0].id.clone()]).await;
+        assert!(matches!(results[0], Err(UserDbError::FailedDependency(..))));
+    }
+}
@@ -0,0 +1,480 @@
+/**
+ *  this module defines the storage abstraction that the WebApi talks to.  UserDb (cosmosdb.rs) talks to a real CosmosDb
+ *  account and MemoryUserDb (memorydb.rs) keeps everything in a map in this process so that the sample (and its tests)
+ *  can run without an Azure subscription.
+ */
+use crate::config::Config;
+use crate::cosmosdb::UserDb;
+use crate::error::UserDbResult;
+use crate::faults::FaultyStore;
+use crate::indexing::IndexingReport;
+use crate::memorydb::MemoryUserDb;
+use crate::models::{
+    MigrationReport, PartialUser, SetupReport, User, UserFilter, UserPage, UserPatch,
+};
+use crate::retry::{RetryMetrics, RetryingStore};
+use crate::throughput::{Throughput, ThroughputReport};
+use crate::transaction::TransactionOp;
+use async_trait::async_trait;
+use futures::stream::{self, StreamExt};
+use std::future::Future;
+use std::sync::Arc;
+
+/**
+ *  how many single user operations a batch runs at the same time when it can't use a transactional batch
+ */
+pub const BATCH_CONCURRENCY: usize = 8;
+
+/**
+ *  the operations the WebApi needs from a backing store.  every backend has to behave the same way so that the
+ *  handlers in users.rs don't need to know which one they are talking to.
+ */
+#[async_trait]
+pub trait UserStore: Send + Sync {
+    /**
+     *  create the database and collection if they don't exist yet.  existing data is left alone, so this is safe to
+     *  run any number of times.  fails with UserDbError::Conflict if the collection exists with a different partition
+     *  key path than the configured one
+     */
+    async fn setupdb(&self) -> UserDbResult<SetupReport>;
+    /**
+     *  DELETE the database with everything in it and set it up again.  the WebApi only calls this when reset is
+     *  allowed and the request has the confirmation token (see users::reset)
+     */
+    async fn reset(&self) -> UserDbResult<SetupReport>;
+    /**
+     *  return one page of at most page_size users that pass the filter, in the order it asks for.  pass the
+     *  continuation from the previous page (with the same filter) to get the next one
+     */
+    async fn list(
+        &self,
+        filter: &UserFilter,
+        page_size: u32,
+        continuation: Option<String>,
+    ) -> UserDbResult<UserPage>;
+    /**
+     *  how many users pass the filter.  the filter's order is ignored
+     */
+    async fn count(&self, filter: &UserFilter) -> UserDbResult<u64>;
+    /**
+     *  add a new user document.  returns the user as stored, including its etag.  fails with UserDbError::Conflict if
+     *  the id or the email is already used (replace and patch can't take another user's email either)
+     */
+    async fn create_user(&self, user: User) -> UserDbResult<User>;
+    /**
+     *  create the user with this id, or replace its name and email if it already exists.  returns the user as stored.
+     *  there is no etag check: an upsert always wins
+     */
+    async fn upsert_user(&self, user_id: &str, user: PartialUser) -> UserDbResult<User>;
+    /**
+     *  replace every field the client owns on an existing user.  returns the updated user.
+     *
+     *  the writes (replace, patch and delete) take the etag the client last saw.  if it is set and the stored document
+     *  has a different etag, they fail with UserDbError::PreconditionFailed and don't change anything.
+     */
+    async fn replace_user(
+        &self,
+        user_id: &str,
+        user: PartialUser,
+        if_match: Option<String>,
+    ) -> UserDbResult<User>;
+    /**
+     *  change only the fields that are set in the patch.  returns the updated user
+     */
+    async fn patch_user(
+        &self,
+        user_id: &str,
+        patch: UserPatch,
+        if_match: Option<String>,
+    ) -> UserDbResult<User>;
+    /**
+     *  delete the user with the unique id.  fails with UserDbError::NotFound if there is no such user
+     */
+    async fn delete_user(&self, unique_id: &str, if_match: Option<String>) -> UserDbResult<()>;
+    /**
+     *  find a user by their unique id.  fails with UserDbError::NotFound if there is no such user
+     */
+    async fn find_user(&self, user_id: &str) -> UserDbResult<User>;
+    /**
+     *  create many users.  there is one result per user, in the same order, and one failing doesn't stop the others.
+     *  a store can override this to write them more cheaply -- UserDb uses a transactional batch when they all go in
+     *  the same partition
+     */
+    async fn create_users(&self, users: Vec<User>) -> Vec<UserDbResult<User>> {
+        bounded(users.into_iter().map(|user| self.create_user(user))).await
+    }
+    /**
+     *  delete many users by id, with one result per id in the same order.  like create_users, one failing doesn't stop
+     *  the others
+     */
+    async fn delete_users(&self, user_ids: Vec<String>) -> Vec<UserDbResult<()>> {
+        bounded(
+            user_ids
+                .into_iter()
+                .map(|id| async move { self.delete_user(&id, None).await }),
+        )
+        .await
+    }
+    /**
+     *  run every operation in one logical partition, or none of them.  use transaction() to build one (see
+     *  transaction.rs) -- it checks the number of operations before this is called
+     */
+    async fn execute_transaction(
+        &self,
+        partition_key: &str,
+        operations: Vec<TransactionOp>,
+    ) -> UserDbResult<Vec<Option<User>>>;
+    /**
+     *  move every user that isn't in the partition the current PartitionStrategy puts it in.  this is safe to run
+     *  more than once and to run again after it fails part way -- documents that are already in the right place are
+     *  left alone
+     */
+    async fn migrate_partitions(&self) -> UserDbResult<MigrationReport>;
+    /**
+     *  the provisioned throughput the users collection runs on: its own, or if it has none its database's shared
+     *  throughput.  NotFound if neither has any (e.g. on a serverless account)
+     */
+    async fn get_throughput(&self) -> UserDbResult<ThroughputReport>;
+    /**
+     *  change the throughput that get_throughput reports.  it can be raised or lowered, but not switched between
+     *  manual and autoscale
+     */
+    async fn set_throughput(&self, throughput: Throughput) -> UserDbResult<ThroughputReport>;
+    /**
+     *  how the users collection's indexing policy differs from the one in indexing.rs
+     */
+    async fn indexing_policy(&self) -> UserDbResult<IndexingReport>;
+    /**
+     *  give the users collection the indexing policy in indexing.rs.  nothing is sent if it already has it
+     */
+    async fn apply_indexing_policy(&self) -> UserDbResult<IndexingReport>;
+}
+
+/**
+ *  run the futures BATCH_CONCURRENCY at a time and return their results in the order the futures were given
+ */
+pub async fn bounded<T>(futures: impl IntoIterator<Item = impl Future<Output = T>>) -> Vec<T> {
+    stream::iter(futures)
+        .buffered(BATCH_CONCURRENCY)
+        .collect()
+        .await
+}
+
+/**
+ *  which UserStore implementation the service runs against.  this is the "backend" setting in Config ("cosmos" or
+ *  "memory") and defaults to cosmos.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    Cosmos,
+    Memory,
+}
+
+impl Backend {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.trim().to_lowercase().as_str() {
+            "cosmos" | "cosmosdb" => Some(Backend::Cosmos),
+            "memory" | "in-memory" | "inmemory" => Some(Backend::Memory),
+            _ => None,
+        }
+    }
+}
+
+/**
+ *  builds the store for the configured backend.  main() calls this once at startup and hands the result to every
+ *  request through actix's application data, so the CosmosClient (and its connection pool) or the in-memory map is
+ *  shared.  the store is wrapped in the retry layer (retry.rs), which counts its retries in metrics, and when
+ *  fault_injection is on, in a FaultyStore under that.  this fails if the backend can't be set up (e.g. the cosmos
+ *  secrets are missing)
+ */
+pub async fn create_store(
+    config: &Config,
+    metrics: Arc<RetryMetrics>,
+) -> UserDbResult<Arc<dyn UserStore>> {
+    let mut store: Arc<dyn UserStore> = match config.backend {
+        Backend::Cosmos => Arc::new(UserDb::try_new(config).await?),
+        Backend::Memory => Arc::new(
+            MemoryUserDb::new(
+                &config.database_name,
+                &config.collection_name,
+                config.partition_strategy,
+            )
+            .with_throughput(config.database_throughput, config.container_throughput),
+        ),
+    };
+    if !config.fault_injection.is_off() {
+        store = Arc::new(FaultyStore::new(store, config.fault_injection));
+    }
+    Ok(Arc::new(RetryingStore::new(store, config.retry, metrics)))
+}
+
+/**
+ *  shared test code: every backend has to pass the same end to end test
+ */
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::error::UserDbError;
+    use crate::utility::get_id;
+    use log::trace;
+    use rand::Rng;
+    use std::iter;
+
+    pub async fn exercise_store(user_db: &dyn UserStore) {
+        match user_db.reset().await {
+            Ok(..) => trace!("created test db and collection"),
+            Err(e) => panic!("failed to setup database and collection {}", e),
+        }
+        // setup again finds both and leaves them alone
+        assert_eq!(user_db.setupdb().await.unwrap(), SetupReport::default());
+        // create users and add them to the database
+        let users = create_users();
+        for user in users {
+            let user_clone = user.clone();
+            match user_db.create_user(user_clone).await {
+                Ok(created) => {
+                    assert!(!created.partition_key.is_empty());
+                    trace!("created user {}", user.id)
+                }
+                Err(e) => panic!("failed to create user.  err: {}", e),
+            }
+        }
+
+        // get a list of all users
+        let users: Vec<User> = match list_all(user_db).await {
+            Ok(u) => {
+                trace!("all_users returned success");
+                u
+            }
+            Err(e) => panic!("failed to setup database and collection {}", e),
+        };
+
+        // count agrees with list, with and without a filter
+        assert_eq!(
+            user_db.count(&UserFilter::default()).await.unwrap(),
+            users.len() as u64
+        );
+        let just_one = UserFilter {
+            email: Some(users[0].email.clone()),
+            ..UserFilter::default()
+        };
+        assert_eq!(user_db.count(&just_one).await.unwrap(), 1);
+
+        // emails are unique, for new users and for updates
+        let mut duplicate = create_users().remove(0);
+        duplicate.email = users[0].email.clone();
+        match user_db.create_user(duplicate).await {
+            Err(UserDbError::Conflict(message)) => assert!(message.contains("email")),
+            other => panic!("expected a Conflict for a duplicate email, got {:?}", other),
+        }
+        // ids can come from the client too, and a batch says which of the two is taken.  the bad users in a batch
+        // don't stop the good ones, whichever way the store writes them
+        let mut batch = create_users();
+        batch[0].id = users[0].id.clone();
+        batch[1].email = users[1].email.clone();
+        let results = user_db.create_users(batch.clone()).await;
+        assert_eq!(results.len(), batch.len());
+        match &results[0] {
+            Err(UserDbError::Conflict(message)) => assert!(message.contains(&users[0].id)),
+            other => panic!("expected a Conflict for a duplicate id, got {:?}", other),
+        }
+        match &results[1] {
+            Err(UserDbError::Conflict(message)) => assert!(message.contains(&users[1].email)),
+            other => panic!("expected a Conflict for a duplicate email, got {:?}", other),
+        }
+        for (user, result) in batch.iter().zip(&results).skip(2) {
+            assert_eq!(result.as_ref().unwrap().id, user.id);
+            assert_eq!(user_db.find_user(&user.id).await.unwrap().email, user.email);
+        }
+        let written: Vec<String> = batch[2..].iter().map(|user| user.id.clone()).collect();
+        for result in user_db.delete_users(written).await {
+            result.unwrap();
+        }
+        let steal_email = UserPatch {
+            email: Some(users[0].email.clone()),
+            name: None,
+        };
+        match user_db.patch_user(&users[1].id, steal_email, None).await {
+            Err(UserDbError::Conflict(..)) => trace!("duplicate email rejected"),
+            other => panic!("expected a Conflict for a duplicate email, got {:?}", other),
+        }
+
+        // a transaction with one bad operation changes nothing, and one that is all good changes everything
+        let rename = UserPatch {
+            email: None,
+            name: Some("renamed in a transaction".to_string()),
+        };
+        let failed = user_db
+            .transaction(&users[0].partition_key)
+            .patch(&users[0].id, rename.clone(), None)
+            .delete("no-such-user", None)
+            .commit()
+            .await;
+        assert!(matches!(failed, Err(UserDbError::NotFound(..))));
+        let unchanged = user_db.find_user(&users[0].id).await.unwrap();
+        assert_eq!(unchanged.name, users[0].name);
+        let results = user_db
+            .transaction(&users[0].partition_key)
+            .patch(&users[0].id, rename, unchanged.etag)
+            .commit()
+            .await
+            .unwrap();
+        assert_eq!(
+            results[0].as_ref().unwrap().name,
+            "renamed in a transaction"
+        );
+
+        if let Some(first_user) = users.first() {
+            let u = user_db.find_user(&first_user.id).await;
+            match u {
+                Ok(found_user) => {
+                    assert!(found_user.etag.is_some());
+                    trace!("found user with id: {}", found_user.id)
+                }
+                Err(e) => panic!("failed to find user that we just inserted. error: {}", e),
+            }
+        } else {
+            panic!("the list should not be empty since we just filled it up!")
+        }
+
+        // an upsert creates a user the first time and replaces it after that
+        let upsert_id = get_id();
+        for name in ["upserted", "upserted again"] {
+            let upsert = PartialUser {
+                id: None,
+                email: format!("{}@example.com", upsert_id),
+                name: name.to_string(),
+            };
+            let user = user_db.upsert_user(&upsert_id, upsert).await.unwrap();
+            assert_eq!(user.name, name);
+        }
+        assert_eq!(
+            user_db.find_user(&upsert_id).await.unwrap().name,
+            "upserted again"
+        );
+        user_db.delete_user(&upsert_id, None).await.unwrap();
+
+        // update the first user, first all the fields and then just the name
+        let first_id = users[0].id.clone();
+        let replacement = PartialUser {
+            id: None,
+            email: "replaced@example.com".to_string(),
+            name: "replaced".to_string(),
+        };
+        let replaced = match user_db.replace_user(&first_id, replacement, None).await {
+            Ok(user) => {
+                assert_eq!(user.email, "replaced@example.com");
+                user
+            }
+            Err(e) => panic!("failed to replace user. error: {}", e),
+        };
+        let patch = UserPatch {
+            email: None,
+            name: Some("patched".to_string()),
+        };
+        match user_db
+            .patch_user(&first_id, patch, replaced.etag.clone())
+            .await
+        {
+            Ok(..) => trace!("patched user with id: {}", first_id),
+            Err(e) => panic!("failed to patch user. error: {}", e),
+        }
+        // the etag from before the patch is stale now, so a write that sends it has to fail
+        let stale = UserPatch {
+            email: None,
+            name: Some("stale".to_string()),
+        };
+        match user_db.patch_user(&first_id, stale, replaced.etag).await {
+            Ok(..) => panic!("patch with a stale etag should fail"),
+            Err(e) => assert!(matches!(e, UserDbError::PreconditionFailed(..))),
+        }
+        match user_db.find_user(&first_id).await {
+            Ok(user) => {
+                assert_eq!(user.name, "patched");
+                assert_eq!(user.email, "replaced@example.com");
+            }
+            Err(e) => panic!("failed to find the patched user. error: {}", e),
+        }
+        //
+        //  delete all the users
+        for user in users {
+            let result = user_db.delete_user(&user.id, None).await;
+            match result {
+                Ok(_) => {
+                    trace!("deleted user with id: {}", &user.id);
+                }
+                Err(e) => {
+                    panic!("failed to delete user. error: {:#?}", e)
+                }
+            }
+        }
+
+        match user_db.find_user(&first_id).await {
+            Err(UserDbError::NotFound(..)) => trace!("deleted user is gone"),
+            other => panic!("expected NotFound for a deleted user, got {:?}", other),
+        }
+
+        // get the list of users again -- should be empty
+        let users: Vec<User> = match list_all(user_db).await {
+            Ok(u) => {
+                trace!("all_users returned success");
+                u
+            }
+            Err(e) => panic!("failed to setup database and collection {}", e),
+        };
+        if !users.is_empty() {
+            panic!("we deleted all the test users but list() found some!");
+        }
+    }
+
+    /**
+     *  walk every page of the list.  the page size is smaller than the number of users that create_users() makes so
+     *  that the continuation token gets used
+     */
+    pub async fn list_all(user_db: &dyn UserStore) -> UserDbResult<Vec<User>> {
+        let mut users = Vec::new();
+        let mut continuation = None;
+        loop {
+            let page = user_db
+                .list(&UserFilter::default(), 3, continuation)
+                .await?;
+            users.extend(page.users);
+            match page.continuation {
+                Some(token) => continuation = Some(token),
+                None => return Ok(users),
+            }
+        }
+    }
+
+    pub fn create_users() -> Vec<User> {
+        let mut rng = rand::thread_rng();
+        let mut users = Vec::new();
+
+        for _ in 0..4 {
+            let id = get_id();
+            let name: String = iter::repeat(())
+                .map(|()| rng.sample(rand::distributions::Alphanumeric))
+                .map(char::from)
+                .take(10) // Adjust as needed.
+                .collect();
+            let email = format!("{}@example.com", name);
+
+            users.push(User {
+                id,
+                partition_key: String::new(),
+                email,
+                name,
+                etag: None,
+            });
+        }
+
+        users
+    }
+
+    #[test]
+    fn test_backend_names() {
+        assert_eq!(Backend::from_name("cosmos"), Some(Backend::Cosmos));
+        assert_eq!(Backend::from_name(" Memory "), Some(Backend::Memory));
+        assert_eq!(Backend::from_name("mongo"), None);
+    }
+}
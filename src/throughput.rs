@@ -0,0 +1,174 @@
+/**
+ *  how many request units per second (RU/s) cosmos sets aside for the users.  throughput is either shared by every
+ *  container in the database or dedicated to one container, and either way it is one of:
+ *
+ *      manual      a fixed number of RU/s, from 400 in steps of 100.  you pay for all of it all the time
+ *      autoscale   a maximum, from 1000 in steps of 1000.  cosmos scales between a tenth of it and all of it with the
+ *                  load, and bills for the highest it got to each hour
+ *
+ *  the `database_throughput` and `container_throughput` settings say what setupdb creates with (see from_spec), and
+ *  GET/PUT /admin/throughput read and change it while the service runs.  a serverless account has no throughput to
+ *  set, so leave both settings at "none" for one.
+ */
+use crate::error::{UserDbError, UserDbResult};
+use serde::{Deserialize, Serialize};
+
+pub const MIN_MANUAL_THROUGHPUT: u32 = 400;
+pub const MIN_AUTOSCALE_THROUGHPUT: u32 = 1000;
+// the most that can be asked for without a support ticket
+pub const MAX_THROUGHPUT: u32 = 1_000_000;
+
+/**
+ *  as JSON this is {"manual": 400} or {"autoscale": 4000}, where autoscale is the maximum
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Throughput {
+    Manual(u32),
+    Autoscale(u32),
+}
+
+/**
+ *  whose throughput it is
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ThroughputScope {
+    // shared by every container in the database
+    Database,
+    // dedicated to the users container
+    Container,
+}
+
+/**
+ *  what GET /admin/throughput returns: the users container's own throughput or, if it doesn't have any, the
+ *  database's shared throughput
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ThroughputReport {
+    pub scope: ThroughputScope,
+    pub throughput: Throughput,
+}
+
+impl Throughput {
+    /**
+     *  the `database_throughput` and `container_throughput` settings: "none", a number of RU/s for manual throughput
+     *  ("400"), or "autoscale:<max RU/s>" ("autoscale:4000").  Some(None) is "none", and None means the spec isn't
+     *  valid
+     */
+    pub fn from_spec(spec: &str) -> Option<Option<Self>> {
+        let spec = spec.trim().to_lowercase();
+        let throughput = match spec.split_once(':') {
+            _ if spec == "none" => return Some(None),
+            Some(("autoscale", max)) => Throughput::Autoscale(max.trim().parse().ok()?),
+            Some(..) => return None,
+            None => Throughput::Manual(spec.parse().ok()?),
+        };
+        throughput.check().ok().map(|()| Some(throughput))
+    }
+
+    /**
+     *  the RU/s cosmos will accept
+     */
+    pub fn check(&self) -> UserDbResult<()> {
+        let (ru, min, step) = match self {
+            Throughput::Manual(ru) => (*ru, MIN_MANUAL_THROUGHPUT, 100),
+            Throughput::Autoscale(ru) => (*ru, MIN_AUTOSCALE_THROUGHPUT, 1000),
+        };
+        if ru < min || ru > MAX_THROUGHPUT || ru % step != 0 {
+            return Err(UserDbError::Validation(format!(
+                "{} RU/s is not valid for {} throughput.  use {} to {} in steps of {}",
+                ru,
+                self.kind(),
+                min,
+                MAX_THROUGHPUT,
+                step
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Throughput::Manual(..) => "manual",
+            Throughput::Autoscale(..) => "autoscale",
+        }
+    }
+}
+
+impl ThroughputScope {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ThroughputScope::Database => "database",
+            ThroughputScope::Container => "container",
+        }
+    }
+}
+
+impl ThroughputReport {
+    /**
+     *  the same scope with the new throughput, if it can be changed to that.  switching between manual and autoscale
+     *  is a migration cosmos runs in the background, so that is left to the portal or the az cli
+     */
+    pub fn change_to(&self, throughput: Throughput) -> UserDbResult<ThroughputReport> {
+        throughput.check()?;
+        if throughput.kind() != self.throughput.kind() {
+            return Err(UserDbError::Validation(format!(
+                "the {} throughput is {}, so it can't be set to {} here.  switch it in the portal or with the az cli",
+                self.scope.name(),
+                self.throughput.kind(),
+                throughput.kind()
+            )));
+        }
+        Ok(ThroughputReport {
+            scope: self.scope,
+            throughput,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_throughput_spec() {
+        assert_eq!(Throughput::from_spec("none"), Some(None));
+        assert_eq!(
+            Throughput::from_spec("400"),
+            Some(Some(Throughput::Manual(400)))
+        );
+        assert_eq!(
+            Throughput::from_spec("Autoscale:4000"),
+            Some(Some(Throughput::Autoscale(4000)))
+        );
+        for bad in &[
+            "300",
+            "450",
+            "autoscale:1500",
+            "autoscale",
+            "manual:400",
+            "lots",
+        ] {
+            assert_eq!(Throughput::from_spec(bad), None, "{}", bad);
+        }
+
+        let report = ThroughputReport {
+            scope: ThroughputScope::Container,
+            throughput: Throughput::Manual(400),
+        };
+        assert_eq!(
+            report
+                .change_to(Throughput::Manual(1000))
+                .unwrap()
+                .throughput,
+            Throughput::Manual(1000)
+        );
+        assert!(report.change_to(Throughput::Autoscale(4000)).is_err());
+        assert!(report.change_to(Throughput::Manual(100)).is_err());
+        assert_eq!(
+            serde_json::to_value(report).unwrap(),
+            serde_json::json!({"scope": "container", "throughput": {"manual": 400}})
+        );
+    }
+}
@@ -0,0 +1,150 @@
+/**
+ *  a group of writes to one logical partition that either all happen or none do.  build it from a store, add the
+ *  operations and commit:
+ *
+ *      let results = userdb
+ *          .transaction("contoso.com")
+ *          .create(new_user)
+ *          .patch(&id, patch, Some(etag))
+ *          .delete(&old_id, None)
+ *          .commit()
+ *          .await?;
+ *
+ *  UserDb sends the whole thing as one cosmos transactional batch, and MemoryUserDb applies it to a copy of its map
+ *  that only replaces the real one when every operation worked.  cosmos only runs a batch inside one logical
+ *  partition, so every user the transaction touches has to be in (and stay in) partition_key, and a batch tops out at
+ *  100 operations.  if any operation fails, commit returns that operation's error and nothing is written.
+ */
+use crate::error::{UserDbError, UserDbResult};
+use crate::models::{PartialUser, User, UserPatch};
+use crate::store::UserStore;
+use serde::Deserialize;
+
+/**
+ *  the most operations one transaction can have.  this is the cosmos limit for a transactional batch
+ */
+pub const MAX_TRANSACTION_OPERATIONS: usize = 100;
+
+/**
+ *  one write in a transaction.  this is also the JSON for POST /partitions/{partition_key}/transaction, e.g.
+ *  {"op": "patch", "id": "unique_id123", "patch": {"name": "ann"}, "if_match": "\"0000\""}
+ */
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case", deny_unknown_fields)]
+pub enum TransactionOp {
+    // a new user, with a new id
+    Create {
+        user: PartialUser,
+    },
+    // create the user with this id, or replace it if it already exists
+    Upsert {
+        id: String,
+        user: PartialUser,
+    },
+    Replace {
+        id: String,
+        user: PartialUser,
+        #[serde(default)]
+        if_match: Option<String>,
+    },
+    Patch {
+        id: String,
+        patch: UserPatch,
+        #[serde(default)]
+        if_match: Option<String>,
+    },
+    Delete {
+        id: String,
+        #[serde(default)]
+        if_match: Option<String>,
+    },
+}
+
+pub struct Transaction<'a> {
+    store: &'a dyn UserStore,
+    partition_key: String,
+    operations: Vec<TransactionOp>,
+}
+
+impl<'s> dyn UserStore + 's {
+    /**
+     *  start a transaction in one logical partition
+     */
+    pub fn transaction(&self, partition_key: &str) -> Transaction<'_> {
+        Transaction {
+            store: self,
+            partition_key: partition_key.to_string(),
+            operations: Vec::new(),
+        }
+    }
+}
+
+impl<'a> Transaction<'a> {
+    pub fn create(self, user: PartialUser) -> Self {
+        self.push(TransactionOp::Create { user })
+    }
+
+    pub fn upsert(self, id: &str, user: PartialUser) -> Self {
+        self.push(TransactionOp::Upsert {
+            id: id.to_string(),
+            user,
+        })
+    }
+
+    pub fn replace(self, id: &str, user: PartialUser, if_match: Option<String>) -> Self {
+        self.push(TransactionOp::Replace {
+            id: id.to_string(),
+            user,
+            if_match,
+        })
+    }
+
+    pub fn patch(self, id: &str, patch: UserPatch, if_match: Option<String>) -> Self {
+        self.push(TransactionOp::Patch {
+            id: id.to_string(),
+            patch,
+            if_match,
+        })
+    }
+
+    pub fn delete(self, id: &str, if_match: Option<String>) -> Self {
+        self.push(TransactionOp::Delete {
+            id: id.to_string(),
+            if_match,
+        })
+    }
+
+    fn push(mut self, operation: TransactionOp) -> Self {
+        self.operations.push(operation);
+        self
+    }
+
+    /**
+     *  run every operation or none.  the result has one entry per operation, in order: the user as it was written, or
+     *  None for a delete
+     */
+    pub async fn commit(self) -> UserDbResult<Vec<Option<User>>> {
+        if self.operations.is_empty() || self.operations.len() > MAX_TRANSACTION_OPERATIONS {
+            return Err(UserDbError::Validation(format!(
+                "a transaction must have between 1 and {} operations",
+                MAX_TRANSACTION_OPERATIONS
+            )));
+        }
+        self.store
+            .execute_transaction(&self.partition_key, self.operations)
+            .await
+    }
+}
+
+/**
+ *  a user written by a transaction has to belong in the transaction's partition -- a batch can't write anywhere else
+ */
+pub fn check_partition(partition_key: &str, user: &User) -> UserDbResult<()> {
+    if user.partition_key != partition_key {
+        return Err(UserDbError::Validation(format!(
+            "user {} belongs in partition {}, not in this transaction's partition {}",
+            user.id, user.partition_key, partition_key
+        )));
+    }
+    Ok(())
+}
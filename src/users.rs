@@ -2,159 +2,1183 @@
  * this module implements the WebApi to create the database/collection, list all the users, and to create/find/delete
  * a User document in CosmosDb
  */
-use crate::cosmosdb::UserDb;
-use crate::models::{PartialUser, User};
-use crate::utility::{COLLECTION_NAME, DATABASE_NAME};
-use actix_web::{web, HttpResponse};
+use crate::config::{Config, SecretToken};
+use crate::error::{FieldError, UserDbError};
+use crate::models::{
+    BatchItemResult, BatchReport, PartialUser, SortField, SortOrder, User, UserCount, UserFilter,
+    UserPatch,
+};
+use crate::retry::RetryMetrics;
+use crate::store::UserStore;
+use crate::throughput::Throughput;
+use crate::transaction::TransactionOp;
+use crate::utility::{id_for_idempotency_key, DEFAULT_PAGE_SIZE, MAX_BATCH_SIZE, MAX_PAGE_SIZE};
+use crate::validation::Normalize;
+use actix_web::dev::Payload;
+use actix_web::error::JsonPayloadError;
+use actix_web::http::header;
+use actix_web::{web, FromRequest, HttpMessage, HttpRequest, HttpResponse};
 use azure_core::StatusCode;
-use serde::Serialize;
+use futures::future::{ready, FutureExt, LocalBoxFuture};
+use log::warn;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use validator::Validate;
+
+/**
+ *  the header that list_users puts the continuation token in.  it is only set when there is another page
+ */
+pub const CONTINUATION_HEADER: &str = "x-continuation";
+pub const TOTAL_COUNT_HEADER: &str = "x-total-count";
+
+/**
+ *  a POST /users with an Idempotency-Key that was already used gets the user from the first POST, with this header set
+ */
+pub const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+pub const IDEMPOTENT_REPLAYED_HEADER: &str = "idempotent-replayed";
+
+/**
+ *  POST /reset has to send the token from the environment (see config::RESET_TOKEN_VAR) in this header
+ */
+pub const RESET_TOKEN_HEADER: &str = "x-reset-token";
+/**
+ *  and the admin writes (POST /migrate, PUT /admin/throughput, POST /admin/indexing) send the one from
+ *  config::ADMIN_TOKEN_VAR in this one
+ */
+pub const ADMIN_TOKEN_HEADER: &str = "x-admin-token";
+const MAX_IDEMPOTENCY_KEY_LENGTH: usize = 255;
+
+// a batch body can be much bigger than actix's default 32 KB limit for JSON
+const MAX_BATCH_BODY_BYTES: usize = 1024 * 1024;
 
 /**
  *  We want every response to be in JSON format so that it is easier to script calling the service...when
- *  we don't have "natural" JSON (e.g. when we call 'setup'), we return the JSON of this object.
+ *  we don't have "natural" JSON (e.g. when we call 'setup'), we return the JSON of this object.  errors are returned
+ *  this way too (see UserDbError::error_response), and a 422 lists every field that is wrong in errors
  */
 #[derive(Debug, Serialize, Clone)]
-struct UserResponse {
-    message: String,
-    status: StatusCode,
-    body: String,
-}
-
-/**
- *  this will get a list of all documents.  Note this does *not* do pagination. This would be a reasonable next step to
- *  show in the sample
- */
-pub async fn list_users() -> HttpResponse {
-    //
-    //  this match should always succeed as it is tested in main()
-    let userdb = UserDb::new(DATABASE_NAME, COLLECTION_NAME).await;
-
-    // Get list of users
-    match userdb.list().await {
-        Ok(users) => HttpResponse::Ok()
-            .content_type("application/json")
-            .json(users),
-        Err(err) => {
-            let response = UserResponse {
-                message: format!("Failed to retrieve user list: {}", err),
-                status: StatusCode::NotFound,
-                body: "".to_owned(),
-            };
-            HttpResponse::NotFound()
-                .content_type("application/json")
-                .json(response)
+pub(crate) struct UserResponse {
+    pub(crate) message: String,
+    pub(crate) status: StatusCode,
+    pub(crate) body: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub(crate) errors: Vec<FieldError>,
+}
+
+/**
+ *  the query string for GET /users, e.g. /users?limit=50&continuation=<token from the last page>, or
+ *  /users?email_domain=example.com&name_prefix=jo&order_by=name&order=desc.  anything else in the query string is a
+ *  400, and so is sorting on a field that isn't in SortField.  include_total=true adds an x-total-count header with
+ *  the number of users that match the filters, which costs one more query
+ */
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ListParams {
+    pub limit: Option<u32>,
+    pub continuation: Option<String>,
+    pub name_prefix: Option<String>,
+    pub email: Option<String>,
+    pub email_domain: Option<String>,
+    pub order_by: Option<SortField>,
+    pub order: Option<SortOrder>,
+    pub include_total: Option<bool>,
+}
+
+impl ListParams {
+    /**
+     *  asking for an order without a field sorts by id
+     */
+    fn filter(&self) -> UserFilter {
+        UserFilter {
+            name_prefix: self.name_prefix.clone(),
+            email: self.email.clone(),
+            email_domain: self.email_domain.clone(),
+            order_by: self.order_by.or_else(|| self.order.map(|_| SortField::Id)),
+            order: self.order.unwrap_or_default(),
         }
+        .normalized()
     }
 }
+
 /**
- *  this will get a list of all documents.  Note this does *not* do pagination. This would be a reasonable next step to
- *  show in the sample
+ *  the query string for PUT /users/{id}.  ?upsert=true creates the user if there isn't one with that id
  */
-pub async fn find_user_by_id(id: web::Path<String>) -> HttpResponse {
-    //
-    //  this match should always succeed as it is tested in main()
-    let userdb = UserDb::new(DATABASE_NAME, COLLECTION_NAME).await;
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ReplaceParams {
+    pub upsert: Option<bool>,
+}
 
-    // Get list of users
-    match userdb.find_user(&id).await {
-        Ok(user) => HttpResponse::Ok()
-            .content_type("application/json")
-            .json(user),
-        Err(err) => {
-            let response = UserResponse {
-                message: format!("Failed to find user: {}", err),
-                status: StatusCode::NotFound,
-                body: "".to_owned(),
-            };
-            HttpResponse::NotFound()
-                .content_type("application/json")
-                .json(response)
+/**
+ *  the query string for GET /users/count: the same filters as ListParams, without the paging and sorting
+ */
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CountParams {
+    pub name_prefix: Option<String>,
+    pub email: Option<String>,
+    pub email_domain: Option<String>,
+}
+
+impl CountParams {
+    fn filter(&self) -> UserFilter {
+        UserFilter {
+            name_prefix: self.name_prefix.clone(),
+            email: self.email.clone(),
+            email_domain: self.email_domain.clone(),
+            ..UserFilter::default()
         }
+        .normalized()
     }
 }
+
+/**
+ *  bad query strings get the same JSON error body as everything else.  main() installs this for every route
+ */
+pub fn query_error(
+    err: actix_web::error::QueryPayloadError,
+    _req: &HttpRequest,
+) -> actix_web::Error {
+    UserDbError::Validation(format!("invalid query string: {}", err)).into()
+}
+
 /**
- * this sets up CosmosDb to make the sample run. the only prereq is the secrets set in
- * .devconainter/required-secrets.json, this API will call setupdb. this just calls the setupdb api and deals with errors
- */
-pub async fn setup() -> HttpResponse {
-    let userdb = UserDb::new(DATABASE_NAME, COLLECTION_NAME).await;
-    match userdb.setupdb().await {
-        Ok(..) => {
-            let response = UserResponse {
-                message: format!(
-                    "database: {} collection: {} \ncreated",
-                    DATABASE_NAME, COLLECTION_NAME
-                ),
-                status: StatusCode::Ok,
-                body: "".to_owned(),
-            };
-            HttpResponse::Ok()
-                .content_type("application/json")
-                .json(response)
-        }
-        Err(err) => {
-            let response = UserResponse {
-                message: format!("Failed to create database/collection: {}", err),
-                status: StatusCode::BadRequest,
-                body: "".to_owned(),
-            };
-            HttpResponse::BadRequest()
-                .content_type("application/json")
-                .json(response)
+ *  the body of a create or an update.  clients can send it as JSON (Content-Type: application/json) or as form data
+ *  (application/x-www-form-urlencoded), and a body that doesn't fit T gets the same 400 UserResponse either way instead
+ *  of actix's plain text error.  any other Content-Type is a 415.  once it is parsed the body is normalized and
+ *  validated (see validation.rs), so a handler that takes a UserBody only ever sees data that passed the rules.
+ */
+#[derive(Debug)]
+pub struct UserBody<T>(pub T);
+
+impl<T> UserBody<T> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T: DeserializeOwned + Normalize + Validate + 'static> FromRequest for UserBody<T> {
+    type Error = UserDbError;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let content_type = req.content_type().to_lowercase();
+        if content_type == "application/json" || content_type.ends_with("+json") {
+            web::Json::<T>::from_request(req, payload)
+                .map(|body| match body {
+                    Ok(body) => checked(body.into_inner()),
+                    Err(e) => Err(invalid_body(e)),
+                })
+                .boxed_local()
+        } else if content_type == "application/x-www-form-urlencoded" {
+            web::Form::<T>::from_request(req, payload)
+                .map(|body| match body {
+                    Ok(body) => checked(body.into_inner()),
+                    Err(e) => Err(invalid_body(e)),
+                })
+                .boxed_local()
+        } else {
+            ready(Err(UserDbError::UnsupportedMediaType(format!(
+                "Content-Type '{}' is not supported.  send application/json or application/x-www-form-urlencoded",
+                content_type
+            ))))
+            .boxed_local()
         }
     }
 }
 
+fn checked<T: Normalize + Validate>(mut body: T) -> Result<UserBody<T>, UserDbError> {
+    body.normalize();
+    body.validate()?;
+    Ok(UserBody(body))
+}
+
+fn invalid_body(err: actix_web::Error) -> UserDbError {
+    UserDbError::Validation(format!("invalid request body: {}", err))
+}
+
+/**
+ *  the JSON settings for /users:batch.  the body is a JSON array (there is no form encoding for a list of users), and
+ *  a body that isn't one gets the same errors as UserBody gives
+ */
+pub fn batch_json_config() -> web::JsonConfig {
+    web::JsonConfig::default()
+        .limit(MAX_BATCH_BODY_BYTES)
+        .error_handler(|err, _req| match err {
+            JsonPayloadError::ContentType => UserDbError::UnsupportedMediaType(
+                "a batch has to be sent as application/json".to_string(),
+            )
+            .into(),
+            err => invalid_body(err.into()).into(),
+        })
+}
+
+fn check_batch_size(len: usize) -> Result<(), UserDbError> {
+    if len == 0 || len > MAX_BATCH_SIZE {
+        return Err(UserDbError::Validation(format!(
+            "a batch must have between 1 and {} items",
+            MAX_BATCH_SIZE
+        )));
+    }
+    Ok(())
+}
+
+/**
+ *  200 when every item worked, 207 Multi-Status when some didn't
+ */
+fn batch_response(mut items: Vec<BatchItemResult>) -> HttpResponse {
+    items.sort_by_key(|item| item.index);
+    let succeeded = items.iter().filter(|item| item.is_success()).count();
+    let report = BatchReport {
+        succeeded,
+        failed: items.len() - succeeded,
+        items,
+    };
+    let status = if report.failed == 0 {
+        actix_web::http::StatusCode::OK
+    } else {
+        actix_web::http::StatusCode::MULTI_STATUS
+    };
+    HttpResponse::build(status)
+        .content_type("application/json")
+        .json(report)
+}
+
+/**
+ *  this will get a page of documents.  the body is the JSON array of users, and if there are more users the token for
+ *  the next page is returned in the x-continuation header.  pass it back as ?continuation= (with the same filters) to
+ *  get the next page.
+ *
+ *  the handlers return UserDbError on failure, which actix turns into a JSON UserResponse with the matching status
+ *  code (404 for a missing user, 409 for a duplicate, 429 when throttled, and so on)
+ */
+pub async fn list_users(
+    userdb: web::Data<dyn UserStore>,
+    params: web::Query<ListParams>,
+) -> Result<HttpResponse, UserDbError> {
+    let params = params.into_inner();
+    let page_size = params.limit.unwrap_or(DEFAULT_PAGE_SIZE);
+    if page_size == 0 || page_size > MAX_PAGE_SIZE {
+        return Err(UserDbError::Validation(format!(
+            "limit must be between 1 and {}",
+            MAX_PAGE_SIZE
+        )));
+    }
+
+    // Get a page of users
+    let filter = params.filter();
+    let page = userdb.list(&filter, page_size, params.continuation).await?;
+    let mut builder = HttpResponse::Ok();
+    builder.content_type("application/json");
+    if let Some(token) = page.continuation {
+        builder.insert_header((CONTINUATION_HEADER, token));
+    }
+    if params.include_total == Some(true) {
+        let total = userdb.count(&filter).await?;
+        builder.insert_header((TOTAL_COUNT_HEADER, total.to_string()));
+    }
+    Ok(builder.json(page.users))
+}
+/**
+ *  how many users match the filters, as {"count": n}.  this is a COUNT query, so it doesn't read the users themselves
+ */
+pub async fn count_users(
+    userdb: web::Data<dyn UserStore>,
+    params: web::Query<CountParams>,
+) -> Result<HttpResponse, UserDbError> {
+    let count = userdb.count(&params.filter()).await?;
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .json(UserCount { count }))
+}
+/**
+ *  find one user by its id
+ */
+pub async fn find_user_by_id(
+    userdb: web::Data<dyn UserStore>,
+    id: web::Path<String>,
+) -> Result<HttpResponse, UserDbError> {
+    let user = userdb.find_user(&id).await?;
+    Ok(user_response(user))
+}
+/**
+ * this sets up CosmosDb to make the sample run. the only prereq is the secrets set in
+ * .devconainter/required-secrets.json, this API will call setupdb. this just calls the setupdb api and deals with errors.
+ * it only creates what is missing, so it is safe to call again, and the body says what it created
+ */
+pub async fn setup(userdb: web::Data<dyn UserStore>) -> Result<HttpResponse, UserDbError> {
+    let report = userdb.setupdb().await?;
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .json(report))
+}
+
+/**
+ *  Forbidden unless the service was started with a token for this and the request sends it in header.  disabled and
+ *  why_confirm say which of those is missing
+ */
+fn check_token(
+    req: &HttpRequest,
+    token: Option<&SecretToken>,
+    header: &str,
+    disabled: &str,
+    why_confirm: &str,
+) -> Result<(), UserDbError> {
+    let token = token.ok_or_else(|| UserDbError::Forbidden(disabled.to_string()))?;
+    let confirmed = req
+        .headers()
+        .get(header)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| token.matches(value));
+    if !confirmed {
+        return Err(UserDbError::Forbidden(format!(
+            "{}.  send the token in the {} header to confirm",
+            why_confirm, header
+        )));
+    }
+    Ok(())
+}
+
+/**
+ *  POST /reset: delete the database with every user in it and set it up again.  this is a 403 unless the service was
+ *  started with allow_reset and the request has the reset token in the x-reset-token header
+ */
+pub async fn reset(
+    req: HttpRequest,
+    userdb: web::Data<dyn UserStore>,
+    config: web::Data<Config>,
+) -> Result<HttpResponse, UserDbError> {
+    check_token(
+        &req,
+        config.reset_token.as_ref(),
+        RESET_TOKEN_HEADER,
+        "reset is disabled.  start the service with allow_reset = true and a reset token to use it",
+        "reset deletes every user",
+    )?;
+    warn!(
+        "resetting database {} collection {}",
+        config.database_name, config.collection_name
+    );
+    let report = userdb.reset().await?;
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .json(report))
+}
+
+/**
+ *  POST /migrate: move every user into the partition the configured partition_strategy puts it in (see partition.rs).
+ *  run this after changing the strategy, or after upgrading from the version of this sample that put every user in
+ *  partition_key 1.  it is safe to run again, and the body says how many users were looked at and how many moved.
+ *  it rewrites every misplaced document, so like the other admin writes it needs allow_admin and the admin token
+ */
+pub async fn migrate(
+    req: HttpRequest,
+    userdb: web::Data<dyn UserStore>,
+    config: web::Data<Config>,
+) -> Result<HttpResponse, UserDbError> {
+    check_token(
+        &req,
+        config.admin_token.as_ref(),
+        ADMIN_TOKEN_HEADER,
+        "migrating partitions is disabled.  start the service with allow_admin = true and an admin token to use it",
+        "the migration moves every user that is in the wrong partition",
+    )?;
+    let report = userdb.migrate_partitions().await?;
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .json(report))
+}
+
+/**
+ *  GET /admin/throughput: the RU/s the users collection runs on, and whether it is the collection's own or shared by
+ *  the database, e.g. {"scope": "container", "throughput": {"autoscale": 4000}}
+ */
+pub async fn get_throughput(userdb: web::Data<dyn UserStore>) -> Result<HttpResponse, UserDbError> {
+    let report = userdb.get_throughput().await?;
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .json(report))
+}
+
+/**
+ *  PUT /admin/throughput with {"manual": <RU/s>} or {"autoscale": <max RU/s>}: change the throughput that GET
+ *  reports.  it has to stay the same kind.  what cosmos bills goes with it, so this is a 403 unless the service was
+ *  started with allow_admin and the request has the admin token in the x-admin-token header
+ */
+pub async fn set_throughput(
+    req: HttpRequest,
+    userdb: web::Data<dyn UserStore>,
+    config: web::Data<Config>,
+    throughput: web::Json<Throughput>,
+) -> Result<HttpResponse, UserDbError> {
+    check_token(
+        &req,
+        config.admin_token.as_ref(),
+        ADMIN_TOKEN_HEADER,
+        "changing the throughput is disabled.  start the service with allow_admin = true and an admin token to use it",
+        "the throughput decides what the account costs",
+    )?;
+    warn!("setting the throughput to {:?}", throughput);
+    let report = userdb.set_throughput(throughput.into_inner()).await?;
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .json(report))
+}
+
+/**
+ *  GET /admin/indexing: how the users collection's indexing policy differs from the one in indexing.rs.  changes lists
+ *  what POST would do, and up_to_date is true when there is nothing to do
+ */
+pub async fn indexing_policy(
+    userdb: web::Data<dyn UserStore>,
+) -> Result<HttpResponse, UserDbError> {
+    let report = userdb.indexing_policy().await?;
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .json(report))
+}
+
+/**
+ *  POST /admin/indexing: give the users collection the indexing policy in indexing.rs.  changes lists what was done.
+ *  searches can miss users while cosmos reindexes, so like PUT /admin/throughput this needs allow_admin and the admin
+ *  token
+ */
+pub async fn apply_indexing_policy(
+    req: HttpRequest,
+    userdb: web::Data<dyn UserStore>,
+    config: web::Data<Config>,
+) -> Result<HttpResponse, UserDbError> {
+    check_token(
+        &req,
+        config.admin_token.as_ref(),
+        ADMIN_TOKEN_HEADER,
+        "changing the indexing policy is disabled.  start the service with allow_admin = true and an admin token to \
+         use it",
+        "searches can miss users until cosmos has reindexed",
+    )?;
+    let report = userdb.apply_indexing_policy().await?;
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .json(report))
+}
+
+/**
+ *  GET /metrics/retries: how often the store has been throttled or unavailable since the service started, and how
+ *  long it has spent waiting to retry (see retry.rs)
+ */
+pub async fn retry_metrics(metrics: web::Data<RetryMetrics>) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("application/json")
+        .json(metrics.snapshot())
+}
+
 /**
- *  this creates a user.  it uses web forms to collect the data from the client.  Note that if you are using PostMan
- *  to call this API, set the form data in 'x-www-form-urlencoded', *not* in 'form-data', as that will fail with a
- *  hard-to-figure-out error in actix_web deserialize layer.
+ *  this creates a user.  the data comes in as JSON or as web form data (see UserBody).  Note that if you are using
+ *  PostMan to send form data, set it in 'x-www-form-urlencoded', *not* in 'form-data' -- multipart bodies get a 415.
  */
-pub async fn create(user_req: web::Form<PartialUser>) -> HttpResponse {
-    let pp: PartialUser = user_req.into_inner();
+pub async fn create(
+    req: HttpRequest,
+    userdb: web::Data<dyn UserStore>,
+    user_req: UserBody<PartialUser>,
+) -> Result<HttpResponse, UserDbError> {
+    let mut pp: PartialUser = user_req.into_inner();
+    let key = idempotency_key(&req)?;
+    if let (None, Some(key)) = (&pp.id, &key) {
+        pp.id = Some(id_for_idempotency_key(key));
+    }
     let user: User = pp.into();
-    let userdb = UserDb::new(DATABASE_NAME, COLLECTION_NAME).await;
     match userdb.create_user(user.clone()).await {
-        Ok(..) => {
-            HttpResponse::Ok()
-                .content_type("application/json")
-                .json(user)
+        Ok(user) => Ok(user_response(user)),
+        Err(UserDbError::Conflict(message)) if key.is_some() => {
+            replay(&userdb, user, UserDbError::Conflict(message)).await
         }
-        Err(err) => {
-            let response = UserResponse {
-                message: format!("Failed to add user to collection: {}", err),
-                status: StatusCode::BadRequest,
-                body: "".to_owned(),
-            };
-            HttpResponse::BadRequest()
-                .content_type("application/json")
-                .json(response)
+        Err(e) => Err(e),
+    }
+}
+
+/**
+ *  a create with an Idempotency-Key ran into a conflict.  if it is with a user that has the id and the data the
+ *  request asked for, the request has been made before and that user is the answer.  if the user with that id is
+ *  different the key was used for another request, and anything else (e.g. somebody else has the email) is the
+ *  conflict it was
+ */
+async fn replay(
+    userdb: &web::Data<dyn UserStore>,
+    requested: User,
+    conflict: UserDbError,
+) -> Result<HttpResponse, UserDbError> {
+    let existing = match userdb.find_user(&requested.id).await {
+        Ok(existing) => existing,
+        Err(UserDbError::NotFound(..)) => return Err(conflict),
+        Err(e) => return Err(e),
+    };
+    if existing.email != requested.email || existing.name != requested.name {
+        return Err(UserDbError::Conflict(
+            "the Idempotency-Key was already used to create a different user".to_string(),
+        ));
+    }
+    let mut response = user_response(existing);
+    response.headers_mut().insert(
+        header::HeaderName::from_static(IDEMPOTENT_REPLAYED_HEADER),
+        header::HeaderValue::from_static("true"),
+    );
+    Ok(response)
+}
+
+/**
+ *  the Idempotency-Key header, if the client sent one.  any printable ASCII up to 255 characters works -- a UUID is
+ *  the usual choice
+ */
+fn idempotency_key(req: &HttpRequest) -> Result<Option<String>, UserDbError> {
+    let value = match req.headers().get(IDEMPOTENCY_KEY_HEADER) {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+    match value.to_str().map(str::trim) {
+        Ok(key) if !key.is_empty() && key.len() <= MAX_IDEMPOTENCY_KEY_LENGTH => {
+            Ok(Some(key.to_string()))
         }
+        _ => Err(UserDbError::Validation(format!(
+            "Idempotency-Key must be 1 to {} printable ASCII characters",
+            MAX_IDEMPOTENCY_KEY_LENGTH
+        ))),
     }
 }
 
-pub async fn delete(id: web::Path<String>) -> HttpResponse {
-    let userdb = UserDb::new(DATABASE_NAME, COLLECTION_NAME).await;
-    match userdb.delete_user(&id).await {
-        Ok(..) => {
-            let response = UserResponse {
-                message: format!("deleted user with id: {}", id),
-                status: StatusCode::Ok,
-                body: "".to_owned(),
-            };
-            HttpResponse::Ok()
-                .content_type("application/json")
-                .json(response)
+/**
+ *  PUT /users/{id}: replace the name and email of an existing user.  like create, the data comes in as JSON or form
+ *  data and every field is required.  the id and partition_key don't change.  send the ETag from the last read in an If-Match
+ *  header to make sure you aren't overwriting a change you haven't seen -- if it is stale you get a 412.
+ *
+ *  with ?upsert=true a user that doesn't exist is created with the id in the url.  an upsert always writes, so it
+ *  can't have an If-Match
+ */
+pub async fn replace(
+    req: HttpRequest,
+    userdb: web::Data<dyn UserStore>,
+    id: web::Path<String>,
+    params: web::Query<ReplaceParams>,
+    user_req: UserBody<PartialUser>,
+) -> Result<HttpResponse, UserDbError> {
+    let user_req = user_req.into_inner();
+    if user_req.id.as_ref().is_some_and(|body_id| *body_id != *id) {
+        return Err(UserDbError::Validation(
+            "the id in the body doesn't match the id in the url".to_string(),
+        ));
+    }
+    let user = if params.upsert == Some(true) {
+        if if_match(&req).is_some() {
+            return Err(UserDbError::Validation(
+                "If-Match can't be used with upsert".to_string(),
+            ));
+        }
+        userdb.upsert_user(&id, user_req).await?
+    } else {
+        userdb.replace_user(&id, user_req, if_match(&req)).await?
+    };
+    Ok(user_response(user))
+}
+
+/**
+ *  PATCH /users/{id}: change any subset of the fields of an existing user.  fields that are not in the body keep their
+ *  current value.  If-Match works the same way as it does for PUT.
+ */
+pub async fn patch(
+    req: HttpRequest,
+    userdb: web::Data<dyn UserStore>,
+    id: web::Path<String>,
+    patch_req: UserBody<UserPatch>,
+) -> Result<HttpResponse, UserDbError> {
+    let user = userdb
+        .patch_user(&id, patch_req.into_inner(), if_match(&req))
+        .await?;
+    Ok(user_response(user))
+}
+
+/**
+ *  POST /users:batch: create every user in a JSON array of {"name": ..., "email": ...} objects.  each one is
+ *  normalized and validated like a single create, and one bad or duplicate user doesn't stop the others -- the
+ *  response reports each item (see BatchReport).  the store writes them with bounded concurrency, or as transactional
+ *  batches when they all go in one partition (see UserStore::create_users)
+ */
+pub async fn create_batch(
+    userdb: web::Data<dyn UserStore>,
+    body: web::Json<Vec<serde_json::Value>>,
+) -> Result<HttpResponse, UserDbError> {
+    let items = body.into_inner();
+    check_batch_size(items.len())?;
+
+    let mut results = Vec::with_capacity(items.len());
+    let mut indexes = Vec::new();
+    let mut users = Vec::new();
+    for (index, item) in items.into_iter().enumerate() {
+        let user = serde_json::from_value::<PartialUser>(item)
+            .map_err(|e| UserDbError::Validation(format!("invalid user: {}", e)))
+            .and_then(checked);
+        match user {
+            Ok(user) => {
+                indexes.push(index);
+                users.push(User::from(user.into_inner()));
+            }
+            Err(e) => results.push(BatchItemResult::failed(index, None, &e)),
+        }
+    }
+
+    let ids: Vec<String> = users.iter().map(|user| user.id.clone()).collect();
+    let outcomes = userdb.create_users(users).await;
+    for ((index, id), outcome) in indexes.into_iter().zip(ids).zip(outcomes) {
+        results.push(match outcome {
+            Ok(user) => BatchItemResult::succeeded(index, id, 201, Some(user)),
+            Err(e) => BatchItemResult::failed(index, Some(id), &e),
+        });
+    }
+    Ok(batch_response(results))
+}
+
+/**
+ *  DELETE /users:batch: delete every user in a JSON array of ids, with a report like create_batch's
+ */
+pub async fn delete_batch(
+    userdb: web::Data<dyn UserStore>,
+    body: web::Json<Vec<String>>,
+) -> Result<HttpResponse, UserDbError> {
+    let ids = body.into_inner();
+    check_batch_size(ids.len())?;
+    let outcomes = userdb.delete_users(ids.clone()).await;
+    let results = ids
+        .into_iter()
+        .zip(outcomes)
+        .enumerate()
+        .map(|(index, (id, outcome))| match outcome {
+            Ok(()) => BatchItemResult::succeeded(index, id, 200, None),
+            Err(e) => BatchItemResult::failed(index, Some(id), &e),
+        })
+        .collect();
+    Ok(batch_response(results))
+}
+
+/**
+ *  POST /partitions/{partition_key}/transaction: run a JSON array of operations (see TransactionOp) in one partition,
+ *  all or nothing.  the users in the operations are normalized and validated just like for the single user calls.
+ *  the response is the array of users as written, with null for each delete
+ */
+pub async fn transaction(
+    userdb: web::Data<dyn UserStore>,
+    partition_key: web::Path<String>,
+    body: web::Json<Vec<TransactionOp>>,
+) -> Result<HttpResponse, UserDbError> {
+    let mut transaction = userdb.transaction(&partition_key);
+    for (index, operation) in body.into_inner().into_iter().enumerate() {
+        transaction = match operation {
+            TransactionOp::Create { user } => transaction.create(checked_operation(user, index)?),
+            TransactionOp::Upsert { id, user } => {
+                transaction.upsert(&id, checked_operation(user, index)?)
+            }
+            TransactionOp::Replace { id, user, if_match } => {
+                transaction.replace(&id, checked_operation(user, index)?, if_match)
+            }
+            TransactionOp::Patch {
+                id,
+                patch,
+                if_match,
+            } => transaction.patch(&id, checked_operation(patch, index)?, if_match),
+            TransactionOp::Delete { id, if_match } => transaction.delete(&id, if_match),
+        };
+    }
+    let users = transaction.commit().await?;
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .json(users))
+}
+
+fn checked_operation<T: Normalize + Validate>(body: T, index: usize) -> Result<T, UserDbError> {
+    checked(body)
+        .map(UserBody::into_inner)
+        .map_err(|e| e.in_operation(index))
+}
+
+/**
+ *  DELETE /users/{id}: delete the user.  if there is an If-Match header, the user is only deleted if it hasn't changed
+ */
+pub async fn delete(
+    req: HttpRequest,
+    userdb: web::Data<dyn UserStore>,
+    id: web::Path<String>,
+) -> Result<HttpResponse, UserDbError> {
+    userdb.delete_user(&id, if_match(&req)).await?;
+    let response = UserResponse {
+        message: format!("deleted user with id: {}", id),
+        status: StatusCode::Ok,
+        body: "".to_owned(),
+        errors: Vec::new(),
+    };
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .json(response))
+}
+
+/**
+ *  a single user is returned as JSON with its etag in the ETag header, which the client can send back in If-Match
+ */
+fn user_response(user: User) -> HttpResponse {
+    let mut builder = HttpResponse::Ok();
+    builder.content_type("application/json");
+    if let Some(etag) = &user.etag {
+        builder.insert_header((header::ETAG, etag.clone()));
+    }
+    builder.json(user)
+}
+
+/**
+ *  the etag the client sent in the If-Match header, if any
+ */
+fn if_match(req: &HttpRequest) -> Option<String> {
+    req.headers()
+        .get(header::IF_MATCH)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memorydb::MemoryUserDb;
+    use crate::models::{MigrationReport, SetupReport};
+    use crate::partition::PartitionStrategy;
+    use crate::throughput::{ThroughputReport, ThroughputScope};
+    use actix_web::{test, App};
+    use std::sync::Arc;
+
+    fn memory_store() -> web::Data<dyn UserStore> {
+        let store: Arc<dyn UserStore> = Arc::new(MemoryUserDb::new(
+            "user-test-db",
+            "user-test-collection",
+            PartitionStrategy::default(),
+        ));
+        web::Data::from(store)
+    }
+
+    #[actix_web::test]
+    async fn test_create_accepts_json_and_form() {
+        let app = test::init_service(
+            App::new()
+                .app_data(memory_store())
+                .route("/users", web::post().to(create)),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/users")
+            .set_json(serde_json::json!({"name": " json ", "email": "json@Example.com"}))
+            .to_request();
+        let user: User = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(user.email, "json@example.com");
+        assert_eq!(user.name, "json");
+
+        let req = test::TestRequest::post()
+            .uri("/users")
+            .set_form([("name", "form"), ("email", "form@example.com")])
+            .to_request();
+        let user: User = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(user.email, "form@example.com");
+    }
+
+    #[actix_web::test]
+    async fn test_count_and_total_header() {
+        let app = test::init_service(
+            App::new()
+                .app_data(memory_store())
+                .app_data(web::QueryConfig::default().error_handler(query_error))
+                .route("/users", web::get().to(list_users))
+                .route("/users", web::post().to(create))
+                .route("/users/count", web::get().to(count_users)),
+        )
+        .await;
+        for (name, email) in [
+            ("a", "a@contoso.com"),
+            ("b", "b@contoso.com"),
+            ("c", "c@fabrikam.com"),
+        ] {
+            let req = test::TestRequest::post()
+                .uri("/users")
+                .set_form([("name", name), ("email", email)])
+                .to_request();
+            test::call_service(&app, req).await;
         }
-        Err(err) => {
-            let response = UserResponse {
-                message: format!("Failed to delete user: {}", err),
-                status: StatusCode::BadRequest,
-                body: "".to_owned(),
+
+        let req = test::TestRequest::get()
+            .uri("/users/count?email_domain=Contoso.com")
+            .to_request();
+        let count: UserCount = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(count.count, 2);
+
+        // the total is every match, not just the page
+        let req = test::TestRequest::get()
+            .uri("/users?limit=1&include_total=true")
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.headers().get(TOTAL_COUNT_HEADER).unwrap(), "3");
+
+        // paging doesn't mean anything to a count
+        let req = test::TestRequest::get()
+            .uri("/users/count?limit=1")
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status().as_u16(), 400);
+    }
+
+    #[actix_web::test]
+    async fn test_reset_is_guarded() {
+        let token = "a-long-enough-reset-token";
+        let reset_app = |config: Config| {
+            test::init_service(
+                App::new()
+                    .app_data(memory_store())
+                    .app_data(web::Data::new(config))
+                    .route("/users", web::post().to(create))
+                    .route("/setup", web::post().to(setup))
+                    .route("/reset", web::post().to(reset)),
+            )
+        };
+        let reset_request = |token: Option<&str>| {
+            let req = test::TestRequest::post().uri("/reset");
+            match token {
+                Some(token) => req.insert_header((RESET_TOKEN_HEADER, token)),
+                None => req,
+            }
+            .to_request()
+        };
+
+        // off by default, whatever the request sends
+        let app = reset_app(Config::default()).await;
+        let response = test::call_service(&app, reset_request(Some(token))).await;
+        assert_eq!(response.status().as_u16(), 403);
+
+        let config = Config::load_from(&["--allow-reset=true".to_string()], &|name| {
+            (name == crate::config::RESET_TOKEN_VAR).then(|| token.to_string())
+        })
+        .unwrap();
+        let app = reset_app(config).await;
+        let req = test::TestRequest::post()
+            .uri("/users")
+            .set_json(serde_json::json!({"name": "ann", "email": "ann@example.com"}))
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+
+        // setup leaves the users alone
+        let req = test::TestRequest::post().uri("/setup").to_request();
+        let report: SetupReport = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(report, SetupReport::default());
+
+        for wrong in [None, Some("not-the-reset-token")] {
+            let response = test::call_service(&app, reset_request(wrong)).await;
+            assert_eq!(response.status().as_u16(), 403);
+        }
+        let response = test::call_service(&app, reset_request(Some(token))).await;
+        assert_eq!(response.status().as_u16(), 200);
+
+        // ann is gone, so her email is free again
+        let req = test::TestRequest::post()
+            .uri("/users")
+            .set_json(serde_json::json!({"name": "ann", "email": "ann@example.com"}))
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+    }
+
+    #[actix_web::test]
+    async fn test_throughput() {
+        let store: Arc<dyn UserStore> = Arc::new(
+            MemoryUserDb::new("db", "users", PartitionStrategy::default())
+                .with_throughput(None, Some(Throughput::Manual(400))),
+        );
+        let token = "a-long-enough-admin-token";
+        let config = Config::load_from(&["--allow-admin=true".to_string()], &|name| {
+            (name == crate::config::ADMIN_TOKEN_VAR).then(|| token.to_string())
+        })
+        .unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::from(store))
+                .app_data(web::Data::new(config))
+                .route("/admin/throughput", web::get().to(get_throughput))
+                .route("/admin/throughput", web::put().to(set_throughput)),
+        )
+        .await;
+
+        // reading it is open, changing it takes the admin token
+        for wrong in [None, Some("not-the-admin-token")] {
+            let req = test::TestRequest::put()
+                .uri("/admin/throughput")
+                .set_json(serde_json::json!({"manual": 1_000_000}));
+            let req = match wrong {
+                Some(wrong) => req.insert_header((ADMIN_TOKEN_HEADER, wrong)),
+                None => req,
             };
-            HttpResponse::BadRequest()
-                .content_type("application/json")
-                .json(response)
+            let response = test::call_service(&app, req.to_request()).await;
+            assert_eq!(response.status().as_u16(), 403);
         }
+        let req = test::TestRequest::put()
+            .uri("/admin/throughput")
+            .insert_header((ADMIN_TOKEN_HEADER, token))
+            .set_json(serde_json::json!({"manual": 1200}))
+            .to_request();
+        let report: ThroughputReport = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(report.throughput, Throughput::Manual(1200));
+        let req = test::TestRequest::get()
+            .uri("/admin/throughput")
+            .to_request();
+        let report: ThroughputReport = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(report.scope, ThroughputScope::Container);
+        assert_eq!(report.throughput, Throughput::Manual(1200));
+
+        for bad in [
+            serde_json::json!({"manual": 450}),
+            serde_json::json!({"autoscale": 4000}),
+        ] {
+            let req = test::TestRequest::put()
+                .uri("/admin/throughput")
+                .insert_header((ADMIN_TOKEN_HEADER, token))
+                .set_json(bad)
+                .to_request();
+            assert_eq!(test::call_service(&app, req).await.status().as_u16(), 400);
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_migrate_is_guarded() {
+        let token = "a-long-enough-admin-token";
+        let migrate_app = |config: Config| {
+            test::init_service(
+                App::new()
+                    .app_data(memory_store())
+                    .app_data(web::Data::new(config))
+                    .route("/migrate", web::post().to(migrate)),
+            )
+        };
+        let migrate_request = |token: Option<&str>| {
+            let req = test::TestRequest::post().uri("/migrate");
+            match token {
+                Some(token) => req.insert_header((ADMIN_TOKEN_HEADER, token)),
+                None => req,
+            }
+            .to_request()
+        };
+
+        // off by default, whatever the request sends
+        let app = migrate_app(Config::default()).await;
+        let response = test::call_service(&app, migrate_request(Some(token))).await;
+        assert_eq!(response.status().as_u16(), 403);
+
+        let config = Config::load_from(&["--allow-admin=true".to_string()], &|name| {
+            (name == crate::config::ADMIN_TOKEN_VAR).then(|| token.to_string())
+        })
+        .unwrap();
+        let app = migrate_app(config).await;
+        for wrong in [None, Some("not-the-admin-token")] {
+            let response = test::call_service(&app, migrate_request(wrong)).await;
+            assert_eq!(response.status().as_u16(), 403);
+        }
+        let report: MigrationReport =
+            test::call_and_read_body_json(&app, migrate_request(Some(token))).await;
+        assert_eq!(report, MigrationReport::default());
+    }
+
+    #[actix_web::test]
+    async fn test_indexing_needs_cosmos() {
+        let token = "a-long-enough-admin-token";
+        let config = Config::load_from(&["--allow-admin=true".to_string()], &|name| {
+            (name == crate::config::ADMIN_TOKEN_VAR).then(|| token.to_string())
+        })
+        .unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(memory_store())
+                .app_data(web::Data::new(config))
+                .route("/admin/indexing", web::get().to(indexing_policy))
+                .route("/admin/indexing", web::post().to(apply_indexing_policy)),
+        )
+        .await;
+
+        // applying the policy takes the admin token
+        let req = test::TestRequest::post().uri("/admin/indexing");
+        let resp = test::call_service(&app, req.to_request()).await;
+        assert_eq!(resp.status().as_u16(), 403);
+
+        // and the memory store has no indexing policy to show or change
+        for req in [
+            test::TestRequest::get().uri("/admin/indexing"),
+            test::TestRequest::post()
+                .uri("/admin/indexing")
+                .insert_header((ADMIN_TOKEN_HEADER, token)),
+        ] {
+            let resp = test::call_service(&app, req.to_request()).await;
+            assert_eq!(resp.status().as_u16(), 404);
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_batch_reports_every_item() {
+        let app = test::init_service(
+            App::new().app_data(memory_store()).service(
+                web::resource("/users:batch")
+                    .app_data(batch_json_config())
+                    .route(web::post().to(create_batch))
+                    .route(web::delete().to(delete_batch)),
+            ),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/users:batch")
+            .set_json(serde_json::json!([
+                {"name": "one", "email": "one@example.com"},
+                {"name": "", "email": "two@example.com"},
+                {"name": "three", "email": "one@EXAMPLE.com"},
+                {"name": "four"},
+                {"name": "five", "email": "five@example.com"}
+            ]))
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status().as_u16(), 207);
+        let report: BatchReport = test::read_body_json(response).await;
+        assert_eq!((report.succeeded, report.failed), (2, 3));
+        let statuses: Vec<u16> = report.items.iter().map(|item| item.status).collect();
+        assert_eq!(statuses, vec![201, 422, 409, 400, 201]);
+        assert_eq!(report.items[1].errors[0].field, "name");
+
+        // deleting the two that were created, and one that doesn't exist
+        let mut ids: Vec<String> = report
+            .items
+            .iter()
+            .filter(|item| item.is_success())
+            .map(|item| item.id.clone().unwrap())
+            .collect();
+        ids.push("not-there".to_string());
+        let req = test::TestRequest::delete()
+            .uri("/users:batch")
+            .set_json(&ids)
+            .to_request();
+        let report: BatchReport = test::call_and_read_body_json(&app, req).await;
+        let statuses: Vec<u16> = report.items.iter().map(|item| item.status).collect();
+        assert_eq!(statuses, vec![200, 200, 404]);
+
+        // a batch has to be JSON, and can't be empty
+        let req = test::TestRequest::post()
+            .uri("/users:batch")
+            .set_form([("name", "form")])
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status().as_u16(), 415);
+        let req = test::TestRequest::delete()
+            .uri("/users:batch")
+            .set_json(serde_json::json!([]))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status().as_u16(), 400);
+    }
+
+    #[actix_web::test]
+    async fn test_retried_posts_create_one_user() {
+        let app = test::init_service(
+            App::new()
+                .app_data(memory_store())
+                .app_data(web::QueryConfig::default().error_handler(query_error))
+                .route("/users", web::post().to(create))
+                .route("/users/count", web::get().to(count_users))
+                .route("/users/{id}", web::put().to(replace)),
+        )
+        .await;
+        let post = |key: &str, name: &str| {
+            test::TestRequest::post()
+                .uri("/users")
+                .insert_header((IDEMPOTENCY_KEY_HEADER, key.to_string()))
+                .set_json(serde_json::json!({"name": name, "email": "retry@example.com"}))
+                .to_request()
+        };
+
+        let first: User = test::call_and_read_body_json(&app, post("key-1", "retry")).await;
+        let response = test::call_service(&app, post("key-1", "retry")).await;
+        assert_eq!(response.status().as_u16(), 200);
+        assert!(response.headers().contains_key(IDEMPOTENT_REPLAYED_HEADER));
+        let second: User = test::read_body_json(response).await;
+        assert_eq!(first.id, second.id);
+
+        // the same key with a different body is a conflict, and so is a new key for the same email
+        for (key, name) in [("key-1", "someone else"), ("key-2", "retry")] {
+            let response = test::call_service(&app, post(key, name)).await;
+            assert_eq!(response.status().as_u16(), 409);
+        }
+        let req = test::TestRequest::get().uri("/users/count").to_request();
+        let count: UserCount = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(count.count, 1);
+
+        // a client can pick the id, and upsert with it
+        let req = test::TestRequest::post()
+            .uri("/users")
+            .set_json(
+                serde_json::json!({"id": "my-id", "name": "mine", "email": "mine@example.com"}),
+            )
+            .to_request();
+        let user: User = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(user.id, "my-id");
+        let req = test::TestRequest::put()
+            .uri("/users/new-id?upsert=true")
+            .set_json(serde_json::json!({"name": "new", "email": "new@example.com"}))
+            .to_request();
+        let user: User = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(user.id, "new-id");
+        let req = test::TestRequest::put()
+            .uri("/users/new-id")
+            .set_json(
+                serde_json::json!({"id": "other-id", "name": "new", "email": "new@example.com"}),
+            )
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status().as_u16(), 400);
+    }
+
+    #[actix_web::test]
+    async fn test_bad_bodies_get_the_same_errors() {
+        let app = test::init_service(
+            App::new()
+                .app_data(memory_store())
+                .route("/users", web::post().to(create)),
+        )
+        .await;
+
+        // a missing field is a 400 with a JSON body, whichever way it was sent
+        let json = test::TestRequest::post()
+            .uri("/users")
+            .set_json(serde_json::json!({"name": "no email"}))
+            .to_request();
+        let form = test::TestRequest::post()
+            .uri("/users")
+            .set_form([("name", "no email")])
+            .to_request();
+        for req in [json, form] {
+            let response = test::call_service(&app, req).await;
+            assert_eq!(response.status().as_u16(), 400);
+            let body: serde_json::Value = test::read_body_json(response).await;
+            assert!(body["message"]
+                .as_str()
+                .unwrap()
+                .starts_with("invalid request body"));
+        }
+
+        // a body that parses but breaks the rules is a 422 listing the fields
+        let req = test::TestRequest::post()
+            .uri("/users")
+            .set_json(serde_json::json!({"name": "", "email": "nope"}))
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status().as_u16(), 422);
+        let body: serde_json::Value = test::read_body_json(response).await;
+        assert_eq!(body["errors"][0]["field"], "email");
+        assert_eq!(body["errors"][1]["field"], "name");
+
+        let req = test::TestRequest::post()
+            .uri("/users")
+            .insert_header((header::CONTENT_TYPE, "text/plain"))
+            .set_payload("name=x")
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status().as_u16(), 415);
     }
 }
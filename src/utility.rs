@@ -1,4 +1,5 @@
 use rand::{rngs::StdRng, Rng, SeedableRng};
+use sha2::{Digest, Sha256};
 use std::cell::RefCell;
 
 /*
@@ -15,15 +16,35 @@ pub fn get_id() -> String {
 }
 
 /**
- *  Hard coded names for the Database and the Collection. These could be passed in as secrets or parameters
+ *  the id of the user created by a POST with this Idempotency-Key.  the same key always gives the same id, so a
+ *  retried POST runs into the user the first one made (a conflict on the id) instead of creating another one -- in any
+ *  instance of the service and after a restart, without remembering the keys anywhere
  */
-pub const DATABASE_NAME: &'static str = "Users-db";
-pub const COLLECTION_NAME: &'static str = "User-Container";
+pub fn id_for_idempotency_key(key: &str) -> String {
+    let digest = Sha256::digest(key.as_bytes());
+    let hex: String = digest[..16].iter().map(|b| format!("{:02x}", b)).collect();
+    format!("idempotent_{}", hex)
+}
 
+/**
+ *  page sizes for listing users.  clients can ask for up to MAX_PAGE_SIZE users at a time with ?limit=
+ */
+pub const DEFAULT_PAGE_SIZE: u32 = 100;
+pub const MAX_PAGE_SIZE: u32 = 1000;
+
+/**
+ *  the most users one POST or DELETE /users:batch can carry
+ */
+pub const MAX_BATCH_SIZE: usize = 1000;
+
+/**
+ *  log an error and return it from the current function, converting it into the function's error type (e.g. an
+ *  azure_core::Error into a UserDbError)
+ */
 #[macro_export]
 macro_rules! log_return_err {
     ( $e:expr ) => {{
         log::error!("\t{}\n {:#?}", $e, $e);
-        return Err($e);
+        return Err($e.into());
     }};
 }
@@ -0,0 +1,148 @@
+/**
+ *  checks on what clients send us.  the rules live on the request structs as #[validate(...)] attributes (see
+ *  PartialUser and UserPatch in models.rs), and UserBody runs them on every create and update before the store is
+ *  called -- whether the body came in as JSON or as form data.  before the rules run the values are normalized:
+ *  whitespace is trimmed and text is put in Unicode NFC, so "José" typed on a Mac and "José" typed on Windows are
+ *  stored (and compared) as the same string.  a body that breaks any rule gets a 422 listing every field that is
+ *  wrong.
+ */
+use crate::error::{FieldError, UserDbError};
+use std::borrow::Cow;
+use unicode_normalization::UnicodeNormalization;
+use validator::{ValidationError, ValidationErrors};
+
+/**
+ *  clean up a request before it is validated
+ */
+pub trait Normalize {
+    fn normalize(&mut self);
+}
+
+pub fn normalize_text(value: &str) -> String {
+    value.trim().nfc().collect()
+}
+
+/**
+ *  the domain of an email address is case insensitive, so we store it lower cased.  the part before the @ is left
+ *  alone -- in theory the receiving server gets to decide whether that is case sensitive
+ */
+pub fn normalize_email(value: &str) -> String {
+    let email = normalize_text(value);
+    match email.rsplit_once('@') {
+        Some((local, domain)) => format!("{}@{}", local, domain.to_lowercase()),
+        None => email,
+    }
+}
+
+/**
+ *  names end up in logs and in other people's UIs, so no newlines, escapes or other control characters
+ */
+pub fn no_control_characters(value: &str) -> Result<(), ValidationError> {
+    if value.chars().any(char::is_control) {
+        let mut error = ValidationError::new("control_character");
+        error.message = Some(Cow::from("can't contain control characters"));
+        return Err(error);
+    }
+    Ok(())
+}
+
+/**
+ *  the ids cosmos accepts: at most 255 characters, and none of / \ ? # since the id goes in the url of the document.
+ *  only clients that pick their own id need this -- the ids we make always pass
+ */
+pub fn valid_id(value: &str) -> Result<(), ValidationError> {
+    let message = if value.is_empty() || value.chars().count() > 255 {
+        "must be 1 to 255 characters"
+    } else if value.chars().any(|c| "/\\?#".contains(c) || c.is_control()) {
+        "can't contain / \\ ? # or control characters"
+    } else if value.trim() != value {
+        "can't start or end with whitespace"
+    } else {
+        return Ok(());
+    };
+    let mut error = ValidationError::new("id");
+    error.message = Some(Cow::from(message));
+    Err(error)
+}
+
+/**
+ *  one FieldError per broken rule, sorted by field so the response is the same every time
+ */
+impl From<ValidationErrors> for UserDbError {
+    fn from(errors: ValidationErrors) -> Self {
+        let mut fields: Vec<FieldError> = errors
+            .field_errors()
+            .into_iter()
+            .flat_map(|(field, errors)| {
+                errors.iter().map(move |error| FieldError {
+                    field: field.to_string(),
+                    code: error.code.to_string(),
+                    message: match &error.message {
+                        Some(message) => message.to_string(),
+                        None => format!("is not valid ({})", error.code),
+                    },
+                })
+            })
+            .collect();
+        fields.sort_by(|a, b| a.field.cmp(&b.field).then(a.code.cmp(&b.code)));
+        UserDbError::InvalidFields(fields)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{PartialUser, UserPatch};
+    use validator::Validate;
+
+    #[test]
+    fn test_normalize() {
+        // "e" followed by a combining acute accent becomes the single character é
+        assert_eq!(normalize_text("  Jose\u{301} "), "Jos\u{e9}");
+        assert_eq!(
+            normalize_email(" Bob.Smith@Example.COM"),
+            "Bob.Smith@example.com"
+        );
+    }
+
+    #[test]
+    fn test_every_bad_field_is_reported() {
+        let mut user = PartialUser {
+            id: None,
+            email: "not an email".to_string(),
+            name: "   ".to_string(),
+        };
+        user.normalize();
+        let err = UserDbError::from(user.validate().unwrap_err());
+        match err {
+            UserDbError::InvalidFields(fields) => {
+                let fields: Vec<&str> = fields.iter().map(|f| f.field.as_str()).collect();
+                assert_eq!(fields, vec!["email", "name"]);
+            }
+            other => panic!("expected InvalidFields, got {:?}", other),
+        }
+
+        let mut user = PartialUser {
+            id: Some("has/a/slash".to_string()),
+            email: "id@example.com".to_string(),
+            name: "id".to_string(),
+        };
+        user.normalize();
+        assert_eq!(
+            user.validate().unwrap_err().field_errors()["id"][0].code,
+            Cow::from("id")
+        );
+
+        // a patch only checks the fields it sets
+        let mut patch = UserPatch {
+            email: None,
+            name: Some("line\nbreak".to_string()),
+        };
+        patch.normalize();
+        let errors = patch.validate().unwrap_err();
+        assert_eq!(
+            errors.field_errors()["name"][0].code,
+            Cow::from("control_character")
+        );
+    }
+}
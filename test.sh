@@ -78,9 +78,32 @@ check_response() {
 
 
 function run_tests() {
+  if [[ -z "$COSMOS_RUST_SAMPLE_RESET_TOKEN" ]]; then
+    echo_error "set COSMOS_RUST_SAMPLE_RESET_TOKEN to the reset token the server was started with"
+    exit 1
+  fi
+  if [[ -z "$COSMOS_RUST_SAMPLE_ADMIN_TOKEN" ]]; then
+    echo_error "set COSMOS_RUST_SAMPLE_ADMIN_TOKEN to the admin token the server was started with"
+    exit 1
+  fi
+
   echo_warning "Running setup on the database"
   status=$(curl -s -w "%{http_code}" -o tmp.txt --location --request POST "$SERVER_URI/setup")
-  check_response "$status" 200 "database: Users-db collection: User-Container"
+  check_response "$status" 200 "\"collection_created\""
+
+
+  #  setup leaves existing users alone, so start from an empty database.  the server needs allow_reset and the same
+  #  COSMOS_RUST_SAMPLE_RESET_TOKEN in its environment
+  echo_warning "Resetting the database"
+  status=$(curl -s -w "%{http_code}" -o tmp.txt --location --request POST "$SERVER_URI/reset" \
+  --header "X-Reset-Token: $COSMOS_RUST_SAMPLE_RESET_TOKEN")
+  check_response "$status" 200 "\"database_created\":true"
+
+
+  echo_warning "Migrating partitions.  Nothing to move in an empty database"
+  status=$(curl -s -w "%{http_code}" -o tmp.txt --location --request POST "$SERVER_URI/migrate" \
+  --header "X-Admin-Token: $COSMOS_RUST_SAMPLE_ADMIN_TOKEN")
+  check_response "$status" 200 "\"moved\":0"
 
 
   echo_warning "Looking for Users. This should be empty:"
@@ -97,10 +120,92 @@ function run_tests() {
   check_response "$status" 200 "id"
 
 
+  echo_warning "Creating a user from JSON"
+  status=$(curl -s -w "%{http_code}" -o tmp.txt --location "$SERVER_URI/users" \
+  --header 'Content-Type: application/json' \
+  --data '{"name": "jason", "email": "jason@test.com"}')
+  check_response "$status" 200 "jason@test.com"
+  json_id=$(jq -r .id tmp.txt)
+
+  echo_warning "Creating a user with an email that is taken"
+  status=$(curl -s -w "%{http_code}" -o tmp.txt --location "$SERVER_URI/users" \
+  --header 'Content-Type: application/json' \
+  --data '{"name": "copycat", "email": "jason@test.com"}')
+  check_response "$status" 409 "already exists"
+
+  echo_warning "Creating a user with a bad email and no name"
+  status=$(curl -s -w "%{http_code}" -o tmp.txt --location "$SERVER_URI/users" \
+  --header 'Content-Type: application/json' \
+  --data '{"name": " ", "email": "not-an-email"}')
+  check_response "$status" 422 "\"field\":\"email\""
+
+  echo_warning "Creating a user from plain text"
+  status=$(curl -s -w "%{http_code}" -o tmp.txt --location "$SERVER_URI/users" \
+  --header 'Content-Type: text/plain' \
+  --data 'name=nobody')
+  check_response "$status" 415 "is not supported"
+
+  echo_warning "Retrying a POST with an Idempotency-Key"
+  idempotency_key="test-$RANDOM-$RANDOM"
+  for attempt in 1 2; do
+    status=$(curl -s -w "%{http_code}" -o tmp.txt --location --request POST "$SERVER_URI/users" \
+    --header 'Content-Type: application/json' \
+    --header "Idempotency-Key: $idempotency_key" \
+    --data-raw '{"name": "retried", "email": "retried@test.com"}')
+    check_response "$status" 200 "retried@test.com"
+  done
+  status=$(curl -s -w "%{http_code}" -o tmp.txt --location --request DELETE "$SERVER_URI/users/$(jq -r .id tmp.txt)")
+  check_response "$status" 200 "deleted user"
+
+  echo_warning "Creating users in a batch"
+  status=$(curl -s -w "%{http_code}" -o tmp.txt --location --request POST "$SERVER_URI/users:batch" \
+  --header 'Content-Type: application/json' \
+  --data-raw '[{"name": "batch one", "email": "batch.one@test.com"}, {"name": "batch two", "email": "batch.two@test.com"}]')
+  check_response "$status" 200 "batch.two@test.com"
+  batch_ids=$(jq -c '[.items[].id]' < tmp.txt)
+
+  echo_warning "Deleting the batch users"
+  status=$(curl -s -w "%{http_code}" -o tmp.txt --location --request DELETE "$SERVER_URI/users:batch" \
+  --header 'Content-Type: application/json' \
+  --data-raw "$batch_ids")
+  check_response "$status" 200 '"failed":0'
+
+  echo_warning "Renaming the JSON user in a transaction"
+  json_partition=$(curl -s --location "$SERVER_URI/users/$json_id" | jq -r .partition_key)
+  status=$(curl -s -w "%{http_code}" -o tmp.txt --location --request POST "$SERVER_URI/partitions/$json_partition/transaction" \
+  --header 'Content-Type: application/json' \
+  --data-raw "[{\"op\": \"patch\", \"id\": \"$json_id\", \"patch\": {\"name\": \"transaction user\"}}]")
+  check_response "$status" 200 "transaction user"
+
+  echo_warning "Deleting the JSON user"
+  status=$(curl -s -w "%{http_code}" -o tmp.txt --location --request DELETE "$SERVER_URI/users/$json_id")
+  check_response "$status" 200 "deleted user with id: $json_id"
+
+
   echo_warning "Getting all users again"
   status=$(curl -s -w "%{http_code}" -o tmp.txt --location "$SERVER_URI/users")
   check_response "$status" 200 "$user"
 
+  echo_warning "Getting the first page of users"
+  status=$(curl -s -w "%{http_code}" -o tmp.txt --location "$SERVER_URI/users?limit=1")
+  check_response "$status" 200 "$user"
+
+  echo_warning "Asking for too many users in a page"
+  status=$(curl -s -w "%{http_code}" -o tmp.txt --location "$SERVER_URI/users?limit=0")
+  check_response "$status" 400 "limit must be between"
+
+  echo_warning "Searching users by email domain"
+  status=$(curl -s -w "%{http_code}" -o tmp.txt --location "$SERVER_URI/users?email_domain=$(echo "$user" | jq -r .email | cut -d@ -f2)&order_by=name&order=desc")
+  check_response "$status" 200 "$user"
+
+  echo_warning "Sorting on a field that isn't allowed"
+  status=$(curl -s -w "%{http_code}" -o tmp.txt --location "$SERVER_URI/users?order_by=partition_key")
+  check_response "$status" 400 "invalid query string"
+
+  echo_warning "Counting users"
+  status=$(curl -s -w "%{http_code}" -o tmp.txt --location "$SERVER_URI/users/count")
+  check_response "$status" 200 "count"
+
   echo_warning "Finding one user"
   id=$(echo "$user" | jq -r .id)
   status=$(curl -s -w "%{http_code}" -o tmp.txt --location "$SERVER_URI/users/$id")
@@ -108,9 +213,37 @@ function run_tests() {
   echo_if_verbose "$found_user \n $status"
 
 
+  echo_warning "Replacing the user"
+  status=$(curl -s -w "%{http_code}" -o tmp.txt --location --request PUT "$SERVER_URI/users/$id" \
+  --header 'Content-Type: application/x-www-form-urlencoded' \
+  --data-urlencode 'name=douglas' \
+  --data-urlencode 'email=douglas@test.com')
+  check_response "$status" 200 "douglas@test.com"
+
+  echo_warning "Patching the user's name"
+  status=$(curl -s -w "%{http_code}" -o tmp.txt --location --request PATCH "$SERVER_URI/users/$id" \
+  --header 'Content-Type: application/x-www-form-urlencoded' \
+  --data-urlencode 'name=doug')
+  check_response "$status" 200 "\"name\":\"doug\""
+
+  echo_warning "Patching the user with a stale ETag"
+  status=$(curl -s -w "%{http_code}" -o tmp.txt --location --request PATCH "$SERVER_URI/users/$id" \
+  --header 'Content-Type: application/x-www-form-urlencoded' \
+  --header 'If-Match: "stale"' \
+  --data-urlencode 'name=nobody')
+  check_response "$status" 412 "has been changed since it was read"
+
   echo_warning "Deleting the user"
   status=$(curl -s -w "%{http_code}" -o tmp.txt --location --request DELETE "$SERVER_URI/users/$id")
   check_response "$status" 200 "deleted user with id: $id"
+
+  echo_warning "Finding the deleted user"
+  status=$(curl -s -w "%{http_code}" -o tmp.txt --location "$SERVER_URI/users/$id")
+  check_response "$status" 404 "not found"
+
+  echo_warning "Reading the retry metrics"
+  status=$(curl -s -w "%{http_code}" -o tmp.txt --location "$SERVER_URI/metrics/retries")
+  check_response "$status" 200 "\"gave_up\":"
 }
 
 function print_results() {